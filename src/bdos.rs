@@ -0,0 +1,865 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+
+use iz80::*;
+
+use super::cpm_trace::CpmTracer;
+use super::mbc2_machine::Mbc2Machine;
+
+// Minimal CP/M 2.2 BDOS provided by the emulator, to run .COM files without
+// the sd directory:
+//
+//    z80-mbc2-emu bdos [--host-dir DIR] [--cpm-trace FILE] PROGRAM.COM [ARGS...]
+//
+// The calls to address 5 are trapped and served with the host console and
+// the files of the host directory. The drives and the user numbers are
+// ignored. A warm boot ends the emulation.
+
+const BDOS_ADDRESS: u16 = 0xfe00;
+const BIOS_ADDRESS: u16 = 0xff00;
+const BIOS_ENTRIES: u16 = 17;
+pub const BIOS3_ENTRIES: u16 = 33; // CP/M 3
+const TPA_ADDRESS: u16 = 0x0100;
+const DEFAULT_FCB: u16 = 0x005c;
+const DEFAULT_FCB2: u16 = 0x006c;
+const DEFAULT_DMA: u16 = 0x0080;
+const RECORD_SIZE: usize = 128;
+const EOF_PADDING: u8 = 0x1a; // Ctrl-z
+
+pub fn function_name(function: u8) -> &'static str {
+    match function {
+        0 => "P_TERMCPM",
+        1 => "C_READ",
+        2 => "C_WRITE",
+        3 => "A_READ",
+        4 => "A_WRITE",
+        5 => "L_WRITE",
+        6 => "C_RAWIO",
+        7 => "A_STATIN",
+        8 => "A_STATOUT",
+        9 => "C_WRITESTR",
+        10 => "C_READSTR",
+        11 => "C_STAT",
+        12 => "S_BDOSVER",
+        13 => "DRV_ALLRESET",
+        14 => "DRV_SET",
+        15 => "F_OPEN",
+        16 => "F_CLOSE",
+        17 => "F_SFIRST",
+        18 => "F_SNEXT",
+        19 => "F_DELETE",
+        20 => "F_READ",
+        21 => "F_WRITE",
+        22 => "F_MAKE",
+        23 => "F_RENAME",
+        24 => "DRV_LOGINVEC",
+        25 => "DRV_GET",
+        26 => "F_DMAOFF",
+        27 => "DRV_ALLOCVEC",
+        28 => "DRV_SETRO",
+        29 => "DRV_ROVEC",
+        30 => "F_ATTRIB",
+        31 => "DRV_DPB",
+        32 => "F_USERNUM",
+        33 => "F_READRAND",
+        34 => "F_WRITERAND",
+        35 => "F_SIZE",
+        36 => "F_RANDREC",
+        37 => "DRV_RESET",
+        40 => "F_WRITEZF",
+        // CP/M 3
+        41 => "F_TESTWRITE",
+        42 => "F_LOCK",
+        43 => "F_UNLOCK",
+        44 => "F_MULTISEC",
+        45 => "F_ERRMODE",
+        46 => "DRV_SPACE",
+        47 => "P_CHAIN",
+        48 => "DRV_FLUSH",
+        49 => "S_SCB",
+        50 => "S_BIOS",
+        59 => "P_LOAD",
+        60 => "P_RSX",
+        98 => "F_CLEANUP",
+        99 => "F_TRUNCATE",
+        100 => "DRV_SETLABEL",
+        101 => "DRV_GETLABEL",
+        102 => "F_TIMEDATE",
+        103 => "F_WRITEXFCB",
+        104 => "T_SET",
+        105 => "T_GET",
+        106 => "F_PASSWD",
+        107 => "S_SERIAL",
+        108 => "P_CODE",
+        109 => "C_MODE",
+        110 => "C_DELIMIT",
+        111 => "C_WRITEBLK",
+        112 => "L_WRITEBLK",
+        152 => "F_PARSE",
+        _ => "unknown",
+    }
+}
+
+pub fn bios_function_name(index: u16) -> &'static str {
+    const NAMES: [&str; BIOS3_ENTRIES as usize] = [
+        "BOOT", "WBOOT", "CONST", "CONIN", "CONOUT", "LIST", "PUNCH", "READER",
+        "HOME", "SELDSK", "SETTRK", "SETSEC", "SETDMA", "READ", "WRITE",
+        "LISTST", "SECTRAN",
+        // CP/M 3
+        "CONOST", "AUXIST", "AUXOST", "DEVTBL", "DEVINI", "DRVTBL", "MULTIO",
+        "FLUSH", "MOVE", "TIME", "SELMEM", "SETBNK", "XMOVE", "USERF",
+        "RESERV1", "RESERV2"];
+    NAMES.get(index as usize).copied().unwrap_or("unknown")
+}
+
+struct Bdos {
+    root: PathBuf,
+    dma: u16,
+    drive: u8,
+    user: u8,
+    files: HashMap<String, fs::File>,
+    search: Vec<String>,
+    unimplemented: HashSet<u8>,
+}
+
+pub fn bdos_command(args: &[String]) -> Result<i32, String> {
+    let mut root = ".".to_string();
+    let mut tracer = None;
+    let mut i = 0;
+    while i < args.len() && args[i].starts_with("--") {
+        match args[i].as_str() {
+            "--host-dir" if i + 1 < args.len() => {
+                root = args[i + 1].clone();
+                i += 1;
+            },
+            "--cpm-trace" if i + 1 < args.len() => {
+                tracer = Some(CpmTracer::new(&args[i + 1])?);
+                i += 1;
+            },
+            _ => return Err(format!("unknown option '{}'", args[i])),
+        }
+        i += 1;
+    }
+    let program = args.get(i)
+        .ok_or("usage: z80-mbc2-emu bdos [--host-dir DIR] [--cpm-trace FILE] PROGRAM.COM [ARGS...]")?;
+    let data = fs::read(program)
+        .map_err(|e| format!("Error reading the program '{}': {}", program, e))?;
+    if data.len() > (BDOS_ADDRESS - TPA_ADDRESS) as usize {
+        return Err(format!("the program '{}' does not fit in the TPA", program));
+    }
+
+    let mut machine = Mbc2Machine::new();
+    let mut cpu = Cpu::new_z80();
+    for (i, value) in data.iter().enumerate() {
+        machine.poke(TPA_ADDRESS + i as u16, *value);
+    }
+    prepare_page_zero(&mut machine, &args[i + 1..]);
+
+    // Returning from the program is a warm boot
+    let sp = BDOS_ADDRESS - 2;
+    machine.poke16(sp, 0x0000);
+    cpu.registers().set16(Reg16::SP, sp);
+    cpu.registers().set_pc(TPA_ADDRESS);
+
+    let mut bdos = Bdos::new(PathBuf::from(root));
+
+    loop {
+        if let Some(tracer) = tracer.as_mut() {
+            tracer.step(&mut cpu, &machine);
+        }
+        let pc = cpu.registers().pc();
+        let go_on = if pc == 0x0000 {
+            false // Warm boot
+        } else if pc == BDOS_ADDRESS {
+            bdos.call(&mut cpu, &mut machine)
+        } else if (BIOS_ADDRESS..BIOS_ADDRESS + 3 * BIOS_ENTRIES).contains(&pc) {
+            bdos.bios_call(&mut cpu, &mut machine, (pc - BIOS_ADDRESS) / 3)
+        } else {
+            true
+        };
+        if !go_on {
+            return Ok(0);
+        }
+
+        cpu.execute_instruction(&mut machine);
+        if cpu.is_halted() {
+            println!("HALT instruction");
+            return Ok(1);
+        }
+        if machine.quit {
+            return Ok(1);
+        }
+    }
+}
+
+fn prepare_page_zero(machine: &mut Mbc2Machine, args: &[String]) {
+    // JP to the BIOS warm boot and to the BDOS
+    machine.poke(0x0000, 0xc3);
+    machine.poke16(0x0001, BIOS_ADDRESS + 3);
+    machine.poke(0x0005, 0xc3);
+    machine.poke16(0x0006, BDOS_ADDRESS);
+    // The traps return with RET
+    machine.poke(BDOS_ADDRESS, 0xc9);
+    for i in 0..BIOS_ENTRIES {
+        machine.poke(BIOS_ADDRESS + 3 * i, 0xc9);
+    }
+
+    // Command tail and default FCBs
+    let mut tail = String::new();
+    for arg in args.iter() {
+        tail.push(' ');
+        tail.push_str(&arg.to_uppercase());
+    }
+    tail.truncate(RECORD_SIZE - 2);
+    machine.poke(DEFAULT_DMA, tail.len() as u8);
+    for (i, ch) in tail.bytes().enumerate() {
+        machine.poke(DEFAULT_DMA + 1 + i as u16, ch);
+    }
+    machine.poke(DEFAULT_DMA + 1 + tail.len() as u16, 0);
+
+    for (address, arg) in [(DEFAULT_FCB, args.first()), (DEFAULT_FCB2, args.get(1))] {
+        let fcb = parse_fcb(arg.map(|a| a.as_str()).unwrap_or(""));
+        for (i, value) in fcb.iter().enumerate() {
+            machine.poke(address + i as u16, *value);
+        }
+    }
+}
+
+// Drive, name and extension of an FCB from "D:NAME.EXT", with wildcards
+fn parse_fcb(text: &str) -> [u8; 16] {
+    let mut fcb = [0; 16];
+    fcb[1..12].fill(b' ');
+    let text = text.to_uppercase();
+    let mut name = text.as_str();
+    if name.len() > 1 && name.as_bytes()[1] == b':' {
+        fcb[0] = name.as_bytes()[0].wrapping_sub(b'A' - 1);
+        name = &name[2..];
+    }
+    let (base, ext) = match name.find('.') {
+        Some(pos) => (&name[..pos], &name[pos+1..]),
+        None => (name, ""),
+    };
+    for (field, start, size) in [(base, 1, 8), (ext, 9, 3)] {
+        for (i, ch) in field.bytes().take(size).enumerate() {
+            if ch == b'*' {
+                fcb[start + i..start + size].fill(b'?');
+                break;
+            }
+            fcb[start + i] = ch;
+        }
+    }
+    fcb
+}
+
+impl Bdos {
+    fn new(root: PathBuf) -> Bdos {
+        Bdos {
+            root,
+            dma: DEFAULT_DMA,
+            drive: 0,
+            user: 0,
+            files: HashMap::new(),
+            search: Vec::new(),
+            unimplemented: HashSet::new(),
+        }
+    }
+
+    // Returns false to end the emulation
+    fn call(&mut self, cpu: &mut Cpu, machine: &mut Mbc2Machine) -> bool {
+        let function = cpu.registers().get8(Reg8::C);
+        let arg = cpu.registers().get16(Reg16::DE);
+        let mut result: u16 = 0;
+        match function {
+            0 => return false,
+            1 => { // Console input
+                let ch = machine.console().read();
+                machine.console().put(ch);
+                result = ch as u16;
+            },
+            2 => machine.console().put(arg as u8),
+            6 => { // Direct console I/O
+                result = match arg as u8 {
+                    0xff if machine.console().status() => machine.console().read() as u16,
+                    0xff => 0,
+                    0xfe if machine.console().status() => 0xff,
+                    0xfe => 0,
+                    ch => {
+                        machine.console().put(ch);
+                        0
+                    }
+                }
+            },
+            9 => { // Print string
+                // Up to the whole memory if the '$' is missing
+                for i in 0..=0xffff {
+                    let ch = machine.peek(arg.wrapping_add(i));
+                    if ch == b'$' {
+                        break;
+                    }
+                    machine.console().put(ch);
+                }
+            },
+            10 => { // Read console buffer
+                let max = machine.peek(arg) as usize;
+                let line = match machine.read_line("", max) {
+                    Some(line) => line,
+                    None => return false, // Control C
+                };
+                machine.poke(arg.wrapping_add(1), line.len() as u8);
+                for (i, ch) in line.bytes().enumerate() {
+                    machine.poke(arg.wrapping_add(2 + i as u16), ch);
+                }
+            },
+            11 => result = if machine.console().status() { 0xff } else { 0 },
+            12 => result = 0x0022, // CP/M 2.2
+            13 => {
+                self.dma = DEFAULT_DMA;
+                self.drive = 0;
+            },
+            14 => self.drive = arg as u8,
+            15 => result = self.open(machine, arg),
+            16 => result = self.close(machine, arg),
+            17 => result = self.search_first(machine, arg),
+            18 => result = self.search_next(machine),
+            19 => result = self.delete(machine, arg),
+            20 => result = self.read_write(machine, arg, false, false),
+            21 => result = self.read_write(machine, arg, true, false),
+            22 => result = self.make(machine, arg),
+            23 => result = self.rename(machine, arg),
+            24 => result = 1, // Only drive A
+            25 => result = self.drive as u16,
+            26 => self.dma = arg,
+            32 => { // Get or set the user number
+                if arg as u8 == 0xff {
+                    result = self.user as u16;
+                } else {
+                    self.user = arg as u8 & 0x0f;
+                }
+            },
+            33 => result = self.read_write(machine, arg, false, true),
+            34 | 40 => result = self.read_write(machine, arg, true, true),
+            35 => result = self.size(machine, arg),
+            36 => { // Set random record
+                let record = fcb_record(machine, arg);
+                set_random_record(machine, arg, record);
+            },
+            37 => (), // Reset drive
+            _ => {
+                if self.unimplemented.insert(function) {
+                    println!("<<BDOS function {} {} not implemented>>", function, function_name(function));
+                }
+            },
+        }
+
+        // The results are on A and HL, with B = H
+        cpu.registers().set16(Reg16::HL, result);
+        cpu.registers().set_a(result as u8);
+        cpu.registers().set8(Reg8::B, (result >> 8) as u8);
+        true
+    }
+
+    fn bios_call(&mut self, cpu: &mut Cpu, machine: &mut Mbc2Machine, index: u16) -> bool {
+        match index {
+            0 | 1 => return false, // Boot
+            2 => { // CONST
+                let status = if machine.console().status() { 0xff } else { 0 };
+                cpu.registers().set_a(status);
+            },
+            3 => { // CONIN
+                let ch = machine.console().read();
+                cpu.registers().set_a(ch);
+            },
+            4 => { // CONOUT
+                let ch = cpu.registers().get8(Reg8::C);
+                machine.console().put(ch);
+            },
+            _ => {
+                if self.unimplemented.insert(0x80 + index as u8) {
+                    println!("<<BIOS function {} not implemented>>", bios_function_name(index));
+                }
+            },
+        }
+        true
+    }
+
+    // Host file for the name on the FCB, with a case insensitive match. None
+    // if the name is not a file of the host folder.
+    fn host_path(&self, name: &str) -> Option<PathBuf> {
+        if !valid_name(name) {
+            return None;
+        }
+        if let Ok(entries) = fs::read_dir(&self.root) {
+            for entry in entries.flatten() {
+                if entry.file_name().to_string_lossy().to_uppercase() == name {
+                    return Some(entry.path());
+                }
+            }
+        }
+        Some(self.root.join(name))
+    }
+
+    fn host_files(&self, pattern: &[u8]) -> Vec<String> {
+        let mut names = Vec::new();
+        if let Ok(entries) = fs::read_dir(&self.root) {
+            for entry in entries.flatten() {
+                let name = entry.file_name().to_string_lossy().to_uppercase();
+                if entry.path().is_file() && matches_pattern(&name, pattern) {
+                    names.push(name);
+                }
+            }
+        }
+        names.sort();
+        names
+    }
+
+    fn open(&mut self, machine: &mut Mbc2Machine, fcb: u16) -> u16 {
+        let name = fcb_name(machine, fcb);
+        let path = match self.host_path(&name) {
+            Some(path) => path,
+            None => return 0xff,
+        };
+        match fs::OpenOptions::new().read(true).write(true).open(&path)
+                .or_else(|_| fs::File::open(&path)) {
+            Ok(file) => {
+                let size = file.metadata().map(|m| m.len()).unwrap_or(0);
+                self.files.insert(name, file);
+                // Records on the current extent
+                let extent = machine.peek(fcb + 12) as u64;
+                let records = size.div_ceil(RECORD_SIZE as u64).saturating_sub(extent * 128).min(128);
+                machine.poke(fcb + 15, records as u8);
+                machine.poke(fcb + 32, 0);
+                0
+            },
+            Err(_) => 0xff,
+        }
+    }
+
+    fn close(&mut self, machine: &mut Mbc2Machine, fcb: u16) -> u16 {
+        let name = fcb_name(machine, fcb);
+        match self.files.remove(&name) {
+            Some(_) => 0,
+            None if self.host_path(&name).is_some_and(|path| path.exists()) => 0,
+            None => 0xff,
+        }
+    }
+
+    fn search_first(&mut self, machine: &mut Mbc2Machine, fcb: u16) -> u16 {
+        let pattern: Vec<u8> = (1..12).map(|i| machine.peek(fcb + i) & 0x7f).collect();
+        self.search = self.host_files(&pattern);
+        self.search.reverse();
+        self.search_next(machine)
+    }
+
+    fn search_next(&mut self, machine: &mut Mbc2Machine) -> u16 {
+        let name = match self.search.pop() {
+            Some(name) => name,
+            None => return 0xff,
+        };
+        let size = self.host_path(&name).and_then(|path| fs::metadata(path).ok())
+            .map(|m| m.len()).unwrap_or(0);
+        let fcb = parse_fcb(&name);
+        let records = size.div_ceil(RECORD_SIZE as u64);
+        let mut entry = [0u8; 32];
+        entry[1..12].copy_from_slice(&fcb[1..12]);
+        entry[12] = (records.saturating_sub(1) / 128) as u8;
+        entry[15] = if records == 0 { 0 } else { ((records - 1) % 128 + 1) as u8 };
+        for (i, value) in entry.iter().enumerate() {
+            machine.poke(self.dma + i as u16, *value);
+        }
+        for i in 32..RECORD_SIZE as u16 {
+            machine.poke(self.dma + i, 0xe5);
+        }
+        0
+    }
+
+    fn delete(&mut self, machine: &mut Mbc2Machine, fcb: u16) -> u16 {
+        let pattern: Vec<u8> = (1..12).map(|i| machine.peek(fcb + i) & 0x7f).collect();
+        let mut result = 0xff;
+        for name in self.host_files(&pattern) {
+            self.files.remove(&name);
+            if self.host_path(&name).is_some_and(|path| fs::remove_file(path).is_ok()) {
+                result = 0;
+            }
+        }
+        result
+    }
+
+    fn make(&mut self, machine: &mut Mbc2Machine, fcb: u16) -> u16 {
+        let name = fcb_name(machine, fcb);
+        let path = match self.host_path(&name) {
+            Some(path) => path,
+            None => return 0xff,
+        };
+        match fs::OpenOptions::new().read(true).write(true).create(true).truncate(true)
+                .open(path) {
+            Ok(file) => {
+                self.files.insert(name, file);
+                machine.poke(fcb + 12, 0);
+                machine.poke(fcb + 15, 0);
+                machine.poke(fcb + 32, 0);
+                0
+            },
+            Err(_) => 0xff,
+        }
+    }
+
+    fn rename(&mut self, machine: &mut Mbc2Machine, fcb: u16) -> u16 {
+        let old = fcb_name(machine, fcb);
+        let new = fcb_name(machine, fcb + 16);
+        let (old_path, new_path) = match (self.host_path(&old), valid_name(&new)) {
+            (Some(path), true) => (path, self.root.join(&new)),
+            _ => return 0xff,
+        };
+        self.files.remove(&old);
+        match fs::rename(old_path, new_path) {
+            Ok(_) => 0,
+            Err(_) => 0xff,
+        }
+    }
+
+    fn size(&mut self, machine: &mut Mbc2Machine, fcb: u16) -> u16 {
+        let name = fcb_name(machine, fcb);
+        match self.host_path(&name).map(fs::metadata) {
+            Some(Ok(metadata)) => {
+                set_random_record(machine, fcb, metadata.len().div_ceil(RECORD_SIZE as u64) as u32);
+                0
+            },
+            _ => 0xff,
+        }
+    }
+
+    fn read_write(&mut self, machine: &mut Mbc2Machine, fcb: u16, write: bool, random: bool) -> u16 {
+        let name = fcb_name(machine, fcb);
+        let record = if random {
+            let record = machine.peek(fcb + 33) as u32
+                + ((machine.peek(fcb + 34) as u32) << 8)
+                + ((machine.peek(fcb + 35) as u32 & 0x03) << 16);
+            // Random access moves the sequential position
+            machine.poke(fcb + 12, ((record >> 7) & 0x1f) as u8);
+            machine.poke(fcb + 14, (record >> 12) as u8);
+            machine.poke(fcb + 32, (record & 0x7f) as u8);
+            record
+        } else {
+            fcb_record(machine, fcb)
+        };
+
+        let file = match self.files.get_mut(&name) {
+            Some(file) => file,
+            None => return 9, // Invalid FCB
+        };
+        if file.seek(SeekFrom::Start(record as u64 * RECORD_SIZE as u64)).is_err() {
+            return 1;
+        }
+
+        let mut buffer = [EOF_PADDING; RECORD_SIZE];
+        if write {
+            for (i, value) in buffer.iter_mut().enumerate() {
+                *value = machine.peek(self.dma.wrapping_add(i as u16));
+            }
+            if file.write_all(&buffer).is_err() {
+                return 2; // Disk full
+            }
+        } else {
+            let mut size = 0;
+            while size < RECORD_SIZE {
+                match file.read(&mut buffer[size..]) {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => size += n,
+                }
+            }
+            if size == 0 {
+                return 1; // End of file
+            }
+            for (i, value) in buffer.iter().enumerate() {
+                machine.poke(self.dma.wrapping_add(i as u16), *value);
+            }
+        }
+
+        if !random {
+            let next = record + 1;
+            machine.poke(fcb + 12, ((next >> 7) & 0x1f) as u8);
+            machine.poke(fcb + 14, (next >> 12) as u8);
+            machine.poke(fcb + 32, (next & 0x7f) as u8);
+        }
+        0
+    }
+}
+
+fn fcb_name(machine: &Mbc2Machine, fcb: u16) -> String {
+    let field = |start: u16, size: u16| -> String {
+        (start..start + size).map(|i| (machine.peek(fcb + i) & 0x7f) as char)
+            .collect::<String>().trim_end().to_uppercase()
+    };
+    let name = field(1, 8);
+    let ext = field(9, 3);
+    if ext.is_empty() {
+        name
+    } else {
+        format!("{}.{}", name, ext)
+    }
+}
+
+// Only the names of a file on the host folder, without paths
+fn valid_name(name: &str) -> bool {
+    if name.is_empty() || name.contains(['/', '\\']) || name.contains("..") {
+        return false;
+    }
+    let mut components = Path::new(name).components();
+    matches!((components.next(), components.next()), (Some(Component::Normal(_)), None))
+}
+
+// Sequential position from the extent and the current record
+fn fcb_record(machine: &Mbc2Machine, fcb: u16) -> u32 {
+    ((machine.peek(fcb + 14) as u32 & 0x3f) << 12)
+        + ((machine.peek(fcb + 12) as u32 & 0x1f) << 7)
+        + (machine.peek(fcb + 32) as u32 & 0x7f)
+}
+
+fn set_random_record(machine: &mut Mbc2Machine, fcb: u16, record: u32) {
+    machine.poke(fcb + 33, record as u8);
+    machine.poke(fcb + 34, (record >> 8) as u8);
+    machine.poke(fcb + 35, (record >> 16) as u8);
+}
+
+fn matches_pattern(name: &str, pattern: &[u8]) -> bool {
+    // Host names that are not valid on CP/M are skipped
+    if name.len() > 12 || name.starts_with('.') {
+        return false;
+    }
+    let fcb = parse_fcb(name);
+    if name.split('.').next().is_some_and(|base| base.len() > 8)
+            || name.split('.').nth(1).is_some_and(|ext| ext.len() > 3) {
+        return false;
+    }
+    pattern.iter().zip(fcb[1..12].iter()).all(|(p, c)| *p == b'?' || p == c)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::rc::Rc;
+
+    use super::super::console::Console;
+
+    const FCB: u16 = 0x1000;
+    const DMA: u16 = 0x2000;
+
+    struct TestConsole {
+        input: VecDeque<u8>,
+        output: Rc<RefCell<Vec<u8>>>,
+    }
+
+    impl Console for TestConsole {
+        fn status(&mut self) -> bool {
+            !self.input.is_empty()
+        }
+
+        fn read(&mut self) -> u8 {
+            self.input.pop_front().unwrap_or(3)
+        }
+
+        fn put(&mut self, ch: u8) {
+            self.output.borrow_mut().push(ch);
+        }
+    }
+
+    struct Test {
+        bdos: Bdos,
+        cpu: Cpu,
+        machine: Mbc2Machine,
+        output: Rc<RefCell<Vec<u8>>>,
+        folder: PathBuf,
+    }
+
+    impl Test {
+        fn new(name: &str, input: &str) -> Test {
+            let folder = std::env::temp_dir().join(format!("z80-mbc2-bdos-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&folder);
+            fs::create_dir_all(&folder).unwrap();
+            let output = Rc::new(RefCell::new(Vec::new()));
+            let console = TestConsole {
+                input: input.bytes().collect(),
+                output: output.clone(),
+            };
+            let mut test = Test {
+                bdos: Bdos::new(folder.clone()),
+                cpu: Cpu::new_z80(),
+                machine: Mbc2Machine::with_console(Box::new(console)),
+                output,
+                folder,
+            };
+            test.call(26, DMA);
+            test
+        }
+
+        // Returns A, or None when the emulation ends
+        fn call(&mut self, function: u8, de: u16) -> Option<u8> {
+            self.cpu.registers().set8(Reg8::C, function);
+            self.cpu.registers().set16(Reg16::DE, de);
+            if self.bdos.call(&mut self.cpu, &mut self.machine) {
+                Some(self.cpu.registers().a())
+            } else {
+                None
+            }
+        }
+
+        fn set_fcb(&mut self, name: &str) {
+            for (i, value) in parse_fcb(name).iter().enumerate() {
+                self.machine.poke(FCB + i as u16, *value);
+            }
+            for i in 16..36 {
+                self.machine.poke(FCB + i, 0);
+            }
+        }
+
+        fn fill_dma(&mut self, value: u8) {
+            for i in 0..RECORD_SIZE as u16 {
+                self.machine.poke(DMA + i, value);
+            }
+        }
+    }
+
+    impl Drop for Test {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.folder);
+        }
+    }
+
+    #[test]
+    fn file_control_blocks() {
+        assert_eq!(&parse_fcb("b:test.com"), b"\x02TEST    COM\0\0\0\0");
+        assert_eq!(&parse_fcb("*.C*"), b"\0????????C??\0\0\0\0");
+        assert!(matches_pattern("TEST.COM", b"T???????COM"));
+        assert!(!matches_pattern("TEST.COM", b"T???????BAS"));
+        assert!(!matches_pattern("TOOLONGNAME.COM", b"???????????"));
+        assert!(!matches_pattern(".HIDDEN", b"???????????"));
+    }
+
+    #[test]
+    fn make_write_and_read() {
+        let mut test = Test::new("write", "");
+        test.set_fcb("DATA.BIN");
+        assert_eq!(test.call(22, FCB), Some(0));
+        for record in 0..130 {
+            test.fill_dma(record);
+            assert_eq!(test.call(21, FCB), Some(0));
+        }
+        // The next record is on the second extent
+        assert_eq!((test.machine.peek(FCB + 12), test.machine.peek(FCB + 32)), (1, 2));
+        assert_eq!(test.call(16, FCB), Some(0));
+        assert_eq!(fs::metadata(test.folder.join("DATA.BIN")).unwrap().len(), 130 * 128);
+
+        test.set_fcb("data.bin");
+        assert_eq!(test.call(15, FCB), Some(0));
+        assert_eq!(test.machine.peek(FCB + 15), 128);
+        for record in 0..130 {
+            assert_eq!(test.call(20, FCB), Some(0));
+            assert_eq!(test.machine.peek(DMA + 127), record);
+        }
+        assert_eq!(test.call(20, FCB), Some(1)); // End of file
+
+        // Random access
+        test.machine.poke(FCB + 33, 129);
+        assert_eq!(test.call(33, FCB), Some(0));
+        assert_eq!(test.machine.peek(DMA), 129);
+        test.fill_dma(0xaa);
+        test.machine.poke(FCB + 33, 200);
+        assert_eq!(test.call(34, FCB), Some(0));
+        assert_eq!(test.call(35, FCB), Some(0));
+        assert_eq!(test.machine.peek(FCB + 33), 201);
+        assert_eq!(test.call(16, FCB), Some(0));
+    }
+
+    #[test]
+    fn names_outside_the_folder() {
+        let mut test = Test::new("paths", "");
+        fs::write(test.folder.join("KEEP.TXT"), "keep").unwrap();
+        let outside = test.folder.parent().unwrap().join("FOO");
+        for name in [b"../FOO     ", b"/TMP       ", b"..\\FOO     "] {
+            test.set_fcb("");
+            for (i, value) in name.iter().enumerate() {
+                test.machine.poke(FCB + 1 + i as u16, *value);
+            }
+            assert_eq!(test.call(22, FCB), Some(0xff)); // Make
+            assert_eq!(test.call(15, FCB), Some(0xff)); // Open
+            assert_eq!(test.call(35, FCB), Some(0xff)); // Size
+        }
+        assert!(!outside.exists());
+
+        // Rename to a name outside the folder
+        test.set_fcb("KEEP.TXT");
+        for (i, value) in b"../FOO     ".iter().enumerate() {
+            test.machine.poke(FCB + 17 + i as u16, *value);
+        }
+        assert_eq!(test.call(23, FCB), Some(0xff));
+        assert!(test.folder.join("KEEP.TXT").exists());
+        assert!(!outside.exists());
+    }
+
+    #[test]
+    fn short_records_and_errors() {
+        let mut test = Test::new("short", "");
+        fs::write(test.folder.join("short.txt"), "hello").unwrap();
+        test.set_fcb("SHORT.TXT");
+        assert_eq!(test.call(20, FCB), Some(9)); // Not opened
+        assert_eq!(test.call(15, FCB), Some(0));
+        assert_eq!(test.machine.peek(FCB + 15), 1);
+        assert_eq!(test.call(20, FCB), Some(0));
+        assert_eq!(test.machine.peek(DMA + 4), b'o');
+        assert_eq!(test.machine.peek(DMA + 5), EOF_PADDING);
+
+        test.set_fcb("MISSING.TXT");
+        assert_eq!(test.call(15, FCB), Some(0xff));
+        assert_eq!(test.call(16, FCB), Some(0xff));
+        assert_eq!(test.call(19, FCB), Some(0xff));
+    }
+
+    #[test]
+    fn search_rename_and_delete() {
+        let mut test = Test::new("search", "");
+        fs::write(test.folder.join("b.com"), vec![0; 300]).unwrap();
+        fs::write(test.folder.join("a.com"), "").unwrap();
+        fs::write(test.folder.join("c.txt"), "").unwrap();
+        test.set_fcb("*.COM");
+        assert_eq!(test.call(17, FCB), Some(0));
+        assert_eq!(test.machine.peek(DMA + 1), b'A');
+        assert_eq!(test.call(18, 0), Some(0));
+        assert_eq!(test.machine.peek(DMA + 1), b'B');
+        assert_eq!(test.machine.peek(DMA + 15), 3);
+        assert_eq!(test.call(18, 0), Some(0xff));
+
+        test.set_fcb("C.TXT");
+        for (i, value) in parse_fcb("D.TXT").iter().enumerate() {
+            test.machine.poke(FCB + 16 + i as u16, *value);
+        }
+        assert_eq!(test.call(23, FCB), Some(0));
+        assert!(test.folder.join("D.TXT").exists());
+
+        test.set_fcb("?.COM");
+        assert_eq!(test.call(19, FCB), Some(0));
+        assert!(!test.folder.join("a.com").exists());
+        assert!(!test.folder.join("b.com").exists());
+        assert_eq!(test.call(17, FCB), Some(0xff));
+    }
+
+    #[test]
+    fn console_strings() {
+        let mut test = Test::new("console", "ab\x08c\rxy\x03");
+        // No '$', the output stops after the whole memory
+        assert_eq!(test.call(9, 0x8000), Some(0));
+        assert_eq!(test.output.borrow().len(), 0x10000);
+        test.output.borrow_mut().clear();
+
+        test.machine.poke(0x3000, 2);
+        assert_eq!(test.call(10, 0x3000), Some(0));
+        assert_eq!(test.machine.peek(0x3001), 2);
+        assert_eq!((test.machine.peek(0x3002), test.machine.peek(0x3003)), (b'a', b'c'));
+        assert_eq!(&test.output.borrow()[..], b"ab\x08 \x08c\r\n");
+        // Control C is a warm boot
+        assert_eq!(test.call(10, 0x3000), None);
+    }
+}
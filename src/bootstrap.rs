@@ -0,0 +1,170 @@
+use std::cell::Cell;
+
+use chrono::Local;
+use iz80::*;
+
+use super::images::ImageDefinition;
+use super::mbc2_machine::{Mbc2Machine, RTC_TEMPERATURE};
+
+// Emulation of the IOS bootstrap phase. The ATmega does not poke the boot
+// program, it holds the data bus with generated instructions while the Z80
+// runs them:
+//    LD HL,address; LD (HL),n; INC HL; ... for each byte of the program
+//    LD HL,0000h; LD (HL),C3h; INC HL; ... a JP to the start address if not 0
+// IOS starts on the load address, the user images can have another one.
+// Then the Z80 is reset. The registers not changed by the reset keep the
+// values left by the loader, HL points after the last byte loaded or after
+// the JP.
+
+const LD_HL_NN: u8 = 0x21;
+const LD_MHL_N: u8 = 0x36;
+const INC_HL: u8 = 0x23;
+const JP_NN: u8 = 0xc3;
+
+// The memory writes go to the RAM, the opcode fetches and the reads get the
+// next byte driven by the ATmega, whatever the address.
+struct InjectionBus<'a> {
+    machine: &'a mut Mbc2Machine,
+    stream: Vec<u8>,
+    next: Cell<usize>,
+}
+
+impl<'a> InjectionBus<'a> {
+    fn done(&self) -> bool {
+        self.next.get() >= self.stream.len()
+    }
+}
+
+impl<'a> Machine for InjectionBus<'a> {
+    fn peek(&self, _address: u16) -> u8 {
+        let next = self.next.get();
+        self.next.set(next + 1);
+        self.stream.get(next).copied().unwrap_or(0) // NOP
+    }
+
+    fn poke(&mut self, address: u16, value: u8) {
+        self.machine.poke(address, value);
+    }
+
+    fn port_in(&mut self, _address: u16) -> u8 {
+        0xff
+    }
+
+    fn port_out(&mut self, _address: u16, _value: u8) {}
+}
+
+fn load_byte(stream: &mut Vec<u8>, value: u8) {
+    stream.extend_from_slice(&[LD_MHL_N, value, INC_HL]);
+}
+
+fn load_hl(stream: &mut Vec<u8>, value: u16) {
+    stream.extend_from_slice(&[LD_HL_NN, value as u8, (value >> 8) as u8]);
+}
+
+pub fn bootstrap(cpu: &mut Cpu, machine: &mut Mbc2Machine, address: u16, start: u16, data: &[u8]) {
+    let mut stream = Vec::new();
+    load_hl(&mut stream, address);
+    for value in data.iter() {
+        load_byte(&mut stream, *value);
+    }
+    if start != 0 {
+        load_hl(&mut stream, 0x0000);
+        load_byte(&mut stream, JP_NN);
+        load_byte(&mut stream, start as u8);
+        load_byte(&mut stream, (start >> 8) as u8);
+    }
+
+    // The loader runs on the OS bank 0
+    machine.set_bank(0);
+    let mut bus = InjectionBus {
+        machine,
+        stream,
+        next: Cell::new(0),
+    };
+    while !bus.done() {
+        cpu.execute_instruction(&mut bus);
+    }
+
+    // Reset of the Z80, AF and SP were 0xFFFF from the power on
+    let regs = cpu.registers();
+    regs.set8(Reg8::I, 0);
+    regs.set8(Reg8::R, 0);
+    regs.set_pc(0x0000);
+}
+
+// The messages of IOS on the console while booting, with the state of the
+// emulated machine: the firmware, the clock, the RTC, that has the host
+// time, and the autoexec flag.
+pub fn boot_messages(machine: &Mbc2Machine, image: &ImageDefinition) -> String {
+    let now = Local::now();
+    let mut text = "\r\nZ80-MBC2 - A040618\r\n".to_string();
+    text += &format!("IOS - I/O Subsystem - {}\r\n\r\n", machine.firmware().name());
+    text += &format!("IOS: Z80 clock set at {}MHz\r\n", machine.clock_mhz());
+    text += &format!("IOS: Found RTC DS3231 Module ({})\r\n", now.format("%d/%m/%y %H:%M:%S"));
+    text += &format!("IOS: RTC DS3231 temperature sensor: {}C\r\n", RTC_TEMPERATURE);
+    text += &format!("IOS: CP/M Autoexec is {}\r\n", if machine.autoexec() { "ON" } else { "OFF" });
+    if image.disk_set != 0xff {
+        text += &format!("IOS: Current Disk Set {} ({})\r\n", image.disk_set, image.name);
+    }
+    if !image.iload {
+        text += &format!("IOS: Loading boot program ({})... Done\r\n", image.file.to_uppercase());
+    }
+    text += "IOS: Z80 is running from now\r\n\r\n";
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::console::NullConsole;
+    use super::super::firmware::Firmware;
+    use super::super::images::all_images;
+
+    fn boot(address: u16, start: u16, data: &[u8]) -> (Cpu, Mbc2Machine) {
+        let mut cpu = Cpu::new_z80();
+        let mut machine = Mbc2Machine::with_console(Box::new(NullConsole));
+        bootstrap(&mut cpu, &mut machine, address, start, data);
+        (cpu, machine)
+    }
+
+    #[test]
+    fn load_and_jump() {
+        let (mut cpu, machine) = boot(0x0100, 0x0103, &[1, 2, 3, 4]);
+        assert_eq!((0..4).map(|i| machine.peek(0x0100 + i)).collect::<Vec<_>>(), [1, 2, 3, 4]);
+        assert_eq!((0..3).map(|i| machine.peek(i)).collect::<Vec<_>>(), [JP_NN, 0x03, 0x01]);
+        // The JP is the last thing loaded
+        assert_eq!(cpu.registers().get16(Reg16::HL), 0x0003);
+        assert_eq!(cpu.registers().pc(), 0x0000);
+    }
+
+    #[test]
+    fn jump_after_the_payload() {
+        // A program loaded at 0 with another start gets the JP over it
+        let (_, machine) = boot(0x0000, 0x0010, &[0x11; 8]);
+        assert_eq!((0..4).map(|i| machine.peek(i)).collect::<Vec<_>>(), [JP_NN, 0x10, 0x00, 0x11]);
+    }
+
+    #[test]
+    fn start_on_the_load_address() {
+        let (mut cpu, machine) = boot(0x0000, 0x0000, &[0xaa, 0xbb]);
+        assert_eq!((machine.peek(0), machine.peek(1), machine.peek(2)), (0xaa, 0xbb, 0x00));
+        assert_eq!(cpu.registers().get16(Reg16::HL), 0x0002);
+    }
+
+    #[test]
+    fn messages_from_the_machine() {
+        let images = all_images(Vec::new());
+        let cpm22 = images.iter().find(|i| i.id == "cpm22").unwrap();
+        let mut machine = Mbc2Machine::with_console(Box::new(NullConsole));
+        machine.set_firmware(Firmware::R240620);
+        machine.set_clock(4);
+        let text = boot_messages(&machine, cpm22);
+        assert!(text.starts_with("\r\nZ80-MBC2 - A040618\r\nIOS - I/O Subsystem - S220718-R240620\r\n"));
+        assert!(text.contains("IOS: Z80 clock set at 4MHz\r\n"));
+        assert!(text.contains(&format!("temperature sensor: {}C", RTC_TEMPERATURE)));
+        assert!(text.contains("IOS: CP/M Autoexec is OFF\r\n"));
+        assert!(text.contains("IOS: Current Disk Set 0 (CP/M 2.2)\r\n"));
+        assert!(text.contains("(CPM22.BIN)... Done"));
+        assert!(text.ends_with("IOS: Z80 is running from now\r\n\r\n"));
+    }
+}
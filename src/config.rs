@@ -0,0 +1,424 @@
+use std::fs;
+use std::io;
+
+use super::cpmfs::Dpb;
+use super::convert::DSK_DPB;
+use super::faults::FaultRule;
+use super::filesystem::{DiskGeometry, DEFAULT_GEOMETRY};
+use super::images::ImageDefinition;
+use super::options::parse_load;
+use super::serial::Serial;
+use super::timing::Timing;
+
+// Configuration file, an INI file with sections like:
+//
+//    [diskset.2]
+//    tracks = 512
+//    sectors = 32
+//    sector_size = 512
+//    dpb = 128,5,1,2043,511,1
+//
+//    [faults]
+//    rule = read:disk=2,track=5,after=10
+//    rule = sdmount:count=1
+//
+//    [timing]
+//    readsect = 16000
+//
+//    [serial]
+//    baud = 115200
+//
+//    [image.mymon]
+//    name = My monitor
+//    file = mymon.bin
+//    address = 0x0000
+//    disk_set = 0
+//    int_rx = true
+//    int_sys_tick = false
+//    pc = 0x0000
+//    load = symbols.bin@0x8000
+//
+pub const DEFAULT_CONFIG_FILE: &str = "z80-mbc2-emu.ini";
+
+pub struct Config {
+    disk_sets: Vec<(u8, DiskGeometry, Option<Dpb>)>,
+    pub fault_rules: Vec<FaultRule>,
+    pub images: Vec<ImageDefinition>,
+    pub timing: Timing,
+    pub serial: Serial,
+}
+
+struct Section {
+    name: String,
+    line: usize,
+    entries: Vec<(String, String, usize)>,
+}
+
+impl Config {
+    pub fn new() -> Config {
+        Config {
+            disk_sets: Vec::new(),
+            fault_rules: Vec::new(),
+            images: Vec::new(),
+            timing: Timing::new(),
+            serial: Serial::new(),
+        }
+    }
+
+    // Loads the config file. A missing file is an error only if required.
+    pub fn load(filename: &str, required: bool) -> Result<Config, String> {
+        let text = match fs::read_to_string(filename) {
+            Ok(text) => text,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound && !required => {
+                return Ok(Config::new());
+            },
+            Err(e) => return Err(format!("Error reading the config file '{}': {}", filename, e)),
+        };
+
+        Config::parse(&text)
+            .map_err(|e| format!("Error in the config file '{}': {}", filename, e))
+    }
+
+    fn parse(text: &str) -> Result<Config, String> {
+        let sections = parse_ini(text)?;
+        let mut config = Config::new();
+        for section in sections.iter() {
+            config.apply_section(section)?;
+        }
+        Ok(config)
+    }
+
+    pub fn geometry(&self, disk_set: u8) -> DiskGeometry {
+        for (set, geometry, _) in self.disk_sets.iter() {
+            if *set == disk_set {
+                return *geometry;
+            }
+        }
+        DEFAULT_GEOMETRY
+    }
+
+    // The CP/M disk parameters of the disk set, to access the files on the
+    // disks. Only the ones of the CP/M 2.2 disk set are known, the other
+    // disk sets require them on the config file.
+    pub fn dpb(&self, disk_set: u8) -> Result<Dpb, String> {
+        for (set, _, dpb) in self.disk_sets.iter() {
+            if *set == disk_set {
+                if let Some(dpb) = dpb {
+                    return Ok(*dpb);
+                }
+            }
+        }
+        if disk_set == 0 && self.geometry(0) == DEFAULT_GEOMETRY {
+            Ok(DSK_DPB)
+        } else {
+            Err(format!("the CP/M disk parameters of the disk set {} are not known, add them as 'dpb = SPT,BSH,EXM,DSM,DRM,OFF' on the [diskset.{}] section of the config file",
+                disk_set, disk_set))
+        }
+    }
+
+    fn apply_section(&mut self, section: &Section) -> Result<(), String> {
+        if let Some(id) = section.name.strip_prefix("diskset.") {
+            let disk_set = match parse_number(id) {
+                Some(n) if n <= 9 => n as u8,
+                _ => return Err(format!("line {}: invalid disk set '{}'", section.line, id)),
+            };
+            let mut geometry = DEFAULT_GEOMETRY;
+            let mut dpb = None;
+            for (key, value, line) in section.entries.iter() {
+                if key == "dpb" {
+                    dpb = Some(parse_dpb(value)
+                        .map_err(|e| format!("line {}: {}", line, e))?);
+                    continue;
+                }
+                let n = parse_number(value)
+                    .ok_or(format!("line {}: invalid number '{}'", line, value))?;
+                match key.as_str() {
+                    "tracks" if (1..=0x1_0000).contains(&n) => geometry.tracks = n,
+                    "sectors" if (1..=0x100).contains(&n) => geometry.sectors = n,
+                    "sector_size" if (1..=0x1_0000).contains(&n) => geometry.sector_size = n,
+                    "tracks" | "sectors" | "sector_size" => {
+                        return Err(format!("line {}: value out of range for '{}'", line, key));
+                    },
+                    _ => return Err(format!("line {}: unknown key '{}'", line, key)),
+                }
+            }
+            if let Some(dpb) = dpb {
+                if dpb.size() as u64 > geometry.disk_size() {
+                    return Err(format!("line {}: the dpb needs {} bytes, the disks have {}",
+                        section.line, dpb.size(), geometry.disk_size()));
+                }
+            }
+            self.disk_sets.retain(|(set, _, _)| *set != disk_set);
+            self.disk_sets.push((disk_set, geometry, dpb));
+            Ok(())
+        } else if section.name == "faults" {
+            for (key, value, line) in section.entries.iter() {
+                if key != "rule" {
+                    return Err(format!("line {}: unknown key '{}'", line, key));
+                }
+                let rule = FaultRule::parse(value)
+                    .map_err(|e| format!("line {}: {}", line, e))?;
+                self.fault_rules.push(rule);
+            }
+            Ok(())
+        } else if section.name == "timing" {
+            for (key, value, line) in section.entries.iter() {
+                self.timing.set(key, value)
+                    .map_err(|e| format!("line {}: {}", line, e))?;
+            }
+            Ok(())
+        } else if section.name == "serial" {
+            for (key, value, line) in section.entries.iter() {
+                self.serial.set(key, value)
+                    .map_err(|e| format!("line {}: {}", line, e))?;
+            }
+            Ok(())
+        } else if let Some(id) = section.name.strip_prefix("image.") {
+            let image = parse_image(id, section)?;
+            self.images.retain(|i| i.id != image.id);
+            self.images.push(image);
+            Ok(())
+        } else {
+            Err(format!("line {}: unknown section '{}'", section.line, section.name))
+        }
+    }
+}
+
+// SPT,BSH,EXM,DSM,DRM,OFF as on the DPB of the BIOS
+fn parse_dpb(text: &str) -> Result<Dpb, String> {
+    let values: Vec<u32> = text.split(',').map(parse_number).collect::<Option<_>>()
+        .ok_or(format!("invalid dpb '{}'", text))?;
+    let dpb = match values[..] {
+        [spt, bsh, exm, dsm, drm, off] if spt <= 0xffff && (3..=7).contains(&bsh)
+                && exm <= 15 && dsm <= 0xffff && drm <= 0xffff && off <= 0xffff => Dpb {
+            spt: spt as u16,
+            bsh: bsh as u8,
+            exm: exm as u8,
+            dsm: dsm as u16,
+            drm: drm as u16,
+            off: off as u16,
+        },
+        _ => return Err(format!("invalid dpb '{}', expected SPT,BSH,EXM,DSM,DRM,OFF", text)),
+    };
+    if !dpb.is_valid() {
+        return Err(format!("inconsistent dpb '{}'", text));
+    }
+    Ok(dpb)
+}
+
+fn parse_image(id: &str, section: &Section) -> Result<ImageDefinition, String> {
+    if id.is_empty() || id.starts_with('-') || id.contains(char::is_whitespace) {
+        return Err(format!("line {}: invalid image id '{}'", section.line, id));
+    }
+    let mut image = ImageDefinition {
+        id: id.to_string(),
+        name: id.to_string(),
+        file: String::new(),
+        address: 0,
+        disk_set: 0xff,
+        int_rx: false,
+        int_sys_tick: false,
+        pc: None,
+        loads: Vec::new(),
+        iload: false,
+    };
+
+    for (key, value, line) in section.entries.iter() {
+        let address = || match parse_number(value) {
+            Some(n) if n <= 0xffff => Ok(n as u16),
+            _ => Err(format!("line {}: invalid address '{}'", line, value)),
+        };
+        let flag = || parse_bool(value)
+            .ok_or(format!("line {}: expected true or false for '{}'", line, key));
+        match key.as_str() {
+            "name" => image.name = value.clone(),
+            "file" => image.file = value.clone(),
+            "address" => image.address = address()?,
+            "pc" => image.pc = Some(address()?),
+            "disk_set" => image.disk_set = match parse_number(value) {
+                Some(n) if n <= 9 || n == 0xff => n as u8,
+                _ => return Err(format!("line {}: invalid disk set '{}'", line, value)),
+            },
+            "int_rx" => image.int_rx = flag()?,
+            "int_sys_tick" => image.int_sys_tick = flag()?,
+            "load" => image.loads.push(parse_load(value, 0)
+                .map_err(|e| format!("line {}: {}", line, e))?),
+            _ => return Err(format!("line {}: unknown key '{}'", line, key)),
+        }
+    }
+
+    if image.file.is_empty() {
+        return Err(format!("line {}: missing 'file' for image '{}'", section.line, id));
+    }
+    Ok(image)
+}
+
+fn parse_ini(text: &str) -> Result<Vec<Section>, String> {
+    let mut sections: Vec<Section> = Vec::new();
+    for (i, raw_line) in text.lines().enumerate() {
+        let line = raw_line.trim();
+        let number = i + 1;
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+
+        if line.starts_with('[') {
+            if !line.ends_with(']') {
+                return Err(format!("line {}: invalid section header", number));
+            }
+            sections.push(Section {
+                name: line[1..line.len()-1].trim().to_lowercase(),
+                line: number,
+                entries: Vec::new(),
+            });
+        } else if let Some(pos) = line.find('=') {
+            let key = line[..pos].trim().to_lowercase();
+            let value = unquote(line[pos+1..].trim());
+            match sections.last_mut() {
+                Some(section) => section.entries.push((key, value, number)),
+                None => return Err(format!("line {}: entry outside of a section", number)),
+            }
+        } else {
+            return Err(format!("line {}: expected 'key = value'", number));
+        }
+    }
+    Ok(sections)
+}
+
+fn unquote(value: &str) -> String {
+    if value.len() >= 2 && value.starts_with('"') && value.ends_with('"') {
+        value[1..value.len()-1].to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+fn parse_bool(text: &str) -> Option<bool> {
+    match text.to_lowercase().as_str() {
+        "true" | "yes" | "on" | "1" => Some(true),
+        "false" | "no" | "off" | "0" => Some(false),
+        _ => None,
+    }
+}
+
+// Decimal or hexadecimal with the 0x prefix
+pub fn parse_number(text: &str) -> Option<u32> {
+    let text = text.trim();
+    if let Some(hex) = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        u32::from_str_radix(hex, 16).ok()
+    } else {
+        text.parse::<u32>().ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn disk_sets() {
+        let config = Config::parse("
+            # Comment
+            [diskset.2]
+            tracks = 0x100
+            ; Comment
+            sectors = 16
+
+            [DISKSET.3]
+            sector_size = 128
+            ").unwrap();
+        let geometry = config.geometry(2);
+        assert_eq!((geometry.tracks, geometry.sectors, geometry.sector_size),
+            (256, 16, DEFAULT_GEOMETRY.sector_size));
+        assert_eq!(config.geometry(3).sector_size, 128);
+        assert_eq!(config.geometry(4), DEFAULT_GEOMETRY);
+    }
+
+    #[test]
+    fn disk_parameters() {
+        let config = Config::parse("
+            [diskset.2]
+            dpb = 128,5,1,2043,1023,1
+            [diskset.3]
+            tracks = 256
+            ").unwrap();
+        assert_eq!(config.dpb(0).unwrap(), DSK_DPB);
+        assert_eq!(config.dpb(2).unwrap().drm, 1023);
+        assert!(config.dpb(3).is_err());
+        assert!(config.dpb(6).is_err());
+
+        let error = |text: &str| Config::parse(text).err().unwrap();
+        assert_eq!(error("[diskset.1]\ndpb = 128,5,1"),
+            "line 2: invalid dpb '128,5,1', expected SPT,BSH,EXM,DSM,DRM,OFF");
+        assert_eq!(error("[diskset.1]\ndpb = 128,5,0,2043,511,1"),
+            "line 2: inconsistent dpb '128,5,0,2043,511,1'");
+        assert!(error("[diskset.1]\ntracks = 256\ndpb = 128,5,1,2043,511,1")
+            .starts_with("line 1: the dpb needs"));
+    }
+
+    #[test]
+    fn images() {
+        let config = Config::parse("
+            [image.mymon]
+            name = \"My monitor\"
+            file = mymon.bin
+            address = 0x8000
+            disk_set = 2
+            int_rx = yes
+            load = symbols.bin@0x9000
+            ").unwrap();
+        let image = &config.images[0];
+        assert_eq!(image.id, "mymon");
+        assert_eq!(image.name, "My monitor");
+        assert_eq!(image.file, "mymon.bin");
+        assert_eq!(image.address, 0x8000);
+        assert_eq!(image.disk_set, 2);
+        assert!(image.int_rx);
+        assert!(!image.int_sys_tick);
+        assert_eq!(image.pc, None);
+        assert_eq!(image.loads[0].address, Some(0x9000));
+    }
+
+    #[test]
+    fn faults_timing_and_serial() {
+        let config = Config::parse("
+            [faults]
+            rule = read:disk=2
+            rule = sdmount:count=1
+            [timing]
+            clock = 4
+            [serial]
+            baud = 9600
+            flow = rtscts
+            ").unwrap();
+        assert_eq!(config.fault_rules.len(), 2);
+        assert_eq!(config.timing.clock_mhz, 4);
+        assert_eq!(config.serial.baud, 9600);
+        assert!(config.serial.rts_cts);
+    }
+
+    #[test]
+    fn errors_with_line() {
+        let error = |text: &str| Config::parse(text).err().unwrap();
+        assert_eq!(error("tracks = 1"), "line 1: entry outside of a section");
+        assert_eq!(error("[diskset.2"), "line 1: invalid section header");
+        assert_eq!(error("[diskset.10]"), "line 1: invalid disk set '10'");
+        assert_eq!(error("[diskset.1]\ntracks"), "line 2: expected 'key = value'");
+        assert_eq!(error("[diskset.1]\n\nsectors = 257"), "line 3: value out of range for 'sectors'");
+        assert_eq!(error("[diskset.1]\nheads = 2"), "line 2: unknown key 'heads'");
+        assert_eq!(error("[printer]"), "line 1: unknown section 'printer'");
+        assert_eq!(error("[image.x]\nname = X"), "line 1: missing 'file' for image 'x'");
+        assert_eq!(error("[image.x]\nfile = x.bin\nint_rx = maybe"),
+            "line 3: expected true or false for 'int_rx'");
+        assert_eq!(error("[faults]\nrule = format"), "line 2: unknown fault operation 'format'");
+    }
+
+    #[test]
+    fn numbers() {
+        assert_eq!(parse_number(" 42 "), Some(42));
+        assert_eq!(parse_number("0x1F"), Some(31));
+        assert_eq!(parse_number("0X1f"), Some(31));
+        assert_eq!(parse_number("1F"), None);
+        assert_eq!(parse_number("-1"), None);
+    }
+}
@@ -0,0 +1,46 @@
+use std::thread;
+use std::time::Duration;
+
+// Console of the serial port of the Z80-MBC2
+pub trait Console {
+    // True if there is a char waiting
+    fn status(&mut self) -> bool;
+    // Blocks waiting for a char
+    fn read(&mut self) -> u8;
+    fn put(&mut self, ch: u8);
+
+    // Waits for a char up to the timeout, true if there is one
+    fn wait(&mut self, timeout: Duration) -> bool {
+        if !self.status() {
+            thread::sleep(timeout);
+        }
+        self.status()
+    }
+
+    // True if control-c and the monitor key are for the emulator, called after
+    // reading the char
+    fn host_keys(&self) -> bool {
+        true
+    }
+
+    // Exit code when the console has nothing more to do and the emulation
+    // can end
+    fn exit_code(&self) -> Option<i32> {
+        None
+    }
+}
+
+// Placeholder while a console is replaced
+pub struct NullConsole;
+
+impl Console for NullConsole {
+    fn status(&mut self) -> bool {
+        false
+    }
+
+    fn read(&mut self) -> u8 {
+        0xff
+    }
+
+    fn put(&mut self, _ch: u8) {}
+}
@@ -0,0 +1,166 @@
+use std::ffi::CStr;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::{symlink, OpenOptionsExt};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use termios::*;
+
+use super::console::Console;
+
+// Console of the serial port on a pseudo-terminal, to use the emulator as a
+// board connected to a serial device with minicom, kermit or the uploaders.
+// The programs can close the device and open it again, the output is lost
+// while it is not open. The chars go to the Z80 as they are, control-c
+// and the monitor key are not used by the emulator.
+
+const POLL_MS: i32 = 100;
+const HANGUP_RETRY: Duration = Duration::from_millis(100);
+
+pub struct PtyConsole {
+    master: Arc<File>,
+    receiver: Receiver<u8>,
+    next_char: Option<u8>,
+    connected: Arc<AtomicBool>,
+    closed: Arc<AtomicBool>,
+    link: Option<String>,
+}
+
+impl PtyConsole {
+    pub fn open(link: Option<&str>) -> Result<PtyConsole, String> {
+        let error = |e: io::Error| format!("Error creating the pty: {}", e);
+        let fd = unsafe { libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY | libc::O_NONBLOCK) };
+        if fd < 0 {
+            return Err(error(io::Error::last_os_error()));
+        }
+        let master = unsafe { File::from_raw_fd(fd) };
+        if unsafe { libc::grantpt(fd) } != 0 || unsafe { libc::unlockpt(fd) } != 0 {
+            return Err(error(io::Error::last_os_error()));
+        }
+        let name = unsafe { libc::ptsname(fd) };
+        if name.is_null() {
+            return Err(error(io::Error::last_os_error()));
+        }
+        let path = unsafe { CStr::from_ptr(name) }.to_string_lossy().into_owned();
+
+        // Raw mode, it is kept when the programs close the device
+        let slave = OpenOptions::new().read(true).write(true)
+            .custom_flags(libc::O_NOCTTY).open(&path).map_err(error)?;
+        let mut raw = Termios::from_fd(slave.as_raw_fd()).map_err(error)?;
+        cfmakeraw(&mut raw);
+        tcsetattr(slave.as_raw_fd(), TCSANOW, &raw).map_err(error)?;
+        drop(slave);
+
+        if let Some(link) = link {
+            if let Ok(metadata) = fs::symlink_metadata(link) {
+                if !metadata.file_type().is_symlink() {
+                    return Err(format!("Error creating the link to the pty: '{}' is not a link", link));
+                }
+                fs::remove_file(link)
+                    .map_err(|e| format!("Error creating the link to the pty '{}': {}", link, e))?;
+            }
+            symlink(&path, link)
+                .map_err(|e| format!("Error creating the link to the pty '{}': {}", link, e))?;
+            println!("Serial console on {}, linked from {}", path, link);
+        } else {
+            println!("Serial console on {}", path);
+        }
+
+        let master = Arc::new(master);
+        let connected = Arc::new(AtomicBool::new(false));
+        let closed = Arc::new(AtomicBool::new(false));
+        let (sender, receiver) = channel();
+        let (thread_master, thread_connected, thread_closed) =
+            (master.clone(), connected.clone(), closed.clone());
+        thread::spawn(move || {
+            let mut buf = [0; 256];
+            while !thread_closed.load(Ordering::Relaxed) {
+                let mut poll_fd = libc::pollfd {
+                    fd: thread_master.as_raw_fd(),
+                    events: libc::POLLIN,
+                    revents: 0,
+                };
+                if unsafe { libc::poll(&mut poll_fd, 1, POLL_MS) } < 0 {
+                    continue; // Interrupted
+                }
+                if poll_fd.revents & libc::POLLHUP != 0 {
+                    // The device is not open
+                    if thread_connected.swap(false, Ordering::Relaxed) {
+                        println!("<<pty closed, waiting for a new connection>>");
+                    }
+                    thread::sleep(HANGUP_RETRY);
+                    continue;
+                }
+                if !thread_connected.swap(true, Ordering::Relaxed) {
+                    println!("<<pty connected>>");
+                }
+                if poll_fd.revents & libc::POLLIN != 0 {
+                    if let Ok(size) = (&*thread_master).read(&mut buf) {
+                        for ch in buf[..size].iter() {
+                            if sender.send(*ch).is_err() {
+                                return;
+                            }
+                        }
+                    }
+                }
+            }
+        });
+
+        Ok(PtyConsole {
+            master,
+            receiver,
+            next_char: None,
+            connected,
+            closed,
+            link: link.map(|l| l.to_string()),
+        })
+    }
+}
+
+impl Console for PtyConsole {
+    fn status(&mut self) -> bool {
+        if self.next_char.is_none() {
+            self.next_char = self.receiver.try_recv().ok();
+        }
+        self.next_char.is_some()
+    }
+
+    fn read(&mut self) -> u8 {
+        match self.next_char.take() {
+            Some(ch) => ch,
+            None => self.receiver.recv().unwrap_or(0xff),
+        }
+    }
+
+    fn put(&mut self, ch: u8) {
+        if self.connected.load(Ordering::Relaxed) {
+            // Lost if the program is not reading
+            let _ = (&*self.master).write(&[ch]);
+        }
+    }
+
+    fn wait(&mut self, timeout: Duration) -> bool {
+        if self.next_char.is_none() {
+            self.next_char = self.receiver.recv_timeout(timeout).ok();
+        }
+        self.next_char.is_some()
+    }
+
+    fn host_keys(&self) -> bool {
+        false
+    }
+}
+
+impl Drop for PtyConsole {
+    fn drop(&mut self) {
+        self.closed.store(true, Ordering::Relaxed);
+        if let Some(link) = self.link.as_ref() {
+            let _ = fs::remove_file(link);
+        }
+    }
+}
@@ -1,84 +1,118 @@
-use std::io::{Read, stdin, Write, stdout};
-use std::thread;
+use std::io::{Error, ErrorKind, Write, stdout};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
 use std::time::Duration;
 
 use termios::*;
 
+use super::console::Console;
+
 const STDIN_FD: i32 = 0;
+const POLL_MS: i32 = 100;
 
-pub struct Console {
+// The input is read on a thread and sent on a channel, the status is a
+// check of the channel. The thread polls stdin to stop when the console is
+// dropped, as when it is replaced by another console.
+pub struct TerminalConsole {
     initial_termios: Option<Termios>,
+    receiver: Receiver<u8>,
     next_char: Option<u8>,
+    stopped: Arc<AtomicBool>,
+    reader: Option<JoinHandle<()>>,
 }
 
-impl Console {
-    pub fn new() -> Console {
+impl TerminalConsole {
+    pub fn new() -> TerminalConsole {
         // Prepare terminal
         let initial_termios = Termios::from_fd(STDIN_FD).ok();
-
-        let c = Console {
-            initial_termios: initial_termios,
-            next_char: None,
-        };
-
-        c.setup_host_terminal(false);
-        c
-    }
-
-    fn setup_host_terminal(&self, blocking: bool) {
-        if let Some(initial) = self.initial_termios {
-            let mut new_term = initial.clone();
+        if let Some(initial) = initial_termios {
+            let mut new_term = initial;
             new_term.c_iflag &= !(IXON | ICRNL);
             new_term.c_lflag &= !(ISIG | ECHO | ICANON | IEXTEN);
-            new_term.c_cc[VMIN] = if blocking {1} else {0};
+            new_term.c_cc[VMIN] = 1;
             new_term.c_cc[VTIME] = 0;
             tcsetattr(STDIN_FD, TCSANOW, &new_term).unwrap();
         }
-    }
 
-    pub fn status(&mut self) -> bool {
-        match self.next_char {
-            Some(_) => true,
-            None => {
-                let mut buf = [0];
-                let size = stdin().read(&mut buf).unwrap_or(0);
-                if size != 0 {
-                    self.next_char = Some(buf[0]);
-                    true
-                } else {
-                    // Avoid 100% CPU usage waiting for input.
-                    thread::sleep(Duration::from_nanos(100));
-                    false
+        let (sender, receiver) = channel();
+        let stopped = Arc::new(AtomicBool::new(false));
+        let thread_stopped = stopped.clone();
+        let reader = thread::spawn(move || {
+            // Ends at the end of the input or when the console is dropped.
+            // Read without the buffer of stdin, to not keep chars the poll
+            // does not see.
+            let mut buf = [0u8; 256];
+            while !thread_stopped.load(Ordering::Relaxed) {
+                let mut poll_fd = libc::pollfd {
+                    fd: STDIN_FD,
+                    events: libc::POLLIN,
+                    revents: 0,
+                };
+                match unsafe { libc::poll(&mut poll_fd, 1, POLL_MS) } {
+                    0 => continue, // Timeout
+                    n if n < 0 && Error::last_os_error().kind() == ErrorKind::Interrupted => continue,
+                    n if n < 0 => break,
+                    _ => {},
+                }
+                let size = unsafe { libc::read(STDIN_FD, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+                if size <= 0 {
+                    break;
+                }
+                for ch in buf[..size as usize].iter() {
+                    if sender.send(*ch).is_err() {
+                        return;
+                    }
                 }
             }
+        });
+
+        TerminalConsole {
+            initial_termios,
+            receiver,
+            next_char: None,
+            stopped,
+            reader: Some(reader),
         }
     }
+}
 
-    pub fn read(&mut self) -> u8 {
-        match self.next_char {
-            Some(ch) => {
-                self.next_char = None;
-                ch
-            },
-            None => {
-                // Blocks waiting for char
-                self.setup_host_terminal(true);
-                let mut buf = [0];
-                stdin().read(&mut buf).unwrap();
-                self.setup_host_terminal(false);
-                buf[0]
-            }
+impl Console for TerminalConsole {
+    fn status(&mut self) -> bool {
+        if self.next_char.is_none() {
+            self.next_char = self.receiver.try_recv().ok();
+        }
+        self.next_char.is_some()
+    }
+
+    fn read(&mut self) -> u8 {
+        match self.next_char.take() {
+            Some(ch) => ch,
+            // Blocks waiting for char, the end of input is a control-c
+            None => self.receiver.recv().unwrap_or(3),
         }
     }
 
-    pub fn put(&mut self, ch: u8) {
+    fn wait(&mut self, timeout: Duration) -> bool {
+        if self.next_char.is_none() {
+            self.next_char = self.receiver.recv_timeout(timeout).ok();
+        }
+        self.next_char.is_some()
+    }
+
+    fn put(&mut self, ch: u8) {
         print!("{}", ch as char);
         stdout().flush().unwrap();
     }
 }
 
-impl Drop for Console {
+impl Drop for TerminalConsole {
     fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+        if let Some(reader) = self.reader.take() {
+            let _ = reader.join();
+        }
         if let Some(initial) = self.initial_termios {
             tcsetattr(STDIN_FD, TCSANOW, &initial).unwrap();
         }
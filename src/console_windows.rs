@@ -1,4 +1,8 @@
 use std::io::{Write, stdout};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
 use std::time::Duration;
 
 use crossterm::terminal;
@@ -6,69 +10,92 @@ use crossterm::event;
 use crossterm::queue;
 use crossterm::style;
 
-pub struct Console {
+use super::console::Console;
+
+const POLL_TIME: Duration = Duration::from_millis(100);
+
+// The key events are read on a thread and the chars sent on a channel, the
+// status is a check of the channel. The thread polls the events to stop when
+// the console is dropped, as when it is replaced by another console.
+pub struct TerminalConsole {
+    receiver: Receiver<u8>,
     next_char: Option<u8>,
+    stopped: Arc<AtomicBool>,
+    reader: Option<JoinHandle<()>>,
 }
 
-impl Console {
-    pub fn new() -> Console {
+impl TerminalConsole {
+    pub fn new() -> TerminalConsole {
         terminal::enable_raw_mode().unwrap();
 
-        Console {
+        let (sender, receiver) = channel();
+        let stopped = Arc::new(AtomicBool::new(false));
+        let thread_stopped = stopped.clone();
+        let reader = thread::spawn(move || {
+            // Ends on an error or when the console is dropped
+            while !thread_stopped.load(Ordering::Relaxed) {
+                match event::poll(POLL_TIME) {
+                    Ok(true) => {},
+                    Ok(false) => continue,
+                    Err(_) => break,
+                }
+                let event = match event::read() {
+                    Ok(event) => event,
+                    Err(_) => break,
+                };
+                // The events that are not valid chars are ignored
+                if let Some(ch) = event_to_char(event) {
+                    if sender.send(ch).is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+
+        TerminalConsole {
+            receiver,
             next_char: None,
+            stopped,
+            reader: Some(reader),
         }
     }
 }
 
-impl Console {
-    pub fn status(&mut self) -> bool {
-        match self.next_char {
-            Some(_) => true,
-            None => {
-                loop {
-                    if event::poll(Duration::from_nanos(100)).unwrap() {
-                        let event = event::read().unwrap();
-                        let some_ch = event_to_char(event);
-                        if let Some(ch) = some_ch {
-                            self.next_char = Some(ch);
-                            break true
-                        }
-                        // The event is not a valid char, ignore and retry
-                    } else {
-                        break false
-                    }
-                }
-            }
+impl Console for TerminalConsole {
+    fn status(&mut self) -> bool {
+        if self.next_char.is_none() {
+            self.next_char = self.receiver.try_recv().ok();
         }
+        self.next_char.is_some()
     }
 
-    pub fn read(&mut self) -> u8 {
-        match self.next_char {
-            Some(ch) => {
-                self.next_char = None;
-                ch
-            },
-            None => {
-                loop {
-                    let event = event::read().unwrap();
-                    let some_ch = event_to_char(event);
-                    if let Some(ch) = some_ch {
-                        break ch;
-                    }
-                    // The event is not a valid char, ignore and retry
-                }
-            }
+    fn read(&mut self) -> u8 {
+        match self.next_char.take() {
+            Some(ch) => ch,
+            // Blocks waiting for char
+            None => self.receiver.recv().unwrap_or(3),
         }
     }
 
-    pub fn put(&mut self, ch: u8) {
+    fn wait(&mut self, timeout: Duration) -> bool {
+        if self.next_char.is_none() {
+            self.next_char = self.receiver.recv_timeout(timeout).ok();
+        }
+        self.next_char.is_some()
+    }
+
+    fn put(&mut self, ch: u8) {
         queue!(stdout(), style::Print(ch as char)).unwrap();
         stdout().flush().unwrap();
     }
 }
 
-impl Drop for Console {
+impl Drop for TerminalConsole {
     fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+        if let Some(reader) = self.reader.take() {
+            let _ = reader.join();
+        }
         terminal::disable_raw_mode().unwrap();
     }
 }
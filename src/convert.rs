@@ -0,0 +1,480 @@
+use std::fs;
+use std::path::Path;
+
+use super::config::{Config, DEFAULT_CONFIG_FILE};
+use super::cpmfs::{CpmDisk, CpmFile, Dpb, RECORD_SIZE};
+
+// Conversion of the CP/M files between disk image formats:
+//
+//    z80-mbc2-emu convert [--add | --force] SOURCE [TARGET]
+//
+// With only the source the files are listed. An existing target is not
+// replaced without --force, with --add the files are added to it. The
+// images are FORMAT:PATH, the format can be omitted for .DSK and .IMD files
+// and for directories:
+//    dsk     Z80-MBC2 DSxNyy.DSK, of the disk set of the name, dskN for the
+//            disk set N, with the DPB of the disk set
+//    imd     ImageDisk of a 8" SSSD IBM 3740 disk
+//    raw     plain 8" SSSD IBM 3740 image, 77 tracks of 26 sectors of 128 bytes
+//    simh    SIMH AltairZ80 8MB hard disk, 2048 tracks of 32 sectors of 128 bytes
+//    runcpm  RunCPM drive directory, with a subdirectory per user: A/0/FILE.EXT
+//
+// The files are copied through the CP/M directory. The system tracks are not
+// copied as the boot code is specific to each machine.
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Format {
+    Dsk(u8), // Disk set
+    Imd,
+    Raw,
+    Simh,
+    RunCpm,
+}
+
+// DPB of the CP/M 2.2 BIOS of the Z80-MBC2
+pub const DSK_DPB: Dpb = Dpb {spt: 128, bsh: 5, exm: 1, dsm: 2043, drm: 511, off: 1};
+// Standard 8" SSSD disk
+const IBM_3740_DPB: Dpb = Dpb {spt: 26, bsh: 3, exm: 0, dsm: 242, drm: 63, off: 2};
+const IBM_3740_TRACKS: usize = 77;
+const IBM_3740_SKEW: [usize; 26] = [
+    1, 7, 13, 19, 25, 5, 11, 17, 23, 3, 9, 15, 21,
+    2, 8, 14, 20, 26, 6, 12, 18, 24, 4, 10, 16, 22];
+// AltairZ80 HDSK default format
+const SIMH_DPB: Dpb = Dpb {spt: 32, bsh: 5, exm: 1, dsm: 2041, drm: 1023, off: 6};
+const SIMH_SIZE: usize = 2048 * 32 * RECORD_SIZE;
+
+const RUNCPM_USERS: &str = "0123456789ABCDEF";
+
+#[derive(Clone, Copy, PartialEq)]
+enum Target {
+    New,
+    Replace,
+    Add,
+}
+
+pub fn convert(args: &[String]) -> Result<(), String> {
+    let mut target = Target::New;
+    let mut images = Vec::new();
+    for arg in args.iter() {
+        match arg.as_str() {
+            "--force" => target = Target::Replace,
+            "--add" => target = Target::Add,
+            _ if arg.starts_with("--") => return Err(format!("unknown option '{}'", arg)),
+            _ => images.push(arg.as_str()),
+        }
+    }
+    if images.is_empty() || images.len() > 2 {
+        return Err("usage: z80-mbc2-emu convert [--add | --force] SOURCE [TARGET]".to_string());
+    }
+
+    let config = Config::load(DEFAULT_CONFIG_FILE, false)?;
+    let (format, path) = parse_image(images[0])?;
+    let files = read_files(&config, format, path)?;
+
+    if images.len() == 1 {
+        let mut total = 0;
+        for file in files.iter() {
+            println!("{:2}: {:<12} {:8}", file.user, file.name, file.data.len());
+            total += file.data.len();
+        }
+        println!("{} files, {} bytes", files.len(), total);
+        return Ok(());
+    }
+
+    let (format, path) = parse_image(images[1])?;
+    if target == Target::New && Path::new(path).exists() {
+        return Err(format!("'{}' already exists, use --add to add the files or --force to replace it", path));
+    }
+    write_files(&config, format, path, &files, target == Target::Add)?;
+    println!("{} files copied to {}", files.len(), path);
+    Ok(())
+}
+
+fn parse_image(text: &str) -> Result<(Format, &str), String> {
+    if let Some(pos) = text.find(':') {
+        let name = text[..pos].to_lowercase();
+        let format = match name.as_str() {
+            "dsk" => Some(Format::Dsk(disk_set_of(&text[pos+1..]).unwrap_or(0))),
+            _ if name.len() == 4 && name.starts_with("dsk") => {
+                name[3..].parse().ok().map(Format::Dsk)
+            },
+            "imd" => Some(Format::Imd),
+            "raw" => Some(Format::Raw),
+            "simh" => Some(Format::Simh),
+            "runcpm" => Some(Format::RunCpm),
+            _ => None,
+        };
+        if let Some(format) = format {
+            return Ok((format, &text[pos+1..]));
+        }
+    }
+
+    let extension = Path::new(text).extension()
+        .map(|e| e.to_string_lossy().to_lowercase());
+    match extension.as_deref() {
+        Some("dsk") => Ok((Format::Dsk(disk_set_of(text).unwrap_or(0)), text)),
+        Some("imd") => Ok((Format::Imd, text)),
+        _ if Path::new(text).is_dir() => Ok((Format::RunCpm, text)),
+        _ => Err(format!("unknown format for '{}', use FORMAT:PATH", text)),
+    }
+}
+
+// The disk set of a DSxNyy.DSK file name
+fn disk_set_of(path: &str) -> Option<u8> {
+    let name = Path::new(path).file_name()?.to_string_lossy().to_uppercase();
+    match name.as_bytes() {
+        [b'D', b'S', set @ b'0'..=b'9', b'N', b'0'..=b'9', b'0'..=b'9', b'.', b'D', b'S', b'K'] => {
+            Some(set - b'0')
+        },
+        _ => None,
+    }
+}
+
+fn read_disk(config: &Config, format: Format, data: Vec<u8>) -> Result<CpmDisk, String> {
+    let disk = match format {
+        Format::Dsk(disk_set) => CpmDisk::from_logical(config.dpb(disk_set)?, data),
+        Format::Simh => CpmDisk::from_logical(SIMH_DPB, data),
+        Format::Raw => CpmDisk::from_logical(IBM_3740_DPB, unskew(&data)),
+        Format::Imd => CpmDisk::from_logical(IBM_3740_DPB, unskew(&read_imd(&data)?)),
+        Format::RunCpm => unreachable!(),
+    };
+    Ok(disk)
+}
+
+fn read_files(config: &Config, format: Format, path: &str) -> Result<Vec<CpmFile>, String> {
+    if format == Format::RunCpm {
+        return read_runcpm(path);
+    }
+
+    let data = fs::read(path)
+        .map_err(|e| format!("Error reading '{}': {}", path, e))?;
+    read_disk(config, format, data)?.files()
+}
+
+// Writes the files on a new image, or adds them to the existing one
+fn write_files(config: &Config, format: Format, path: &str, files: &[CpmFile], add: bool) -> Result<(), String> {
+    if format == Format::RunCpm {
+        return write_runcpm(path, files);
+    }
+
+    let mut disk = if add {
+        let data = fs::read(path)
+            .map_err(|e| format!("Error reading '{}': {}", path, e))?;
+        read_disk(config, format, data)?
+    } else {
+        read_disk(config, format, Vec::new())?
+    };
+    for file in files.iter() {
+        disk.remove_file(file.user, &file.name);
+        disk.add_file(file)?;
+    }
+
+    let mut data = disk.logical().to_vec();
+    match format {
+        Format::Dsk(disk_set) => data.resize(config.geometry(disk_set).disk_size() as usize, 0xe5),
+        Format::Simh => data.resize(SIMH_SIZE, 0xe5),
+        Format::Raw => data = skew(&data),
+        Format::Imd => data = write_imd(&skew(&data)),
+        Format::RunCpm => unreachable!(),
+    }
+    fs::write(path, data)
+        .map_err(|e| format!("Error writing '{}': {}", path, e))
+}
+
+// Physical sector order of the IBM 3740 image to logical order. The
+// system tracks are not skewed.
+fn unskew(physical: &[u8]) -> Vec<u8> {
+    let track_size = IBM_3740_SKEW.len() * RECORD_SIZE;
+    let mut physical = physical.to_vec();
+    physical.resize(IBM_3740_TRACKS * track_size, 0xe5);
+
+    let mut logical = physical.clone();
+    for track in IBM_3740_DPB.off as usize..IBM_3740_TRACKS {
+        for (record, sector) in IBM_3740_SKEW.iter().enumerate() {
+            let from = track * track_size + (sector - 1) * RECORD_SIZE;
+            let to = track * track_size + record * RECORD_SIZE;
+            logical[to..to + RECORD_SIZE].copy_from_slice(&physical[from..from + RECORD_SIZE]);
+        }
+    }
+    logical
+}
+
+fn skew(logical: &[u8]) -> Vec<u8> {
+    let track_size = IBM_3740_SKEW.len() * RECORD_SIZE;
+    let mut logical = logical.to_vec();
+    logical.resize(IBM_3740_TRACKS * track_size, 0xe5);
+
+    let mut physical = logical.clone();
+    for track in IBM_3740_DPB.off as usize..IBM_3740_TRACKS {
+        for (record, sector) in IBM_3740_SKEW.iter().enumerate() {
+            let from = track * track_size + record * RECORD_SIZE;
+            let to = track * track_size + (sector - 1) * RECORD_SIZE;
+            physical[to..to + RECORD_SIZE].copy_from_slice(&logical[from..from + RECORD_SIZE]);
+        }
+    }
+    physical
+}
+
+// ImageDisk to a plain IBM 3740 image in physical sector order
+fn read_imd(data: &[u8]) -> Result<Vec<u8>, String> {
+    let sectors = IBM_3740_SKEW.len();
+    let mut image = vec![0xe5; IBM_3740_TRACKS * sectors * RECORD_SIZE];
+
+    // The ASCII header ends with ctrl-z
+    let mut pos = match data.iter().position(|b| *b == 0x1a) {
+        Some(pos) if data.starts_with(b"IMD ") => pos + 1,
+        _ => return Err("not an ImageDisk file".to_string()),
+    };
+
+    let truncated = || "truncated ImageDisk file".to_string();
+    while pos < data.len() {
+        let header = data.get(pos..pos + 5).ok_or_else(truncated)?;
+        let (cylinder, head, count, size_code) = (header[1] as usize, header[2], header[3] as usize, header[4]);
+        pos += 5;
+        let numbering = data.get(pos..pos + count).ok_or_else(truncated)?.to_vec();
+        pos += count;
+        if head & 0x80 != 0 {
+            pos += count; // Cylinder map
+        }
+        if head & 0x40 != 0 {
+            pos += count; // Head map
+        }
+        if (head & 0x0f) != 0 || size_code != 0 || cylinder >= IBM_3740_TRACKS {
+            return Err("only 8\" SSSD IBM 3740 ImageDisk files are supported".to_string());
+        }
+
+        for id in numbering.iter() {
+            let kind = *data.get(pos).ok_or_else(truncated)?;
+            pos += 1;
+            let content = match kind {
+                0 => None, // Unavailable
+                1 | 3 | 5 | 7 => {
+                    let sector = data.get(pos..pos + RECORD_SIZE).ok_or_else(truncated)?;
+                    pos += RECORD_SIZE;
+                    Some(sector.to_vec())
+                },
+                2 | 4 | 6 | 8 => {
+                    let fill = *data.get(pos).ok_or_else(truncated)?;
+                    pos += 1;
+                    Some(vec![fill; RECORD_SIZE])
+                },
+                _ => return Err(format!("invalid sector record type {} in ImageDisk file", kind)),
+            };
+            let id = *id as usize;
+            if id < 1 || id > sectors {
+                return Err(format!("invalid sector number {} in ImageDisk file", id));
+            }
+            if let Some(content) = content {
+                let start = (cylinder * sectors + id - 1) * RECORD_SIZE;
+                image[start..start + RECORD_SIZE].copy_from_slice(&content);
+            }
+        }
+    }
+    Ok(image)
+}
+
+fn write_imd(image: &[u8]) -> Vec<u8> {
+    let sectors = IBM_3740_SKEW.len();
+    let mut data = b"IMD 1.18: z80-mbc2-emu\r\n".to_vec();
+    data.push(0x1a);
+    for track in 0..IBM_3740_TRACKS {
+        // Mode 0 is 500 kbps FM
+        data.extend_from_slice(&[0, track as u8, 0, sectors as u8, 0]);
+        data.extend(1..=sectors as u8);
+        for sector in 0..sectors {
+            let start = (track * sectors + sector) * RECORD_SIZE;
+            let content = &image[start..start + RECORD_SIZE];
+            if content.iter().all(|b| *b == content[0]) {
+                data.push(2);
+                data.push(content[0]);
+            } else {
+                data.push(1);
+                data.extend_from_slice(content);
+            }
+        }
+    }
+    data
+}
+
+fn read_runcpm(path: &str) -> Result<Vec<CpmFile>, String> {
+    let mut files = Vec::new();
+    let entries = fs::read_dir(path)
+        .map_err(|e| format!("Error reading '{}': {}", path, e))?;
+    for entry in entries.flatten() {
+        let folder = entry.file_name().to_string_lossy().to_uppercase();
+        let user = match RUNCPM_USERS.find(folder.as_str()) {
+            Some(user) if folder.len() == 1 && entry.path().is_dir() => user as u8,
+            _ => continue,
+        };
+
+        let user_entries = fs::read_dir(entry.path())
+            .map_err(|e| format!("Error reading '{}': {}", entry.path().display(), e))?;
+        for file in user_entries.flatten() {
+            if !file.path().is_file() {
+                continue;
+            }
+            let name = file.file_name().to_string_lossy().to_uppercase();
+            let data = fs::read(file.path())
+                .map_err(|e| format!("Error reading '{}': {}", file.path().display(), e))?;
+            files.push(CpmFile {user, name, data});
+        }
+    }
+    files.sort_by(|a, b| (a.user, &a.name).cmp(&(b.user, &b.name)));
+    Ok(files)
+}
+
+fn write_runcpm(path: &str, files: &[CpmFile]) -> Result<(), String> {
+    for file in files.iter() {
+        let folder = Path::new(path).join(&RUNCPM_USERS[file.user as usize..file.user as usize + 1]);
+        fs::create_dir_all(&folder)
+            .map_err(|e| format!("Error creating '{}': {}", folder.display(), e))?;
+        let filename = folder.join(&file.name);
+        fs::write(&filename, &file.data)
+            .map_err(|e| format!("Error writing '{}': {}", filename.display(), e))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::filesystem::DEFAULT_GEOMETRY;
+
+    const TRACK_SIZE: usize = 26 * RECORD_SIZE;
+
+    fn test_folder(name: &str) -> std::path::PathBuf {
+        let folder = std::env::temp_dir().join(format!("z80-mbc2-convert-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&folder);
+        fs::create_dir_all(&folder).unwrap();
+        folder
+    }
+
+    // Each record filled with its physical sector number
+    fn numbered_image() -> Vec<u8> {
+        let mut image = Vec::new();
+        for _ in 0..IBM_3740_TRACKS {
+            for sector in 1..=26u8 {
+                image.extend_from_slice(&[sector; RECORD_SIZE]);
+            }
+        }
+        image
+    }
+
+    #[test]
+    fn sector_skew() {
+        let physical = numbered_image();
+        let logical = unskew(&physical);
+        // The system tracks are kept in physical order
+        assert_eq!(logical[..2 * TRACK_SIZE], physical[..2 * TRACK_SIZE]);
+        for (record, sector) in IBM_3740_SKEW.iter().enumerate() {
+            assert_eq!(logical[2 * TRACK_SIZE + record * RECORD_SIZE], *sector as u8);
+        }
+        assert_eq!(logical[76 * TRACK_SIZE + RECORD_SIZE], 7);
+        assert_eq!(skew(&logical), physical);
+    }
+
+    #[test]
+    fn imd_round_trip() {
+        let mut image = numbered_image();
+        image[5 * TRACK_SIZE + 3] = 0x42; // Not compressed
+        let imd = write_imd(&image);
+        assert!(imd.starts_with(b"IMD "));
+        // 77 tracks of 25 compressed sectors and one stored
+        assert_eq!(imd.len(), 25 + 77 * (5 + 26 + 26 * 2) + RECORD_SIZE - 1);
+        assert_eq!(read_imd(&imd).unwrap(), image);
+    }
+
+    #[test]
+    fn imd_maps_and_missing_sectors() {
+        let mut imd = b"IMD 1.18: test\r\n\x1a".to_vec();
+        // Track 3 with a cylinder map, sectors 2 and 1, the first unavailable
+        imd.extend_from_slice(&[0, 3, 0x80, 2, 0, 2, 1, 3, 3, 0, 1]);
+        imd.extend_from_slice(&[0x11; RECORD_SIZE]);
+        let image = read_imd(&imd).unwrap();
+        assert_eq!(image[3 * TRACK_SIZE], 0x11);
+        assert_eq!(image[3 * TRACK_SIZE + RECORD_SIZE], 0xe5);
+
+        let error = |data: &[u8]| read_imd(&[&imd[..17], data].concat()).unwrap_err();
+        assert_eq!(read_imd(b"not an image").unwrap_err(), "not an ImageDisk file");
+        assert_eq!(error(&[0, 3, 0, 1, 0, 1, 1]), "truncated ImageDisk file");
+        assert_eq!(error(&[0, 3, 0]), "truncated ImageDisk file");
+        assert!(error(&[0, 3, 1, 1, 0, 1, 2, 0]).starts_with("only 8\""));
+        assert!(error(&[0, 3, 0, 1, 1, 1, 2, 0]).starts_with("only 8\""));
+        assert!(error(&[0, 77, 0, 1, 0, 1, 2, 0]).starts_with("only 8\""));
+        assert_eq!(error(&[0, 3, 0, 1, 0, 27, 2, 0]), "invalid sector number 27 in ImageDisk file");
+        assert_eq!(error(&[0, 3, 0, 1, 0, 1, 9]), "invalid sector record type 9 in ImageDisk file");
+    }
+
+    #[test]
+    fn image_names() {
+        assert_eq!(parse_image("sd/DS2N05.DSK").unwrap(), (Format::Dsk(2), "sd/DS2N05.DSK"));
+        assert_eq!(parse_image("disk.dsk").unwrap(), (Format::Dsk(0), "disk.dsk"));
+        assert_eq!(parse_image("dsk3:disk.img").unwrap(), (Format::Dsk(3), "disk.img"));
+        assert_eq!(parse_image("dsk:ds4n00.dsk").unwrap(), (Format::Dsk(4), "ds4n00.dsk"));
+        assert_eq!(parse_image("A.IMD").unwrap(), (Format::Imd, "A.IMD"));
+        assert_eq!(parse_image("raw:a.img").unwrap(), (Format::Raw, "a.img"));
+        assert_eq!(parse_image("simh:a.img").unwrap(), (Format::Simh, "a.img"));
+        assert!(parse_image("a.img").is_err());
+        assert!(parse_image("dskx:a.img").is_err());
+    }
+
+    #[test]
+    fn full_simh_disk() {
+        assert_eq!(SIMH_DPB.size(), SIMH_SIZE);
+        // All the blocks after the 8 of the directory
+        let size = (2042 - 8) * 4096;
+        let data: Vec<u8> = (0..size).map(|i| (i / RECORD_SIZE) as u8).collect();
+        let folder = test_folder("simh");
+        let image = folder.join("full.img");
+        let path = image.to_str().unwrap();
+        let file = CpmFile {user: 0, name: "FULL.BIN".to_string(), data: data.clone()};
+        write_files(&Config::new(), Format::Simh, path, &[file], false).unwrap();
+
+        // The last block is at the end of the image
+        let written = fs::read(&image).unwrap();
+        assert_eq!(written.len(), SIMH_SIZE);
+        assert_eq!(written[SIMH_SIZE - 4096..], data[size - 4096..]);
+        let files = read_files(&Config::new(), Format::Simh, path).unwrap();
+        assert_eq!(files.len(), 1);
+        assert!(files[0].data == data);
+        fs::remove_dir_all(&folder).unwrap();
+    }
+
+    #[test]
+    fn copy_add_and_replace() {
+        let folder = test_folder("copy");
+        let source = folder.join("A");
+        fs::create_dir_all(source.join("0")).unwrap();
+        fs::create_dir_all(source.join("2")).unwrap();
+        fs::write(source.join("0").join("HELLO.TXT"), "hello").unwrap();
+        fs::write(source.join("2").join("DATA.BIN"), vec![7; 5000]).unwrap();
+        let more = folder.join("B");
+        fs::create_dir_all(more.join("0")).unwrap();
+        fs::write(more.join("0").join("MORE.TXT"), "more").unwrap();
+
+        let path = |p: &std::path::Path| p.to_str().unwrap().to_string();
+        let target = folder.join("DS0N05.DSK");
+        let run = |args: &[&str]| convert(&args.iter().map(|a| a.to_string()).collect::<Vec<_>>());
+        let names = || {
+            let config = Config::new();
+            let files = read_files(&config, Format::Dsk(0), &path(&target)).unwrap();
+            files.into_iter().map(|f| (f.user, f.name)).collect::<Vec<_>>()
+        };
+
+        run(&[&path(&source), &path(&target)]).unwrap();
+        assert_eq!(fs::metadata(&target).unwrap().len(), DEFAULT_GEOMETRY.disk_size());
+        assert_eq!(names(), [(0, "HELLO.TXT".to_string()), (2, "DATA.BIN".to_string())]);
+
+        assert!(run(&[&path(&more), &path(&target)]).unwrap_err().contains("already exists"));
+        run(&["--add", &path(&more), &path(&target)]).unwrap();
+        assert_eq!(names().len(), 3);
+        run(&["--force", &path(&more), &path(&target)]).unwrap();
+        assert_eq!(names(), [(0, "MORE.TXT".to_string())]);
+
+        // Back to a RunCPM folder, the records are padded
+        let copy = folder.join("C");
+        run(&[&path(&target), &format!("runcpm:{}", path(&copy))]).unwrap();
+        assert_eq!(fs::read(copy.join("0").join("MORE.TXT")).unwrap().len(), RECORD_SIZE);
+        fs::remove_dir_all(&folder).unwrap();
+    }
+}
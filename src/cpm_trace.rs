@@ -0,0 +1,200 @@
+use std::fs;
+use std::io::{BufWriter, Write};
+
+use iz80::*;
+
+use super::bdos::{bios_function_name, function_name, BIOS3_ENTRIES};
+use super::mbc2_machine::Mbc2Machine;
+
+// Trace of the calls to the CP/M BDOS and BIOS, for the CP/M 2.2, QP/M and
+// CP/M 3 images. The calls to address 5 and to the entries of the BIOS jump
+// table are logged with the arguments and, on return, with the results:
+//
+//    0103 BDOS 15 F_OPEN A:TEST.TXT -> A=00 HL=0000
+//
+// The first column is the return address. The nested calls are indented.
+
+const MAX_PENDING: usize = 16;
+
+struct Pending {
+    return_address: u16,
+    sp: u16,
+    name: &'static str,
+}
+
+pub struct CpmTracer {
+    file: BufWriter<fs::File>,
+    pending: Vec<Pending>,
+    open_line: bool,
+    bios_base: u16,
+    bios_entries: u16,
+}
+
+impl CpmTracer {
+    pub fn new(filename: &str) -> Result<CpmTracer, String> {
+        let file = fs::File::create(filename)
+            .map_err(|e| format!("Error creating the trace file '{}': {}", filename, e))?;
+        Ok(CpmTracer {
+            file: BufWriter::new(file),
+            pending: Vec::new(),
+            open_line: false,
+            bios_base: 0,
+            bios_entries: 0,
+        })
+    }
+
+    // Called before each instruction
+    pub fn step(&mut self, cpu: &mut Cpu, machine: &Mbc2Machine) {
+        let pc = cpu.registers().pc();
+        let sp = cpu.registers().get16(Reg16::SP);
+
+        // Returns, a BIOS entry reached with JP returns with the BDOS
+        while let Some(call) = self.pending.last() {
+            if call.return_address != pc || call.sp.wrapping_add(2) != sp {
+                break;
+            }
+            let name = call.name;
+            self.pending.pop();
+            let result = format!("A={:02X} HL={:04X}",
+                cpu.registers().a(), cpu.registers().get16(Reg16::HL));
+            if self.open_line {
+                self.write(&format!(" -> {}\n", result));
+            } else {
+                let indent = "  ".repeat(self.pending.len());
+                self.write(&format!("{:04X} {}<- {} {}\n", pc, indent, name, result));
+            }
+            self.open_line = false;
+        }
+
+        if pc == 0x0005 {
+            let function = cpu.registers().get8(Reg8::C);
+            let name = function_name(function);
+            let args = bdos_args(machine, function, cpu.registers().get16(Reg16::DE));
+            self.call(machine, sp, &format!("BDOS {} {}{}", function, name, args), name);
+        } else if let Some(index) = self.bios_entry(machine, pc) {
+            let name = bios_function_name(index);
+            let args = bios_args(index,
+                cpu.registers().get16(Reg16::BC), cpu.registers().get16(Reg16::DE));
+            self.call(machine, sp, &format!("BIOS {}{}", name, args), name);
+        }
+    }
+
+    fn call(&mut self, machine: &Mbc2Machine, sp: u16, text: &str, name: &'static str) {
+        let return_address = machine.peek16(sp);
+        if self.open_line {
+            self.write("\n");
+        }
+        let indent = "  ".repeat(self.pending.len());
+        self.write(&format!("{:04X} {}{}", return_address, indent, text));
+        self.open_line = true;
+
+        if self.pending.len() >= MAX_PENDING {
+            // Calls that did not return, like a warm boot
+            self.pending.remove(0);
+        }
+        self.pending.push(Pending {return_address, sp, name});
+    }
+
+    fn bios_entry(&mut self, machine: &Mbc2Machine, pc: u16) -> Option<u16> {
+        // The warm boot entry is on the JP at address 0
+        if machine.peek(0x0000) != 0xc3 {
+            return None;
+        }
+        let base = machine.peek16(0x0001).wrapping_sub(3);
+        if base != self.bios_base {
+            // Count the JP instructions of the table
+            self.bios_base = base;
+            self.bios_entries = (0..BIOS3_ENTRIES)
+                .take_while(|i| machine.peek(base.wrapping_add(3 * i)) == 0xc3)
+                .count() as u16;
+        }
+
+        let offset = pc.wrapping_sub(base);
+        if offset < 3 * self.bios_entries && offset.is_multiple_of(3) {
+            Some(offset / 3)
+        } else {
+            None
+        }
+    }
+
+    fn write(&mut self, text: &str) {
+        // Errors writing the trace are ignored
+        let _ = self.file.write_all(text.as_bytes());
+    }
+}
+
+impl Drop for CpmTracer {
+    fn drop(&mut self) {
+        if self.open_line {
+            self.write("\n");
+        }
+        let _ = self.file.flush();
+    }
+}
+
+fn bdos_args(machine: &Mbc2Machine, function: u8, de: u16) -> String {
+    match function {
+        2 | 4 | 5 => format!(" {}", char_text(de as u8)),
+        6 => match de as u8 {
+            0xff => " input".to_string(),
+            0xfe => " status".to_string(),
+            ch => format!(" {}", char_text(ch)),
+        },
+        9 => {
+            let mut text = String::new();
+            for i in 0..40 {
+                let ch = machine.peek(de.wrapping_add(i));
+                if ch == b'$' {
+                    break;
+                }
+                match ch {
+                    0x20..=0x7e => text.push(ch as char),
+                    _ => text += &format!("\\x{:02X}", ch),
+                }
+            }
+            format!(" \"{}\"", text)
+        },
+        14 => format!(" {}:", (b'A' + (de as u8 & 0x0f)) as char),
+        15 | 16 | 17 | 19 | 22 | 30 | 35 | 36 | 99 | 100 | 102 | 103 | 106 => {
+            format!(" {}", fcb_text(machine, de))
+        },
+        20 | 21 => format!(" {} record {}", fcb_text(machine, de),
+            ((machine.peek(de + 12) as u16 & 0x1f) << 7) + (machine.peek(de + 32) as u16 & 0x7f)),
+        33 | 34 | 40 => format!(" {} record {}", fcb_text(machine, de),
+            machine.peek(de + 33) as u32 + ((machine.peek(de + 34) as u32) << 8)),
+        23 => format!(" {} to {}", fcb_text(machine, de), fcb_text(machine, de + 16)),
+        26 => format!(" {:04X}", de),
+        _ => format!(" DE={:04X}", de),
+    }
+}
+
+fn bios_args(index: u16, bc: u16, de: u16) -> String {
+    match index {
+        4..=6 => format!(" {}", char_text(bc as u8)),
+        9 => format!(" {}:", (b'A' + (bc as u8 & 0x0f)) as char),
+        10 | 11 => format!(" {}", bc),
+        12 => format!(" {:04X}", bc),
+        16 => format!(" {} table {:04X}", bc, de),
+        _ => String::new(),
+    }
+}
+
+fn fcb_text(machine: &Mbc2Machine, fcb: u16) -> String {
+    let field = |start: u16, size: u16| -> String {
+        (start..start + size).map(|i| (machine.peek(fcb + i) & 0x7f) as char)
+            .collect::<String>().trim_end().to_string()
+    };
+    let drive = match machine.peek(fcb) {
+        0 => String::new(),
+        d if d <= 16 => format!("{}:", (b'A' + d - 1) as char),
+        _ => "?:".to_string(),
+    };
+    format!("{}{}.{}", drive, field(1, 8), field(9, 3))
+}
+
+fn char_text(ch: u8) -> String {
+    match ch {
+        0x20..=0x7e => format!("'{}'", ch as char),
+        _ => format!("{:02X}h", ch),
+    }
+}
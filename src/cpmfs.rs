@@ -0,0 +1,456 @@
+use std::collections::BTreeMap;
+
+// CP/M 2.2 file system on a disk image, the records are in logical order.
+// Used to convert the files between image formats.
+
+pub const RECORD_SIZE: usize = 128;
+const ENTRY_SIZE: usize = 32;
+const EMPTY: u8 = 0xe5;
+const MAX_USER: u8 = 15;
+const RECORDS_PER_EXTENT: usize = 128;
+
+// Disk parameter block
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Dpb {
+    pub spt: u16, // 128 bytes records per track
+    pub bsh: u8,
+    pub exm: u8,
+    pub dsm: u16,
+    pub drm: u16,
+    pub off: u16,
+}
+
+impl Dpb {
+    // The extent mask, the block size and the number of blocks agree
+    pub fn is_valid(&self) -> bool {
+        let pointer_blocks = self.pointers_per_entry() * self.block_size();
+        self.spt > 0
+            && !(self.dsm > 255 && self.bsh == 3)
+            && pointer_blocks == self.records_per_entry() * RECORD_SIZE
+            && self.dir_blocks() <= 16
+            && self.dir_blocks() <= self.dsm as usize
+    }
+
+    pub fn block_size(&self) -> usize {
+        RECORD_SIZE << self.bsh
+    }
+
+    fn dir_blocks(&self) -> usize {
+        ((self.drm as usize + 1) * ENTRY_SIZE).div_ceil(self.block_size())
+    }
+
+    fn pointers_per_entry(&self) -> usize {
+        if self.dsm > 255 { 8 } else { 16 }
+    }
+
+    fn records_per_entry(&self) -> usize {
+        (self.exm as usize + 1) * RECORDS_PER_EXTENT
+    }
+
+    fn data_offset(&self) -> usize {
+        self.off as usize * self.spt as usize * RECORD_SIZE
+    }
+
+    pub fn size(&self) -> usize {
+        self.data_offset() + (self.dsm as usize + 1) * self.block_size()
+    }
+}
+
+pub struct CpmFile {
+    pub user: u8,
+    pub name: String, // NAME.EXT
+    pub data: Vec<u8>,
+}
+
+pub struct CpmDisk {
+    dpb: Dpb,
+    data: Vec<u8>,
+}
+
+impl CpmDisk {
+    pub fn from_logical(dpb: Dpb, mut data: Vec<u8>) -> CpmDisk {
+        data.resize(dpb.size(), EMPTY);
+        CpmDisk {
+            dpb,
+            data,
+        }
+    }
+
+    pub fn logical(&self) -> &[u8] {
+        &self.data
+    }
+
+    fn block(&self, block: usize) -> &[u8] {
+        let start = self.dpb.data_offset() + block * self.dpb.block_size();
+        &self.data[start..start + self.dpb.block_size()]
+    }
+
+    fn block_mut(&mut self, block: usize) -> &mut [u8] {
+        let start = self.dpb.data_offset() + block * self.dpb.block_size();
+        let size = self.dpb.block_size();
+        &mut self.data[start..start + size]
+    }
+
+    fn entry(&self, index: usize) -> &[u8] {
+        let start = self.dpb.data_offset() + index * ENTRY_SIZE;
+        &self.data[start..start + ENTRY_SIZE]
+    }
+
+    fn entry_mut(&mut self, index: usize) -> &mut [u8] {
+        let start = self.dpb.data_offset() + index * ENTRY_SIZE;
+        &mut self.data[start..start + ENTRY_SIZE]
+    }
+
+    fn entry_blocks(&self, entry: &[u8]) -> Vec<usize> {
+        let mut blocks = Vec::new();
+        for i in 0..self.dpb.pointers_per_entry() {
+            let block = if self.dpb.dsm > 255 {
+                entry[16 + 2*i] as usize + ((entry[17 + 2*i] as usize) << 8)
+            } else {
+                entry[16 + i] as usize
+            };
+            if block != 0 {
+                blocks.push(block);
+            }
+        }
+        blocks
+    }
+
+    pub fn files(&self) -> Result<Vec<CpmFile>, String> {
+        // Group the extents of each file
+        let mut extents: BTreeMap<(u8, String), Vec<(usize, usize)>> = BTreeMap::new();
+        for index in 0..=self.dpb.drm as usize {
+            let entry = self.entry(index);
+            let user = entry[0];
+            if user > MAX_USER {
+                // Deleted files, labels and time stamps
+                continue;
+            }
+            let name = entry_name(entry);
+            let extent = (entry[12] as usize & 0x1f) + ((entry[14] as usize & 0x3f) << 5);
+            extents.entry((user, name)).or_default().push((extent, index));
+        }
+
+        let mut files = Vec::new();
+        for ((user, name), mut entries) in extents {
+            entries.sort();
+            let mut data = Vec::new();
+            for (extent, index) in entries {
+                let entry = self.entry(index);
+                let records = (extent & self.dpb.exm as usize) * RECORDS_PER_EXTENT
+                    + entry[15] as usize;
+                let start = data.len();
+                for block in self.entry_blocks(entry) {
+                    if block > self.dpb.dsm as usize {
+                        return Err(format!("invalid block {} on file {}", block, name));
+                    }
+                    data.extend_from_slice(self.block(block));
+                }
+                data.truncate(start + records * RECORD_SIZE);
+            }
+            files.push(CpmFile {user, name, data});
+        }
+        Ok(files)
+    }
+
+    fn used_blocks(&self) -> Vec<bool> {
+        let mut used = vec![false; self.dpb.dsm as usize + 1];
+        for block in used.iter_mut().take(self.dpb.dir_blocks()) {
+            *block = true;
+        }
+        for index in 0..=self.dpb.drm as usize {
+            let entry = self.entry(index);
+            if entry[0] != EMPTY {
+                for block in self.entry_blocks(entry) {
+                    if block < used.len() {
+                        used[block] = true;
+                    }
+                }
+            }
+        }
+        used
+    }
+
+    pub fn remove_file(&mut self, user: u8, name: &str) {
+        for index in 0..=self.dpb.drm as usize {
+            let entry = self.entry(index);
+            if entry[0] == user && entry_name(entry) == name.to_uppercase() {
+                self.entry_mut(index)[0] = EMPTY;
+            }
+        }
+    }
+
+    pub fn add_file(&mut self, file: &CpmFile) -> Result<(), String> {
+        let name = cpm_name(&file.name)
+            .ok_or(format!("'{}' is not a valid CP/M file name", file.name))?;
+        if file.user > MAX_USER {
+            return Err(format!("invalid user {} for {}", file.user, file.name));
+        }
+
+        let mut used = self.used_blocks();
+        let records = file.data.len().div_ceil(RECORD_SIZE);
+        let records_per_entry = self.dpb.records_per_entry();
+        let entry_count = records.div_ceil(records_per_entry).max(1);
+
+        let free_entries: Vec<usize> = (0..=self.dpb.drm as usize)
+            .filter(|i| self.entry(*i)[0] == EMPTY).collect();
+        let block_records = self.dpb.block_size() / RECORD_SIZE;
+        // Check the space first to not leave a partial file
+        if free_entries.len() < entry_count {
+            return Err(format!("directory full adding {}", file.name));
+        }
+        if used.iter().filter(|u| !**u).count() < records.div_ceil(block_records) {
+            return Err(format!("disk full adding {}", file.name));
+        }
+        for n in 0..entry_count {
+            let index = *free_entries.get(n)
+                .ok_or(format!("directory full adding {}", file.name))?;
+            let first_record = n * records_per_entry;
+            let entry_records = (records - first_record.min(records)).min(records_per_entry);
+
+            // Allocate and fill the blocks
+            let mut blocks = Vec::new();
+            for b in 0..entry_records.div_ceil(block_records) {
+                let block = used.iter().position(|u| !u)
+                    .ok_or(format!("disk full adding {}", file.name))?;
+                used[block] = true;
+                blocks.push(block);
+
+                let start = (first_record + b * block_records) * RECORD_SIZE;
+                let end = (start + self.dpb.block_size()).min(file.data.len());
+                let target = self.block_mut(block);
+                target.fill(0x1a); // Pad the last record with ctrl-z
+                target[..end - start].copy_from_slice(&file.data[start..end]);
+            }
+
+            // The entry describes its last logical extent
+            let last_extent = if entry_records == 0 { 0 } else { (entry_records - 1) / RECORDS_PER_EXTENT };
+            let extent = n * (self.dpb.exm as usize + 1) + last_extent;
+            let rc = entry_records - last_extent * RECORDS_PER_EXTENT;
+
+            let big_blocks = self.dpb.dsm > 255;
+            let entry = self.entry_mut(index);
+            entry.fill(0);
+            entry[0] = file.user;
+            entry[1..12].copy_from_slice(&name);
+            entry[12] = (extent & 0x1f) as u8;
+            entry[14] = (extent >> 5) as u8;
+            entry[15] = rc as u8;
+            for (i, block) in blocks.iter().enumerate() {
+                if big_blocks {
+                    entry[16 + 2*i] = *block as u8;
+                    entry[17 + 2*i] = (*block >> 8) as u8;
+                } else {
+                    entry[16 + i] = *block as u8;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn entry_name(entry: &[u8]) -> String {
+    // The high bits are attributes
+    let name: String = entry[1..9].iter().map(|c| (c & 0x7f) as char).collect();
+    let ext: String = entry[9..12].iter().map(|c| (c & 0x7f) as char).collect();
+    let name = name.trim_end();
+    let ext = ext.trim_end();
+    if ext.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}.{}", name, ext)
+    }
+}
+
+// Returns the 11 bytes of the directory entry name
+pub fn cpm_name(name: &str) -> Option<[u8; 11]> {
+    let upper = name.to_uppercase();
+    let (base, ext) = match upper.rfind('.') {
+        Some(pos) => (&upper[..pos], &upper[pos+1..]),
+        None => (upper.as_str(), ""),
+    };
+    if base.is_empty() || base.len() > 8 || ext.len() > 3 {
+        return None;
+    }
+
+    let mut entry = [b' '; 11];
+    for (i, c) in base.bytes().enumerate() {
+        entry[i] = c;
+    }
+    for (i, c) in ext.bytes().enumerate() {
+        entry[8 + i] = c;
+    }
+    let valid = entry.iter().all(|c| *c > b' ' && *c < 0x7f && !b"<>.,;:=?*[]".contains(c)
+        || *c == b' ');
+    if valid {
+        Some(entry)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The DPBs of the Z80-MBC2 and of the 8" SSSD disks
+    const BIG: Dpb = Dpb {spt: 128, bsh: 5, exm: 1, dsm: 2043, drm: 511, off: 1};
+    const SMALL: Dpb = Dpb {spt: 26, bsh: 3, exm: 0, dsm: 242, drm: 63, off: 2};
+
+    fn file(user: u8, name: &str, size: usize) -> CpmFile {
+        CpmFile {
+            user,
+            name: name.to_string(),
+            data: (0..size).map(|i| (i % 251) as u8).collect(),
+        }
+    }
+
+    fn empty(dpb: Dpb) -> CpmDisk {
+        CpmDisk::from_logical(dpb, Vec::new())
+    }
+
+    fn padded(data: &[u8]) -> Vec<u8> {
+        let mut data = data.to_vec();
+        data.resize(data.len().div_ceil(RECORD_SIZE) * RECORD_SIZE, 0x1a);
+        data
+    }
+
+    #[test]
+    fn dpb_checks() {
+        assert!(BIG.is_valid());
+        assert!(SMALL.is_valid());
+        assert_eq!(BIG.size(), 16384 + 2044 * 4096);
+        assert!(!Dpb {exm: 0, ..BIG}.is_valid());
+        assert!(!Dpb {bsh: 3, exm: 0, ..BIG}.is_valid());
+        assert!(!Dpb {spt: 0, ..SMALL}.is_valid());
+        assert!(!Dpb {drm: 1023, ..SMALL}.is_valid());
+    }
+
+    #[test]
+    fn names() {
+        assert_eq!(&cpm_name("hello.com").unwrap(), b"HELLO   COM");
+        assert_eq!(&cpm_name("README").unwrap(), b"README     ");
+        assert!(cpm_name("toolongname.com").is_none());
+        assert!(cpm_name("a.text").is_none());
+        assert!(cpm_name("a*b.com").is_none());
+        assert!(cpm_name(".com").is_none());
+    }
+
+    #[test]
+    fn extents_with_big_blocks() {
+        // 313 records, two entries of two logical extents each
+        let mut disk = empty(BIG);
+        let big = file(0, "BIG.DAT", 40000);
+        disk.add_file(&big).unwrap();
+
+        let entry = disk.entry(0);
+        assert_eq!(&entry[1..12], b"BIG     DAT");
+        assert_eq!((entry[12], entry[14], entry[15]), (1, 0, 128));
+        // The directory takes the 4 first blocks, 8 pointers of 2 bytes
+        assert_eq!(disk.entry_blocks(entry), (4..12).collect::<Vec<_>>());
+        let entry = disk.entry(1);
+        assert_eq!((entry[12], entry[14], entry[15]), (2, 0, 57));
+        assert_eq!(disk.entry_blocks(entry), [12, 13]);
+
+        let files = disk.files().unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].name, "BIG.DAT");
+        assert_eq!(files[0].data, padded(&big.data));
+    }
+
+    #[test]
+    fn extents_with_small_blocks() {
+        // 157 records, two entries of one extent
+        let mut disk = empty(SMALL);
+        let data = file(3, "X.TXT", 20000);
+        disk.add_file(&data).unwrap();
+
+        let entry = disk.entry(0);
+        assert_eq!((entry[0], entry[12], entry[15]), (3, 0, 128));
+        assert_eq!(disk.entry_blocks(entry), (2..18).collect::<Vec<_>>());
+        let entry = disk.entry(1);
+        assert_eq!((entry[12], entry[15]), (1, 29));
+        assert_eq!(disk.entry_blocks(entry), (18..22).collect::<Vec<_>>());
+
+        let files = disk.files().unwrap();
+        assert_eq!((files[0].user, files[0].data.len()), (3, 157 * RECORD_SIZE));
+        assert_eq!(files[0].data, padded(&data.data));
+    }
+
+    #[test]
+    fn extent_beyond_512k() {
+        // The extent number goes over 31 into the S2 byte
+        let mut disk = empty(BIG);
+        disk.add_file(&file(0, "HUGE.DAT", 33 * 16384)).unwrap();
+        let entry = disk.entry(16);
+        assert_eq!((entry[12], entry[14], entry[15]), (0, 1, 128));
+        assert_eq!(disk.files().unwrap()[0].data.len(), 33 * 16384);
+    }
+
+    #[test]
+    fn empty_file_and_attributes() {
+        let mut disk = empty(SMALL);
+        disk.add_file(&file(0, "EMPTY", 0)).unwrap();
+        disk.add_file(&file(0, "RO.SYS", 10)).unwrap();
+        disk.entry_mut(1)[9] |= 0x80; // Read only attribute
+        let entry = disk.entry(0);
+        assert_eq!((entry[12], entry[15]), (0, 0));
+        assert!(disk.entry_blocks(entry).is_empty());
+
+        let files = disk.files().unwrap();
+        assert_eq!(files[0].name, "EMPTY");
+        assert!(files[0].data.is_empty());
+        assert_eq!(files[1].name, "RO.SYS");
+        assert_eq!(files[1].data.len(), RECORD_SIZE);
+    }
+
+    #[test]
+    fn skipped_entries() {
+        let mut disk = empty(SMALL);
+        disk.add_file(&file(0, "A", 10)).unwrap();
+        disk.add_file(&file(0, "B", 10)).unwrap();
+        disk.entry_mut(1)[0] = 0x21; // Time stamps
+        disk.add_file(&file(0, "C", 10)).unwrap();
+        let names: Vec<String> = disk.files().unwrap().into_iter().map(|f| f.name).collect();
+        assert_eq!(names, ["A", "C"]);
+        // The blocks of the other entries are kept
+        assert_eq!(disk.entry_blocks(disk.entry(2)), [4]);
+    }
+
+    #[test]
+    fn remove_and_reuse() {
+        let mut disk = empty(SMALL);
+        disk.add_file(&file(0, "A.TXT", 3000)).unwrap();
+        disk.add_file(&file(1, "a.txt", 10)).unwrap();
+        disk.remove_file(0, "a.txt");
+        disk.add_file(&file(0, "B.TXT", 100)).unwrap();
+        let files = disk.files().unwrap();
+        assert_eq!((files[0].user, files[0].name.as_str()), (0, "B.TXT"));
+        assert_eq!((files[1].user, files[1].name.as_str()), (1, "A.TXT"));
+        assert_eq!(disk.entry_blocks(disk.entry(0)), [2]);
+    }
+
+    #[test]
+    fn full_disk_and_directory() {
+        let mut disk = empty(SMALL);
+        for i in 0..64 {
+            disk.add_file(&file(0, &format!("F{}", i), 10)).unwrap();
+        }
+        assert!(disk.add_file(&file(0, "LAST", 10)).unwrap_err().starts_with("directory full"));
+
+        let mut disk = empty(SMALL);
+        disk.add_file(&file(0, "FIRST", 100 * 1024)).unwrap();
+        let before = disk.logical().to_vec();
+        assert!(disk.add_file(&file(0, "SECOND", 200 * 1024)).unwrap_err().starts_with("disk full"));
+        assert!(disk.logical() == &before[..]);
+        assert!(disk.add_file(&file(16, "USER", 10)).is_err());
+    }
+
+    #[test]
+    fn invalid_block() {
+        let mut disk = empty(SMALL);
+        disk.add_file(&file(0, "A", 10)).unwrap();
+        disk.entry_mut(0)[16] = 250;
+        assert!(disk.files().is_err());
+    }
+}
@@ -0,0 +1,110 @@
+use std::fs;
+use std::panic;
+use std::sync::Mutex;
+
+use iz80::*;
+
+use super::mbc2_machine::Mbc2Machine;
+
+// Post-mortem report written with --crash-report when the emulation aborts:
+// on an opcode not implemented, on a HALT or on a panic. It has the
+// registers, the stack, the last instructions executed disassembled and the
+// last IOS operations.
+//
+// The instructions are disassembled when the report is written, the
+// self modifying code may show the current content of the memory.
+
+const HISTORY_SIZE: usize = 64;
+const STACK_WORDS: u16 = 16;
+
+static PANIC_MESSAGE: Mutex<Option<String>> = Mutex::new(None);
+
+pub struct CrashRecorder {
+    filename: String,
+    history: [(u16, u8); HISTORY_SIZE], // PC and bank
+    next: usize,
+    count: usize,
+}
+
+impl CrashRecorder {
+    pub fn new(filename: &str) -> CrashRecorder {
+        CrashRecorder {
+            filename: filename.to_string(),
+            history: [(0, 0); HISTORY_SIZE],
+            next: 0,
+            count: 0,
+        }
+    }
+
+    // Called before each instruction
+    pub fn record(&mut self, pc: u16, bank: u8) {
+        self.history[self.next] = (pc, bank);
+        self.next = (self.next + 1) % HISTORY_SIZE;
+        self.count += 1;
+    }
+
+    pub fn write(&self, reason: &str, cpu: &mut Cpu, machine: &mut Mbc2Machine) {
+        let report = self.report(reason, cpu, machine);
+        match fs::write(&self.filename, report) {
+            Ok(_) => println!("<<crash report written to '{}'>>", self.filename),
+            Err(e) => println!("<<error writing the crash report to '{}': {}>>", self.filename, e),
+        }
+    }
+
+    fn report(&self, reason: &str, cpu: &mut Cpu, machine: &mut Mbc2Machine) -> String {
+        let mut text = format!("z80-mbc2-emu {} crash report\n\nReason: {}\n",
+            env!("CARGO_PKG_VERSION"), reason);
+        if let Some(message) = PANIC_MESSAGE.lock().ok().and_then(|m| m.clone()) {
+            text += &format!("Panic: {}\n", message);
+        }
+
+        let regs = cpu.immutable_registers();
+        text += "\nRegisters:\n";
+        text += &format!("  PC={:04X} SP={:04X} AF={:04X} BC={:04X} DE={:04X} HL={:04X}\n",
+            regs.pc(), regs.get16(Reg16::SP), regs.get16(Reg16::AF),
+            regs.get16(Reg16::BC), regs.get16(Reg16::DE), regs.get16(Reg16::HL));
+        text += &format!("  IX={:04X} IY={:04X} I={:02X} R={:02X} Flags={:08b} Cycles={}\n",
+            regs.get16(Reg16::IX), regs.get16(Reg16::IY),
+            regs.get8(Reg8::I), regs.get8(Reg8::R), regs.get8(Reg8::F), cpu.cycle_count());
+        let bank = machine.bank();
+        text += &format!("  Bank={}\n", bank);
+
+        let sp = regs.get16(Reg16::SP);
+        text += "\nStack:\n";
+        for i in 0..STACK_WORDS {
+            let address = sp.wrapping_add(2 * i);
+            text += &format!("  {:04X}: {:04X}\n", address, machine.peek16(address));
+        }
+
+        // Disassembled with a scratch cpu on the bank of each instruction
+        text += &format!("\nLast {} instructions:\n", self.count.min(HISTORY_SIZE));
+        let mut scratch = Cpu::new_z80();
+        let first = if self.count < HISTORY_SIZE { 0 } else { self.next };
+        for i in 0..self.count.min(HISTORY_SIZE) {
+            let (pc, instruction_bank) = self.history[(first + i) % HISTORY_SIZE];
+            machine.set_bank(instruction_bank);
+            scratch.registers().set_pc(pc);
+            let disasm = scratch.disasm_instruction(machine);
+            let lower_bank = if pc & 0x8000 != 0 { "-".to_string() } else { instruction_bank.to_string() };
+            text += &format!("  {:04X} {:05X} {} {}\n", pc, machine.decode_address(pc), lower_bank, disasm);
+        }
+        machine.set_bank(bank);
+
+        text += "\nLast IOS operations:\n";
+        for line in machine.ios_history() {
+            text += &format!("  {}\n", line);
+        }
+        text
+    }
+}
+
+// Keeps the panic message for the report, the default hook shows it
+pub fn install_panic_hook() {
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        if let Ok(mut message) = PANIC_MESSAGE.lock() {
+            *message = Some(info.to_string());
+        }
+        default_hook(info);
+    }));
+}
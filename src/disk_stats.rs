@@ -0,0 +1,174 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::time::Instant;
+
+// Disk activity counters, to compare the BIOS deblocking strategies
+#[derive(Default)]
+struct DiskCounters {
+    reads: u64,
+    writes: u64,
+    selects: u64,
+    track_selects: u64,
+    sector_selects: u64,
+    seeks: u64, // Track changes
+    errors: u64,
+    sequential: u64,
+    repeated: u64,
+    random: u64,
+    sectors: HashSet<u64>,
+}
+
+pub struct DiskStats {
+    label: String,
+    disk_set: u8,
+    started: Instant,
+    disks: BTreeMap<u8, DiskCounters>,
+    last_transfer: Option<(u8, u64)>,
+    last_track: Option<(u8, u16)>,
+}
+
+impl DiskStats {
+    pub fn new() -> DiskStats {
+        DiskStats {
+            label: String::new(),
+            disk_set: 0xff,
+            started: Instant::now(),
+            disks: BTreeMap::new(),
+            last_transfer: None,
+            last_track: None,
+        }
+    }
+
+    pub fn set_label(&mut self, label: &str, disk_set: u8) {
+        self.label = label.to_string();
+        self.disk_set = disk_set;
+    }
+
+    fn counters(&mut self, disk: u8) -> &mut DiskCounters {
+        self.disks.entry(disk).or_default()
+    }
+
+    pub fn select(&mut self, disk: u8) {
+        self.counters(disk).selects += 1;
+    }
+
+    pub fn select_track(&mut self, disk: u8, track: u16) {
+        let counters = self.disks.entry(disk).or_default();
+        counters.track_selects += 1;
+        if self.last_track != Some((disk, track)) {
+            counters.seeks += 1;
+            self.last_track = Some((disk, track));
+        }
+    }
+
+    pub fn select_sector(&mut self, disk: u8) {
+        self.counters(disk).sector_selects += 1;
+    }
+
+    pub fn transfer(&mut self, disk: u8, sector: u64, write: bool) {
+        let last = self.last_transfer;
+        let counters = self.disks.entry(disk).or_default();
+        if write {
+            counters.writes += 1;
+        } else {
+            counters.reads += 1;
+        }
+        counters.sectors.insert(sector);
+
+        match last {
+            Some((d, s)) if d == disk && s + 1 == sector => counters.sequential += 1,
+            Some((d, s)) if d == disk && s == sector => counters.repeated += 1,
+            _ => counters.random += 1,
+        }
+        self.last_transfer = Some((disk, sector));
+    }
+
+    pub fn error(&mut self, disk: u8) {
+        self.counters(disk).errors += 1;
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut text = format!("Disk activity for {} on disk set {} in {:.1} seconds:\n",
+            self.label, self.disk_set, self.started.elapsed().as_secs_f64());
+        text += "  disk    reads   writes  selects    seeks  sectors  sequen.  repeat.   random   errors\n";
+        for (disk, c) in self.disks.iter() {
+            text += &format!("  {:4} {:8} {:8} {:8} {:8} {:8} {:8} {:8} {:8} {:8}\n",
+                disk, c.reads, c.writes, c.selects, c.seeks, c.sectors.len(),
+                c.sequential, c.repeated, c.random, c.errors);
+        }
+        text
+    }
+
+    pub fn to_json(&self) -> String {
+        let mut disks = Vec::new();
+        for (disk, c) in self.disks.iter() {
+            disks.push(format!(concat!("    {{\"disk\": {}, \"reads\": {}, \"writes\": {}, ",
+                "\"selects\": {}, \"track_selects\": {}, \"sector_selects\": {}, \"seeks\": {}, ",
+                "\"sectors_touched\": {}, \"sequential\": {}, \"repeated\": {}, \"random\": {}, ",
+                "\"errors\": {}}}"),
+                disk, c.reads, c.writes, c.selects, c.track_selects, c.sector_selects,
+                c.seeks, c.sectors.len(), c.sequential, c.repeated, c.random, c.errors));
+        }
+        format!("{{\n  \"image\": \"{}\",\n  \"disk_set\": {},\n  \"seconds\": {:.3},\n  \"disks\": [\n{}\n  ]\n}}\n",
+            json_escape(&self.label), self.disk_set, self.started.elapsed().as_secs_f64(), disks.join(",\n"))
+    }
+
+    pub fn write_json(&self, filename: &str) -> bool {
+        match fs::write(filename, self.to_json()) {
+            Ok(_) => true,
+            Err(error) => {
+                println!("Error writing the disk statistics to '{}': {}", filename, error);
+                false
+            }
+        }
+    }
+}
+
+// Escapes the text for a JSON string, the label comes from the config file
+fn json_escape(text: &str) -> String {
+    let mut escaped = String::new();
+    for ch in text.chars() {
+        match ch {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            _ if (ch as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", ch as u32)),
+            _ => escaped.push(ch),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escape_label() {
+        assert_eq!(json_escape("cpm22"), "cpm22");
+        assert_eq!(json_escape("my \"mon\" c:\\bin\t\u{1}"), "my \\\"mon\\\" c:\\\\bin\\t\\u0001");
+        let mut stats = DiskStats::new();
+        stats.set_label("a\"b", 2);
+        assert!(stats.to_json().contains("\"image\": \"a\\\"b\","));
+    }
+
+    #[test]
+    fn access_patterns() {
+        let mut stats = DiskStats::new();
+        stats.select(1);
+        for sector in [10, 11, 11, 12, 40].iter() {
+            stats.transfer(1, *sector, false);
+        }
+        stats.transfer(1, 41, true);
+        stats.select_track(1, 3);
+        stats.select_track(1, 3);
+        stats.select_track(1, 4);
+        stats.error(1);
+        let c = &stats.disks[&1];
+        assert_eq!((c.reads, c.writes, c.selects, c.errors), (5, 1, 1, 1));
+        assert_eq!((c.sequential, c.repeated, c.random), (3, 1, 2));
+        assert_eq!((c.track_selects, c.seeks, c.sectors.len()), (3, 2, 5));
+    }
+}
@@ -0,0 +1,263 @@
+use std::fs;
+use std::io::{BufWriter, Write};
+
+use iz80::*;
+
+use super::config::parse_number;
+use super::mbc2_machine::Mbc2Machine;
+use super::options::parse_address;
+
+// Trace of the instructions executed to a file, a line per instruction with
+// the address, the physical address on the 128KB RAM, the bank for the
+// lower 32KB, the opcode bytes, the disassembly and the registers changed:
+//
+//    0105 10105 1 3E 41        LD A, 41h               AF=4144
+//
+// The filter is a list of key=value:
+//    range: START-END addresses to trace, can be repeated
+//    bank: 0, 1, 2 or common for the upper 32KB, can be repeated
+//    start: start tracing when the PC reaches this address
+//    stop: stop tracing when the PC reaches this address
+//
+// Example: "range=0x0100-0x01ff,bank=1,start=0x0100"
+
+const REGISTERS: [(Reg16, &str); 7] = [
+    (Reg16::AF, "AF"), (Reg16::BC, "BC"), (Reg16::DE, "DE"), (Reg16::HL, "HL"),
+    (Reg16::IX, "IX"), (Reg16::IY, "IY"), (Reg16::SP, "SP")];
+
+const BANK_COMMON: u8 = 0xff;
+
+struct Instruction {
+    text: String,
+    registers: [u16; 7],
+}
+
+pub struct ExecTracer {
+    file: BufWriter<fs::File>,
+    ranges: Vec<(u16, u16)>,
+    banks: Vec<u8>,
+    start: Option<u16>,
+    stop: Option<u16>,
+    active: bool,
+    current: Option<Instruction>,
+}
+
+impl ExecTracer {
+    pub fn new(filename: &str, filter: Option<&str>) -> Result<ExecTracer, String> {
+        let mut tracer = ExecTracer {
+            file: BufWriter::new(fs::File::create(filename)
+                .map_err(|e| format!("Error creating the trace file '{}': {}", filename, e))?),
+            ranges: Vec::new(),
+            banks: Vec::new(),
+            start: None,
+            stop: None,
+            active: true,
+            current: None,
+        };
+
+        let filter = filter.unwrap_or("");
+        for param in filter.split(',').map(|p| p.trim()).filter(|p| !p.is_empty()) {
+            let (key, value) = match param.find('=') {
+                Some(pos) => (param[..pos].trim(), param[pos+1..].trim()),
+                None => return Err(format!("expected 'key=value' on trace filter, found '{}'", param)),
+            };
+            match key {
+                "range" => {
+                    let (start, end) = value.split_once('-')
+                        .ok_or(format!("expected START-END on trace range, found '{}'", value))?;
+                    tracer.ranges.push((parse_address(start)?, parse_address(end)?));
+                },
+                "bank" => tracer.banks.push(match parse_number(value) {
+                    Some(n) if n <= 2 => n as u8,
+                    _ if value == "common" => BANK_COMMON,
+                    _ => return Err(format!("invalid bank '{}' on trace filter", value)),
+                }),
+                "start" => tracer.start = Some(parse_address(value)?),
+                "stop" => tracer.stop = Some(parse_address(value)?),
+                _ => return Err(format!("unknown key '{}' on trace filter", key)),
+            }
+        }
+        tracer.active = tracer.start.is_none();
+        Ok(tracer)
+    }
+
+    // Called before each instruction
+    pub fn before(&mut self, cpu: &mut Cpu, machine: &mut Mbc2Machine) {
+        let pc = cpu.registers().pc();
+        if Some(pc) == self.start {
+            self.active = true;
+        }
+        if !self.active {
+            return;
+        }
+        if Some(pc) == self.stop {
+            // The stop instruction is the last traced
+            self.active = false;
+        }
+
+        let bank = if pc & 0x8000 != 0 { BANK_COMMON } else { machine.bank() };
+        if !self.ranges.is_empty() && !self.ranges.iter().any(|(start, end)| *start <= pc && pc <= *end) {
+            return;
+        }
+        if !self.banks.is_empty() && !self.banks.contains(&bank) {
+            return;
+        }
+
+        // The disassembly advances the PC
+        let disasm = cpu.disasm_instruction(machine);
+        cpu.registers().set_pc(pc);
+
+        let opcode: Vec<u8> = (0..4).map(|i| machine.peek(pc.wrapping_add(i))).collect();
+        let size = instruction_size(&opcode);
+        let bytes = (0..size).map(|i| format!("{:02X}", machine.peek(pc.wrapping_add(i))))
+            .collect::<Vec<String>>().join(" ");
+        let bank_text = if bank == BANK_COMMON { "-".to_string() } else { bank.to_string() };
+        let mut registers = [0; 7];
+        for (i, (reg, _)) in REGISTERS.iter().enumerate() {
+            registers[i] = cpu.registers().get16(*reg);
+        }
+        self.current = Some(Instruction {
+            text: format!("{:04X} {:05X} {} {:<12} {:<22}",
+                pc, machine.decode_address(pc), bank_text, bytes, disasm),
+            registers,
+        });
+    }
+
+    // Called after each instruction
+    pub fn after(&mut self, cpu: &mut Cpu) {
+        let instruction = match self.current.take() {
+            Some(instruction) => instruction,
+            None => return,
+        };
+
+        let mut changes = Vec::new();
+        for (i, (reg, name)) in REGISTERS.iter().enumerate() {
+            let value = cpu.registers().get16(*reg);
+            if value != instruction.registers[i] {
+                changes.push(format!("{}={:04X}", name, value));
+            }
+        }
+        let line = format!("{} {}", instruction.text, changes.join(" "));
+        let _ = writeln!(self.file, "{}", line.trim_end());
+    }
+}
+
+impl Drop for ExecTracer {
+    fn drop(&mut self) {
+        let _ = self.file.flush();
+    }
+}
+
+// Size of the Z80 instruction starting with the bytes given, at least 4
+fn instruction_size(bytes: &[u8]) -> u16 {
+    match bytes[0] {
+        0xcb => 2,
+        0xed => {
+            // LD (nn),rp and LD rp,(nn)
+            if bytes[1] & 0xc7 == 0x43 { 4 } else { 2 }
+        },
+        0xdd | 0xfd => match bytes[1] {
+            0xcb => 4, // With displacement
+            0xdd | 0xed | 0xfd => 1, // The prefix is ignored
+            opcode => {
+                // (HL) becomes (IX+d) with a displacement
+                let indexed = match opcode {
+                    0x34..=0x36 => true, // INC, DEC and LD (HL),n
+                    0x76 => false, // HALT
+                    0x40..=0xbf => opcode & 0x07 == 6 || opcode & 0xf8 == 0x70,
+                    _ => false,
+                };
+                1 + unprefixed_size(opcode) + indexed as u16
+            },
+        },
+        opcode => unprefixed_size(opcode),
+    }
+}
+
+fn unprefixed_size(opcode: u8) -> u16 {
+    let (x, y, z) = (opcode >> 6, (opcode >> 3) & 0x07, opcode & 0x07);
+    match (x, z) {
+        (0, 0) if y >= 2 => 2, // DJNZ and JR
+        (0, 1) if y & 1 == 0 => 3, // LD rp,nn
+        (0, 2) if y >= 4 => 3, // LD (nn),HL, LD HL,(nn), LD (nn),A and LD A,(nn)
+        (0, 6) => 2, // LD r,n
+        (3, 2) | (3, 4) => 3, // JP cc,nn and CALL cc,nn
+        (3, 3) => match y {
+            0 => 3, // JP nn
+            2 | 3 => 2, // OUT (n),A and IN A,(n)
+            _ => 1,
+        },
+        (3, 5) if y == 1 => 3, // CALL nn
+        (3, 6) => 2, // ALU n
+        _ => 1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn size(bytes: &[u8]) -> u16 {
+        let mut padded = bytes.to_vec();
+        padded.resize(4, 0);
+        instruction_size(&padded)
+    }
+
+    #[test]
+    fn unprefixed() {
+        assert_eq!(size(&[0x00]), 1); // NOP
+        assert_eq!(size(&[0x3e, 0x41]), 2); // LD A,41h
+        assert_eq!(size(&[0x21, 0x00, 0x80]), 3); // LD HL,8000h
+        assert_eq!(size(&[0x09]), 1); // ADD HL,BC
+        assert_eq!(size(&[0x22, 0x00, 0x80]), 3); // LD (8000h),HL
+        assert_eq!(size(&[0x3a, 0x00, 0x80]), 3); // LD A,(8000h)
+        assert_eq!(size(&[0x0a]), 1); // LD A,(BC)
+        assert_eq!(size(&[0x10, 0xfe]), 2); // DJNZ
+        assert_eq!(size(&[0x38, 0x00]), 2); // JR C
+        assert_eq!(size(&[0x08]), 1); // EX AF,AF'
+        assert_eq!(size(&[0x36, 0x00]), 2); // LD (HL),0
+        assert_eq!(size(&[0x76]), 1); // HALT
+        assert_eq!(size(&[0xc3, 0x00, 0x01]), 3); // JP
+        assert_eq!(size(&[0xca, 0x00, 0x01]), 3); // JP Z
+        assert_eq!(size(&[0xcd, 0x05, 0x00]), 3); // CALL
+        assert_eq!(size(&[0xc4, 0x05, 0x00]), 3); // CALL NZ
+        assert_eq!(size(&[0xd3, 0x01]), 2); // OUT (1),A
+        assert_eq!(size(&[0xdb, 0x01]), 2); // IN A,(1)
+        assert_eq!(size(&[0xfe, 0x0d]), 2); // CP 0Dh
+        assert_eq!(size(&[0xc9]), 1); // RET
+        assert_eq!(size(&[0xff]), 1); // RST 38h
+        assert_eq!(size(&[0xe9]), 1); // JP (HL)
+        assert_eq!(size(&[0xe3]), 1); // EX (SP),HL
+    }
+
+    #[test]
+    fn prefixed() {
+        assert_eq!(size(&[0xcb, 0x46]), 2); // BIT 0,(HL)
+        assert_eq!(size(&[0xed, 0xb0]), 2); // LDIR
+        assert_eq!(size(&[0xed, 0x4b, 0x00, 0x80]), 4); // LD BC,(8000h)
+        assert_eq!(size(&[0xed, 0x73, 0x00, 0x80]), 4); // LD (8000h),SP
+        assert_eq!(size(&[0xed, 0x78]), 2); // IN A,(C)
+        assert_eq!(size(&[0xed, 0x00]), 2); // Invalid, a NOP
+    }
+
+    #[test]
+    fn indexed() {
+        assert_eq!(size(&[0xdd, 0x21, 0x00, 0x80]), 4); // LD IX,8000h
+        assert_eq!(size(&[0xfd, 0x2a, 0x00, 0x80]), 4); // LD IY,(8000h)
+        assert_eq!(size(&[0xdd, 0x7e, 0x05]), 3); // LD A,(IX+5)
+        assert_eq!(size(&[0xfd, 0x70, 0x05]), 3); // LD (IY+5),B
+        assert_eq!(size(&[0xdd, 0x36, 0x05, 0x41]), 4); // LD (IX+5),41h
+        assert_eq!(size(&[0xdd, 0x34, 0x05]), 3); // INC (IX+5)
+        assert_eq!(size(&[0xfd, 0x86, 0x05]), 3); // ADD A,(IY+5)
+        assert_eq!(size(&[0xdd, 0xbe, 0x05]), 3); // CP (IX+5)
+        assert_eq!(size(&[0xdd, 0x66, 0x05]), 3); // LD H,(IX+5)
+        assert_eq!(size(&[0xdd, 0x26, 0x41]), 3); // LD IXH,41h
+        assert_eq!(size(&[0xdd, 0x7c]), 2); // LD A,IXH
+        assert_eq!(size(&[0xdd, 0x76]), 2); // HALT
+        assert_eq!(size(&[0xdd, 0xe9]), 2); // JP (IX)
+        assert_eq!(size(&[0xdd, 0xe5]), 2); // PUSH IX
+        assert_eq!(size(&[0xdd, 0xcb, 0x05, 0x46]), 4); // BIT 0,(IX+5)
+        assert_eq!(size(&[0xfd, 0xcb, 0x05, 0xc6]), 4); // SET 0,(IY+5)
+        assert_eq!(size(&[0xdd, 0xdd, 0x21]), 1); // Repeated prefix
+    }
+}
@@ -0,0 +1,260 @@
+use super::config::parse_number;
+use super::filesystem::FsError;
+
+// Disk fault injection to test the error handling of the BIOS. The rules
+// are given with --fault or on the [faults] section of the config file:
+//
+//    OPERATION[:key=value,...]
+//
+// The operations are seldisk, seltrack, selsect, read, write and sdmount.
+// The keys are:
+//    disk, track, sector: only for operations on this disk, track or sector
+//    after: fail after this number of matching operations
+//    count: fail only this number of times, forever by default
+//    byte: for read and write, fail on this byte of the sector, it must be
+//        lower than the sector size of the disk set
+//    error: FsError name or number, the default depends on the operation
+//
+// Examples: "read:disk=2,track=5,after=10", "sdmount:count=1"
+
+const MAX_SECTOR_SIZE: u32 = 0x1_0000;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum FaultOp {
+    SelDisk,
+    SelTrack,
+    SelSect,
+    Read,
+    Write,
+    SdMount,
+}
+
+#[derive(Clone, Debug)]
+pub struct FaultRule {
+    op: FaultOp,
+    disk: Option<u8>,
+    track: Option<u16>,
+    sector: Option<u8>,
+    after: u32,
+    count: Option<u32>,
+    byte: u32,
+    error: FsError,
+
+    matched: u32,
+    fired: u32,
+}
+
+pub struct Fault {
+    pub error: FsError,
+    pub byte: u32,
+}
+
+impl FaultRule {
+    pub fn parse(text: &str) -> Result<FaultRule, String> {
+        let text = text.trim();
+        let (op_name, params) = match text.find(':') {
+            Some(pos) => (&text[..pos], &text[pos+1..]),
+            None => (text, ""),
+        };
+
+        let op = match op_name.trim().to_lowercase().as_str() {
+            "seldisk" => FaultOp::SelDisk,
+            "seltrack" => FaultOp::SelTrack,
+            "selsect" => FaultOp::SelSect,
+            "read" | "readsect" => FaultOp::Read,
+            "write" | "writesect" => FaultOp::Write,
+            "sdmount" => FaultOp::SdMount,
+            _ => return Err(format!("unknown fault operation '{}'", op_name)),
+        };
+
+        let mut rule = FaultRule {
+            op,
+            disk: None,
+            track: None,
+            sector: None,
+            after: 0,
+            count: None,
+            byte: 0,
+            error: default_error(op),
+            matched: 0,
+            fired: 0,
+        };
+
+        for param in params.split(',').map(|p| p.trim()).filter(|p| !p.is_empty()) {
+            let (key, value) = match param.find('=') {
+                Some(pos) => (param[..pos].trim(), param[pos+1..].trim()),
+                None => return Err(format!("expected 'key=value' on fault rule, found '{}'", param)),
+            };
+
+            if key == "error" {
+                rule.error = error_from_name(value)
+                    .ok_or(format!("unknown fault error '{}'", value))?;
+                continue;
+            }
+
+            let n = parse_number(value)
+                .ok_or(format!("invalid number '{}' on fault rule", value))?;
+            match key {
+                "disk" if n <= 99 => rule.disk = Some(n as u8),
+                "track" if n <= 0xffff => rule.track = Some(n as u16),
+                "sector" if n <= 0xff => rule.sector = Some(n as u8),
+                "after" => rule.after = n,
+                "count" => rule.count = Some(n),
+                "byte" if op != FaultOp::Read && op != FaultOp::Write => {
+                    return Err("'byte' is only valid for read and write on fault rule".to_string());
+                },
+                "byte" if n < MAX_SECTOR_SIZE => rule.byte = n,
+                "disk" | "track" | "sector" | "byte" => {
+                    return Err(format!("value out of range for '{}' on fault rule", key));
+                },
+                _ => return Err(format!("unknown key '{}' on fault rule", key)),
+            }
+        }
+        Ok(rule)
+    }
+}
+
+pub struct Faults {
+    rules: Vec<FaultRule>,
+}
+
+impl Faults {
+    pub fn new(rules: Vec<FaultRule>) -> Faults {
+        Faults {
+            rules,
+        }
+    }
+
+    // The faults on a byte past the end of the sector would never fire
+    pub fn check_sector_size(&self, sector_size: u32) -> Result<(), String> {
+        match self.rules.iter().find(|rule| rule.byte >= sector_size) {
+            Some(rule) => Err(format!("the fault rule byte {} is beyond the sector size of {} bytes",
+                rule.byte, sector_size)),
+            None => Ok(()),
+        }
+    }
+
+    // Returns the fault to inject on this operation, if any
+    pub fn check(&mut self, op: FaultOp, disk: Option<u8>, track: u16, sector: u8) -> Option<Fault> {
+        for rule in self.rules.iter_mut() {
+            if rule.op != op
+                    || (rule.disk.is_some() && rule.disk != disk)
+                    || (rule.track.is_some() && rule.track != Some(track))
+                    || (rule.sector.is_some() && rule.sector != Some(sector)) {
+                continue;
+            }
+
+            rule.matched += 1;
+            if rule.matched <= rule.after {
+                continue;
+            }
+            if let Some(count) = rule.count {
+                if rule.fired >= count {
+                    continue;
+                }
+            }
+
+            rule.fired += 1;
+            return Some(Fault {
+                error: rule.error,
+                byte: rule.byte,
+            });
+        }
+        None
+    }
+}
+
+fn default_error(op: FaultOp) -> FsError {
+    match op {
+        FaultOp::SelDisk => FsError::NoFile,
+        FaultOp::SelTrack => FsError::IllegalTrackNumber,
+        FaultOp::SelSect => FsError::IllegalSectorNumber,
+        FaultOp::Read | FaultOp::Write => FsError::DiskError,
+        FaultOp::SdMount => FsError::NotReady,
+    }
+}
+
+fn error_from_name(name: &str) -> Option<FsError> {
+    let error = match name.to_lowercase().as_str() {
+        "ok" | "0" => FsError::Ok,
+        "diskerror" | "1" => FsError::DiskError,
+        "notready" | "2" => FsError::NotReady,
+        "nofile" | "3" => FsError::NoFile,
+        "notopened" | "4" => FsError::NotOpened,
+        "notenabled" | "5" => FsError::NotEnabled,
+        "nofilesystem" | "6" => FsError::NoFilesystem,
+        "illegaldisknumber" | "16" => FsError::IllegalDiskNumber,
+        "illegaltracknumber" | "17" => FsError::IllegalTrackNumber,
+        "illegalsectornumber" | "18" => FsError::IllegalSectorNumber,
+        _ => return None,
+    };
+    Some(error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_rule() {
+        let rule = FaultRule::parse("read:disk=2,track=0x10,sector=3,after=1,count=2,byte=5,error=notready").unwrap();
+        assert_eq!(rule.op, FaultOp::Read);
+        assert_eq!(rule.disk, Some(2));
+        assert_eq!(rule.track, Some(16));
+        assert_eq!(rule.sector, Some(3));
+        assert_eq!(rule.after, 1);
+        assert_eq!(rule.count, Some(2));
+        assert_eq!(rule.byte, 5);
+        assert_eq!(rule.error, FsError::NotReady);
+    }
+
+    #[test]
+    fn parse_defaults() {
+        let rule = FaultRule::parse(" SDMOUNT ").unwrap();
+        assert_eq!(rule.op, FaultOp::SdMount);
+        assert_eq!(rule.error, FsError::NotReady);
+        assert_eq!(rule.count, None);
+        assert_eq!(FaultRule::parse("writesect").unwrap().error, FsError::DiskError);
+        assert_eq!(FaultRule::parse("seldisk:error=16").unwrap().error, FsError::IllegalDiskNumber);
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(FaultRule::parse("format").is_err());
+        assert!(FaultRule::parse("read:disk").is_err());
+        assert!(FaultRule::parse("read:disk=100").is_err());
+        assert!(FaultRule::parse("read:sector=256").is_err());
+        assert!(FaultRule::parse("read:color=1").is_err());
+        assert!(FaultRule::parse("read:error=broken").is_err());
+        assert!(FaultRule::parse("read:byte=0x10000").is_err());
+        assert!(FaultRule::parse("seltrack:byte=1").is_err());
+    }
+
+    #[test]
+    fn sector_size() {
+        let faults = Faults::new(vec![FaultRule::parse("read:byte=511").unwrap()]);
+        assert!(faults.check_sector_size(512).is_ok());
+        assert!(faults.check_sector_size(128).is_err());
+    }
+
+    #[test]
+    fn after_and_count() {
+        let mut faults = Faults::new(vec![FaultRule::parse("read:disk=1,after=2,count=2").unwrap()]);
+        let mut fired = Vec::new();
+        for _ in 0..6 {
+            fired.push(faults.check(FaultOp::Read, Some(1), 0, 0).is_some());
+        }
+        assert_eq!(fired, [false, false, true, true, false, false]);
+        assert!(faults.check(FaultOp::Read, Some(2), 0, 0).is_none());
+    }
+
+    #[test]
+    fn match_position() {
+        let mut faults = Faults::new(vec![FaultRule::parse("write:track=5,sector=7,byte=3").unwrap()]);
+        assert!(faults.check(FaultOp::Write, Some(0), 5, 6).is_none());
+        assert!(faults.check(FaultOp::Read, Some(0), 5, 7).is_none());
+        let fault = faults.check(FaultOp::Write, None, 5, 7).unwrap();
+        assert_eq!(fault.byte, 3);
+        assert_eq!(fault.error, FsError::DiskError);
+    }
+}
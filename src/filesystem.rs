@@ -1,43 +1,122 @@
+use std::collections::HashMap;
 use std::fs;
 use std::io;
 use std::io::Seek;
 use std::io::Read;
 use std::io::Write;
+use std::path::PathBuf;
 
-const TRACKS: u16 = 512;
-const SECTORS: u8 = 32;
-const SECTOR_SIZE: u16 = 512;
+use super::disk_stats::DiskStats;
+use super::faults::{FaultOp, Faults};
+
+const SD_FOLDER: &str = "sd";
+const LOCK_FILE: &str = "z80-mbc2-emu.lock";
 
 #[derive(Clone, Copy, PartialEq, Debug)]
-enum FsError {
+pub struct DiskGeometry {
+    pub tracks: u32,
+    pub sectors: u32,
+    pub sector_size: u32,
+}
+
+// Geometry of the disk sets on the IOS firmware
+pub const DEFAULT_GEOMETRY: DiskGeometry = DiskGeometry {
+    tracks: 512,
+    sectors: 32,
+    sector_size: 512,
+};
+
+impl DiskGeometry {
+    pub fn disk_size(&self) -> u64 {
+        (self.tracks as u64) * (self.sectors as u64) * (self.sector_size as u64)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum FsError {
     // Error codes from Petit FatFs
     Ok = 0,
     DiskError = 1,
-    //NotReady = 2,
+    NotReady = 2, // Only with fault injection
     NoFile = 3,
     NotOpened = 4,
-    //NotEnabled = 5,
-    //NoFilesystem = 6,
+    NotEnabled = 5, // Only with fault injection
+    NoFilesystem = 6, // Only with fault injection
 
     IllegalDiskNumber = 16,
     IllegalTrackNumber = 17,
     IllegalSectorNumber = 18,
 }
 
+// What to do when a disk is locked by another instance of the emulator
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum LockMode {
+    Off,
+    Fail,
+    ReadOnly,
+    CopyOnWrite,
+}
+
+impl LockMode {
+    pub fn from_name(name: &str) -> Option<LockMode> {
+        match name {
+            "off" => Some(LockMode::Off),
+            "fail" => Some(LockMode::Fail),
+            "readonly" => Some(LockMode::ReadOnly),
+            "cow" => Some(LockMode::CopyOnWrite),
+            _ => None,
+        }
+    }
+}
+
+struct Disk {
+    file: Option<fs::File>, // None for a virtual disk without file
+    name: String,
+    read_only: bool,
+    // Private copy of the written sectors for copy-on-write
+    overlay: Option<HashMap<u64, Vec<u8>>>,
+}
+
 pub struct FileSystem {
-    file: Option<fs::File>,
+    folder: PathBuf,
+    disks: HashMap<u8, Disk>,
+    geometry: DiskGeometry,
+    lock_mode: LockMode,
+    root_lock: Option<fs::File>,
+    disk: Option<u8>,
     track: u16,
     sector: u8,
     last_error: FsError,
+
+    buffer: Vec<u8>,
+    sector_byte: u32,
+
+    faults: Faults,
+    fault_byte: Option<(u32, FsError)>,
+
+    pub stats: DiskStats,
 }
 
 impl FileSystem  {
     pub fn new() -> FileSystem {
         FileSystem {
-            file: None,
+            folder: PathBuf::from(SD_FOLDER),
+            disks: HashMap::new(),
+            geometry: DEFAULT_GEOMETRY,
+            lock_mode: LockMode::Off,
+            root_lock: None,
+            disk: None,
             track: 0,
             sector: 0,
             last_error: FsError::Ok,
+
+            buffer: Vec::new(),
+            sector_byte: 0,
+
+            faults: Faults::new(Vec::new()),
+            fault_byte: None,
+
+            stats: DiskStats::new(),
         }
     }
 
@@ -45,16 +124,122 @@ impl FileSystem  {
         self.last_error as u8
     }
 
+    pub fn last_error(&self) -> FsError {
+        self.last_error
+    }
+
+    pub fn set_geometry(&mut self, geometry: DiskGeometry) {
+        self.geometry = geometry;
+    }
+
+    pub fn sector_size(&self) -> u32 {
+        self.geometry.sector_size
+    }
+
+    pub fn set_faults(&mut self, faults: Faults) {
+        self.faults = faults;
+    }
+
+    pub fn set_lock_mode(&mut self, lock_mode: LockMode) {
+        self.lock_mode = lock_mode;
+        self.root_lock = None;
+        if lock_mode == LockMode::Off {
+            return;
+        }
+
+        // Lock the SD folder to warn early about other instances. The disk
+        // files are locked individually when selected, only the disks used
+        // by both instances are affected by the lock mode.
+        let result = fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(self.folder.join(LOCK_FILE));
+        if let Ok(file) = result {
+            match file.try_lock() {
+                Ok(_) => self.root_lock = Some(file),
+                Err(fs::TryLockError::WouldBlock) => {
+                    println!("Another instance of the emulator is using the 'sd' folder, the disks in use by both will be {}.",
+                        match lock_mode {
+                            LockMode::ReadOnly => "read only",
+                            LockMode::CopyOnWrite => "private copies",
+                            _ => "unavailable",
+                        });
+                },
+                Err(fs::TryLockError::Error(_)) => {
+                    // Locking is not supported, go on without it
+                },
+            }
+        }
+    }
+
+    fn inject_fault(&mut self, op: FaultOp, disk: Option<u8>, track: u16, sector: u8) -> Option<(u32, FsError)> {
+        let fault = self.faults.check(op, disk, track, sector)?;
+        println!("<<fault injected on {:?} disk {:?} track {} sector {}: {:?}>>",
+            op, disk, track, sector, fault.error);
+        Some((fault.byte, fault.error))
+    }
+
+    // Updates the statistics with the result of an operation
+    fn record_result(&mut self, disk: Option<u8>) {
+        if let Some(disk) = disk {
+            if self.last_error != FsError::Ok {
+                self.stats.error(disk);
+            }
+        }
+    }
+
+    fn record_transfer(&mut self, write: bool) {
+        if let Some(disk) = self.disk {
+            if self.last_error == FsError::Ok {
+                let sector = self.sector_pos() / (self.geometry.sector_size as u64);
+                self.stats.transfer(disk, sector, write);
+            } else {
+                self.stats.error(disk);
+            }
+        }
+    }
+
+    pub fn mount(&mut self) -> u8 {
+        match self.inject_fault(FaultOp::SdMount, self.disk, self.track, self.sector) {
+            Some((_, error)) => {
+                self.last_error = error;
+                self.record_result(self.disk);
+                error as u8
+            },
+            None => {
+                self.last_error = FsError::Ok;
+                FsError::Ok as u8
+            },
+        }
+    }
+
     pub fn select_disk(&mut self, disk_set: u8, disk_number: u8) {
-        let filename = format!("sd/DS{}N{:02}.DSK", disk_set, disk_number);
+        let path = self.folder.join(format!("DS{}N{:02}.DSK", disk_set, disk_number));
+        let filename = path.to_string_lossy().to_string();
 
         if disk_set > 9 || disk_number > 99 {
             self.last_error = FsError::IllegalDiskNumber
+        } else if let Some((_, error)) = self.inject_fault(FaultOp::SelDisk,
+                Some(disk_number), self.track, self.sector) {
+            self.last_error = error
+        } else if self.disks.contains_key(&disk_number) {
+            // Already opened and locked
+            self.disk = Some(disk_number);
+            self.last_error = FsError::Ok;
         } else {
-            let result = fs::OpenOptions::new()
+            let mut result = fs::OpenOptions::new()
                 .write(true)
                 .read(true)
                 .open(&filename);
+            let mut read_only = false;
+            if let Err(ref e) = result {
+                if e.kind() == io::ErrorKind::PermissionDenied && self.lock_mode == LockMode::ReadOnly {
+                    // Read only media or file
+                    result = fs::File::open(&filename);
+                    read_only = true;
+                }
+            }
 
             self.last_error = match result {
                 Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
@@ -64,90 +249,342 @@ impl FileSystem  {
                     FsError::DiskError
                 },
                 Ok(file) => {
-                    self.file = Some(file);
-                    FsError::Ok
+                    self.open_disk(&filename, disk_number, file, read_only)
                 }
             }
         }
+
+        if self.last_error == FsError::Ok {
+            self.stats.select(disk_number);
+        } else {
+            self.stats.error(disk_number);
+        }
+    }
+
+    fn open_disk(&mut self, filename: &str, disk_number: u8, file: fs::File, read_only: bool) -> FsError {
+        let error = self.check_size(filename, &file);
+        if error != FsError::Ok {
+            return error;
+        }
+
+        let mut disk = Disk {
+            file: Some(file),
+            name: filename.to_string(),
+            read_only,
+            overlay: None,
+        };
+
+        if self.lock_mode != LockMode::Off && !read_only {
+            let result = disk.file.as_ref().map(|file| file.try_lock());
+            match result.unwrap_or(Ok(())) {
+                Ok(_) => (),
+                Err(fs::TryLockError::WouldBlock) => {
+                    match self.lock_mode {
+                        LockMode::ReadOnly => {
+                            println!("<<{} is in use by another instance, using it read only>>",
+                                filename);
+                            // Without write access to the file
+                            disk.file = match fs::File::open(filename) {
+                                Ok(file) => Some(file),
+                                Err(_) => return FsError::DiskError,
+                            };
+                            disk.read_only = true;
+                        },
+                        LockMode::CopyOnWrite => {
+                            println!("<<{} is in use by another instance, using a private copy, the changes will be lost>>",
+                                filename);
+                            disk.overlay = Some(HashMap::new());
+                        },
+                        _ => {
+                            println!("<<{} is in use by another instance of the emulator>>",
+                                filename);
+                            return FsError::DiskError;
+                        },
+                    }
+                },
+                Err(fs::TryLockError::Error(_)) => {
+                    // Locking is not supported, go on without it
+                }
+            }
+        }
+
+        self.disks.insert(disk_number, disk);
+        self.disk = Some(disk_number);
+        FsError::Ok
+    }
+
+    // Disk kept in memory, over the file if any. The changes are lost on exit.
+    pub fn add_virtual_disk(&mut self, disk_number: u8, name: &str,
+            file: Option<fs::File>, overlay: HashMap<u64, Vec<u8>>) {
+        self.disks.insert(disk_number, Disk {
+            file,
+            name: name.to_string(),
+            read_only: false,
+            overlay: Some(overlay),
+        });
+    }
+
+    fn check_size(&self, filename: &str, file: &fs::File) -> FsError {
+        let size = match file.metadata() {
+            Ok(metadata) => metadata.len(),
+            Err(_) => return FsError::DiskError,
+        };
+
+        let expected = self.geometry.disk_size();
+        if size < expected {
+            println!("<<{} is truncated: {} bytes, {} expected for {} tracks of {} sectors of {} bytes>>",
+                filename, size, expected,
+                self.geometry.tracks, self.geometry.sectors, self.geometry.sector_size);
+            return FsError::DiskError;
+        }
+        if size > expected {
+            println!("<<{} is {} bytes, only the first {} are used with the disk set geometry>>",
+                filename, size, expected);
+        }
+        FsError::Ok
     }
 
     pub fn select_track(&mut self, track: u16) {
-        if track < TRACKS {
+        if let Some((_, error)) = self.inject_fault(FaultOp::SelTrack,
+                self.disk, track, self.sector) {
+            self.last_error = error;
+        } else if (track as u32) < self.geometry.tracks {
             self.track = track;
             self.last_error = FsError::Ok;
         } else {
             self.last_error = FsError::IllegalTrackNumber;
         }
+
+        if let Some(disk) = self.disk {
+            if self.last_error == FsError::Ok {
+                self.stats.select_track(disk, track);
+            } else {
+                self.stats.error(disk);
+            }
+        }
     }
 
     pub fn select_sector(&mut self, sector: u8) {
-        if sector < SECTORS {
+        if let Some((_, error)) = self.inject_fault(FaultOp::SelSect,
+                self.disk, self.track, sector) {
+            self.last_error = error;
+        } else if (sector as u32) < self.geometry.sectors {
             self.sector = sector;
             self.last_error = FsError::Ok;
         } else {
             self.last_error = FsError::IllegalSectorNumber;
         }
+
+        if let Some(disk) = self.disk {
+            if self.last_error == FsError::Ok {
+                self.stats.select_sector(disk);
+            } else {
+                self.stats.error(disk);
+            }
+        }
     }
 
-    fn sector_pos(&mut self) -> u64 {
-        ((self.track as u64) * (SECTORS as u64) + (self.sector as u64)) *
-        (SECTOR_SIZE as u64)
+    fn sector_pos(&self) -> u64 {
+        ((self.track as u64) * (self.geometry.sectors as u64) + (self.sector as u64)) *
+        (self.geometry.sector_size as u64)
     }
 
     pub fn seek(&mut self) {
-        let pos = self.sector_pos();
+        self.sector_byte = 0;
+        self.fault_byte = None;
+        self.buffer.resize(self.geometry.sector_size as usize, 0);
 
-        self.last_error = match self.file.as_mut() {
+        self.last_error = match self.disk {
             None => FsError::NotOpened,
-            Some(f) => {
-                match f.seek(io::SeekFrom::Start(pos)) { 
-                    Err(_) => FsError::DiskError,
-                    Ok(_) => FsError::Ok,
-                }
+            Some(_) => FsError::Ok,
+        }
+    }
+
+    // The sectors are read and written whole with the buffer: the
+    // copy-on-write overlay keeps whole sectors, and the write to a read
+    // only disk is rejected before any byte of the sector reaches the file.
+    fn load_sector(&mut self) -> FsError {
+        let pos = self.sector_pos();
+        let buffer = &mut self.buffer;
+        let disks = &mut self.disks;
+        let disk = match self.disk.and_then(|d| disks.get_mut(&d)) {
+            None => return FsError::NotOpened,
+            Some(disk) => disk,
+        };
+
+        if let Some(data) = disk.overlay.as_ref().and_then(|o| o.get(&pos)) {
+            buffer.copy_from_slice(data);
+            return FsError::Ok;
+        }
+
+        let file = match disk.file.as_mut() {
+            Some(file) => file,
+            None => {
+                // Virtual disk, the sectors not written are formatted
+                buffer.fill(0xe5);
+                return FsError::Ok;
             }
+        };
+        let result = file.seek(io::SeekFrom::Start(pos))
+            .and_then(|_| file.read_exact(buffer));
+        match result {
+            Err(_) => FsError::DiskError, // Including a truncated file
+            Ok(_) => FsError::Ok,
+        }
+    }
+
+    fn store_sector(&mut self) -> FsError {
+        let pos = self.sector_pos();
+        let buffer = &self.buffer;
+        let disks = &mut self.disks;
+        let disk = match self.disk.and_then(|d| disks.get_mut(&d)) {
+            None => return FsError::NotOpened,
+            Some(disk) => disk,
+        };
+
+        if disk.read_only {
+            println!("<<{} is read only, the write is rejected>>", disk.name);
+            return FsError::DiskError;
+        }
+
+        if let Some(overlay) = disk.overlay.as_mut() {
+            overlay.insert(pos, buffer.clone());
+            return FsError::Ok;
+        }
+
+        let file = match disk.file.as_mut() {
+            Some(file) => file,
+            None => return FsError::DiskError,
+        };
+        let result = file.seek(io::SeekFrom::Start(pos))
+            .and_then(|_| file.write_all(buffer));
+        match result {
+            Err(_) => FsError::DiskError,
+            Ok(_) => FsError::Ok,
+        }
+    }
+
+    // Injects a fault in the middle of a sector transfer
+    fn sector_fault(&mut self, op: FaultOp) -> bool {
+        if self.sector_byte == 0 {
+            self.fault_byte = self.inject_fault(op, self.disk, self.track, self.sector);
+        }
+        let byte = self.sector_byte;
+        self.sector_byte += 1;
+
+        match self.fault_byte {
+            Some((fault_byte, error)) if fault_byte == byte => {
+                self.last_error = error;
+                true
+            },
+            _ => false,
         }
     }
 
     pub fn read(&mut self) -> u8 {
+        let byte = self.sector_byte as usize;
+        if self.sector_fault(FaultOp::Read) {
+            self.record_result(self.disk);
+            return 0
+        }
         if self.last_error != FsError::Ok {
             return 0
         }
 
-        let mut value: u8 = 0;
-        self.last_error = match self.file.as_mut() {
-            None => FsError::NotOpened,
-            Some(f) => {
-                let mut buffer: [u8; 1] = [0; 1];
-                match f.read(&mut buffer) {
-                    Err(_) => FsError::DiskError,
-                    Ok(_) => {
-                        value = buffer[0];
-                        FsError::Ok
-                    }
-                }
+        if byte == 0 {
+            self.last_error = self.load_sector();
+            self.record_transfer(false);
+            if self.last_error != FsError::Ok {
+                return 0
             }
-        };
-        value
+        }
+        self.buffer.get(byte).copied().unwrap_or(0)
     }
 
     pub fn write(&mut self, data: u8) {
+        let byte = self.sector_byte as usize;
+        if self.sector_fault(FaultOp::Write) {
+            self.record_result(self.disk);
+            return
+        }
         if self.last_error != FsError::Ok {
             return
         }
-        self.last_error = match self.file.as_mut() {
-            None => FsError::NotOpened,
-            Some(f) => {
-                let mut buffer: [u8; 1] = [0; 1];
-                buffer[0] = data;
-                match f.write(&mut buffer) {
-                    Err(_) => FsError::DiskError,
-                    Ok(_) => FsError::Ok
-                }
+
+        if byte < self.buffer.len() {
+            self.buffer[byte] = data;
+            if byte + 1 == self.buffer.len() {
+                // The sector is complete
+                self.last_error = self.store_sector();
+                self.record_transfer(true);
             }
-        };
+        }
+    }
+}
 
+#[cfg(test)]
+mod tests {
+    use super::*;
 
+    const GEOMETRY: DiskGeometry = DiskGeometry {
+        tracks: 2,
+        sectors: 4,
+        sector_size: 128,
+    };
 
+    fn test_folder(name: &str) -> PathBuf {
+        let folder = std::env::temp_dir().join(format!("z80-mbc2-filesystem-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&folder);
+        fs::create_dir_all(&folder).unwrap();
+        for disk_set in 0..2 {
+            fs::write(folder.join(format!("DS{}N00.DSK", disk_set)), vec![0xe5; GEOMETRY.disk_size() as usize]).unwrap();
+        }
+        folder
     }
 
+    fn file_system(folder: &std::path::Path, lock_mode: LockMode) -> FileSystem {
+        let mut system = FileSystem::new();
+        system.folder = folder.to_path_buf();
+        system.set_geometry(GEOMETRY);
+        system.set_lock_mode(lock_mode);
+        system
+    }
+
+    #[test]
+    fn instances_on_other_disk_sets() {
+        let folder = test_folder("sets");
+        let mut first = file_system(&folder, LockMode::Fail);
+        let mut second = file_system(&folder, LockMode::Fail);
+        assert!(first.root_lock.is_some());
+        assert!(second.root_lock.is_none());
+
+        first.select_disk(0, 0);
+        assert_eq!(first.last_error(), FsError::Ok);
+        second.select_disk(1, 0);
+        assert_eq!(second.last_error(), FsError::Ok);
+        fs::remove_dir_all(&folder).unwrap();
+    }
+
+    #[test]
+    fn shared_disks() {
+        let folder = test_folder("shared");
+        let mut first = file_system(&folder, LockMode::Fail);
+        first.select_disk(0, 0);
+        assert_eq!(first.last_error(), FsError::Ok);
+
+        let mut second = file_system(&folder, LockMode::Fail);
+        second.select_disk(0, 0);
+        assert_eq!(second.last_error(), FsError::DiskError);
+
+        let mut third = file_system(&folder, LockMode::ReadOnly);
+        third.select_disk(0, 0);
+        assert_eq!(third.last_error(), FsError::Ok);
+        assert!(third.disks[&0].read_only);
+
+        let mut fourth = file_system(&folder, LockMode::CopyOnWrite);
+        fourth.select_disk(0, 0);
+        assert_eq!(fourth.last_error(), FsError::Ok);
+        assert!(fourth.disks[&0].overlay.is_some());
+        fs::remove_dir_all(&folder).unwrap();
+    }
 }
@@ -0,0 +1,293 @@
+// IOS firmware revisions of the Z80-MBC2. Each revision has its own table
+// of opcodes and of boot images supported:
+//    S220718-R190918: the disk opcodes and CP/M 3 support
+//    S220718-R240620: interrupts and options for Fuzix, UCSD Pascal and Collapse OS
+//    S220718-R290823: SPP Adapter opcodes, the version of the current SD pack
+//
+// The tables follow the lists of opcodes on the header comments of the
+// S220718-*.ino sketches of each revision. The opcodes keep their number on
+// the later revisions. The emulator only opcodes to access the host files
+// are on all of them.
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Firmware {
+    R190918,
+    R240620,
+    R290823,
+}
+
+pub const DEFAULT_FIRMWARE: Firmware = Firmware::R290823;
+// With all the opcodes, for the names used before the revision is known
+pub const LATEST_FIRMWARE: Firmware = Firmware::R290823;
+
+const OPCODE_NOP: u8 = 0xff;
+
+// Operations of the opcodes
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum IosOp {
+    // Write opcodes
+    UserLed,
+    SerialTx,
+    GpioAWrite,
+    GpioBWrite,
+    IoDirAWrite,
+    IoDirBWrite,
+    GppuAWrite,
+    GppuBWrite,
+    SelDisk,
+    SelTrack,
+    SelSect,
+    WriteSect,
+    SetBank,
+    SetIrq,
+    SetTick,
+    SetOpt,
+    SetSpp,
+    WrSpp,
+    HostName,
+    HostOpen,
+    HostWrite,
+    HostClose,
+
+    // Read opcodes
+    UserKey,
+    GpioARead,
+    GpioBRead,
+    SysFlags,
+    DateTime,
+    ErrDisk,
+    ReadSect,
+    SdMount,
+    AtxBuff,
+    SysIrq,
+    GetSpp,
+    HostRead,
+    HostErr,
+}
+
+impl IosOp {
+    pub fn name(&self) -> &'static str {
+        match self {
+            IosOp::UserLed => "USER LED",
+            IosOp::SerialTx => "SERIAL TX",
+            IosOp::GpioAWrite => "GPIOA W",
+            IosOp::GpioBWrite => "GPIOB W",
+            IosOp::IoDirAWrite => "IODIRA W",
+            IosOp::IoDirBWrite => "IODIRB W",
+            IosOp::GppuAWrite => "GPPUA W",
+            IosOp::GppuBWrite => "GPPUB W",
+            IosOp::SelDisk => "SELDISK",
+            IosOp::SelTrack => "SELTRACK",
+            IosOp::SelSect => "SELSECT",
+            IosOp::WriteSect => "WRITESECT",
+            IosOp::SetBank => "SETBANK",
+            IosOp::SetIrq => "SETIRQ",
+            IosOp::SetTick => "SETTICK",
+            IosOp::SetOpt => "SETOPT",
+            IosOp::SetSpp => "SETSPP",
+            IosOp::WrSpp => "WRSPP",
+            IosOp::HostName => "HOSTNAME",
+            IosOp::HostOpen => "HOSTOPEN",
+            IosOp::HostWrite => "HOSTWRITE",
+            IosOp::HostClose => "HOSTCLOSE",
+            IosOp::UserKey => "USER KEY",
+            IosOp::GpioARead => "GPIOA R",
+            IosOp::GpioBRead => "GPIOB R",
+            IosOp::SysFlags => "SYSFLAGS",
+            IosOp::DateTime => "DATETIME",
+            IosOp::ErrDisk => "ERRDISK",
+            IosOp::ReadSect => "READSECT",
+            IosOp::SdMount => "SDMOUNT",
+            IosOp::AtxBuff => "ATXBUFF",
+            IosOp::SysIrq => "SYSIRQ",
+            IosOp::GetSpp => "GETSPP",
+            IosOp::HostRead => "HOSTREAD",
+            IosOp::HostErr => "HOSTERR",
+        }
+    }
+}
+
+const R190918_OPCODES: [(u8, IosOp); 21] = [
+    (0x00, IosOp::UserLed),
+    (0x01, IosOp::SerialTx),
+    (0x03, IosOp::GpioAWrite),
+    (0x04, IosOp::GpioBWrite),
+    (0x05, IosOp::IoDirAWrite),
+    (0x06, IosOp::IoDirBWrite),
+    (0x07, IosOp::GppuAWrite),
+    (0x08, IosOp::GppuBWrite),
+    (0x09, IosOp::SelDisk),
+    (0x0A, IosOp::SelTrack),
+    (0x0B, IosOp::SelSect),
+    (0x0C, IosOp::WriteSect),
+    (0x0D, IosOp::SetBank),
+    (0x80, IosOp::UserKey),
+    (0x81, IosOp::GpioARead),
+    (0x82, IosOp::GpioBRead),
+    (0x83, IosOp::SysFlags),
+    (0x84, IosOp::DateTime),
+    (0x85, IosOp::ErrDisk),
+    (0x86, IosOp::ReadSect),
+    (0x87, IosOp::SdMount),
+];
+
+const R240620_OPCODES: [(u8, IosOp); 5] = [
+    (0x0E, IosOp::SetIrq),
+    (0x0F, IosOp::SetTick),
+    (0x10, IosOp::SetOpt),
+    (0x88, IosOp::AtxBuff),
+    (0x89, IosOp::SysIrq),
+];
+
+const R290823_OPCODES: [(u8, IosOp); 3] = [
+    (0x11, IosOp::SetSpp),
+    (0x12, IosOp::WrSpp),
+    (0x8A, IosOp::GetSpp),
+];
+
+const HOST_OPCODES: [(u8, IosOp); 6] = [
+    (0x40, IosOp::HostName),
+    (0x41, IosOp::HostOpen),
+    (0x42, IosOp::HostWrite),
+    (0x43, IosOp::HostClose),
+    (0xC0, IosOp::HostRead),
+    (0xC1, IosOp::HostErr),
+];
+
+// Built-in boot images that need R240620 or later
+const R240620_IMAGES: [&str; 3] = ["pascal", "collapse", "fuzix"];
+
+impl Firmware {
+    pub fn parse(text: &str) -> Result<Firmware, String> {
+        let upper = text.trim().to_uppercase();
+        match upper.strip_prefix("S220718-").unwrap_or(&upper) {
+            "R190918" => Ok(Firmware::R190918),
+            "R240620" => Ok(Firmware::R240620),
+            "R290823" => Ok(Firmware::R290823),
+            _ => Err(format!("unknown firmware '{}', use R190918, R240620 or R290823", text)),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Firmware::R190918 => "S220718-R190918",
+            Firmware::R240620 => "S220718-R240620",
+            Firmware::R290823 => "S220718-R290823",
+        }
+    }
+
+    fn opcodes(&self) -> Vec<(u8, IosOp)> {
+        let mut opcodes = R190918_OPCODES.to_vec();
+        if *self != Firmware::R190918 {
+            opcodes.extend_from_slice(&R240620_OPCODES);
+        }
+        if *self == Firmware::R290823 {
+            opcodes.extend_from_slice(&R290823_OPCODES);
+        }
+        opcodes.extend_from_slice(&HOST_OPCODES);
+        opcodes
+    }
+
+    pub fn opcode_table(&self) -> [Option<IosOp>; 256] {
+        let mut table = [None; 256];
+        for (opcode, op) in self.opcodes() {
+            table[opcode as usize] = Some(op);
+        }
+        table
+    }
+
+    pub fn opcode_name(&self, opcode: u8) -> &'static str {
+        match self.opcodes().iter().find(|(o, _)| *o == opcode) {
+            Some((_, op)) => op.name(),
+            None if opcode == OPCODE_NOP => "NOP",
+            None => "UNKNOWN",
+        }
+    }
+
+    // Opcode from its name, without spaces or underscores, or from its number
+    pub fn opcode_from_name(&self, name: &str) -> Option<u8> {
+        let simple = |text: &str| text.to_uppercase().replace([' ', '_'], "");
+        let name = simple(name);
+        if name == "NOP" {
+            return Some(OPCODE_NOP);
+        }
+        if let Some((opcode, _)) = self.opcodes().iter().find(|(_, op)| simple(op.name()) == name) {
+            return Some(*opcode);
+        }
+        let hex = name.strip_prefix("0X").unwrap_or(&name);
+        u8::from_str_radix(hex, 16).ok()
+    }
+
+    // Operation from its name or from its opcode number
+    pub fn op_from_name(&self, name: &str) -> Option<IosOp> {
+        let opcode = self.opcode_from_name(name)?;
+        self.opcodes().into_iter().find(|(o, _)| *o == opcode).map(|(_, op)| op)
+    }
+
+    pub fn supports_image(&self, id: &str) -> bool {
+        *self != Firmware::R190918 || !R240620_IMAGES.contains(&id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn revisions() {
+        assert_eq!(Firmware::parse("s220718-r240620"), Ok(Firmware::R240620));
+        assert_eq!(Firmware::parse("R190918"), Ok(Firmware::R190918));
+        assert!(Firmware::parse("R010101").is_err());
+        assert_eq!(Firmware::R290823.name(), "S220718-R290823");
+        assert!(Firmware::R190918.supports_image("cpm3"));
+        assert!(!Firmware::R190918.supports_image("fuzix"));
+        assert!(Firmware::R240620.supports_image("fuzix"));
+    }
+
+    #[test]
+    fn opcode_tables() {
+        let count = |firmware: Firmware| firmware.opcode_table().iter().filter(|o| o.is_some()).count();
+        assert_eq!(count(Firmware::R190918), 21 + 6);
+        assert_eq!(count(Firmware::R240620), 26 + 6);
+        assert_eq!(count(Firmware::R290823), 29 + 6);
+
+        let r190918 = Firmware::R190918.opcode_table();
+        assert_eq!(r190918[0x0D], Some(IosOp::SetBank));
+        assert_eq!(r190918[0x87], Some(IosOp::SdMount));
+        assert_eq!(r190918[0x0E], None);
+        assert_eq!(r190918[0x88], None);
+        let r240620 = Firmware::R240620.opcode_table();
+        assert_eq!(r240620[0x10], Some(IosOp::SetOpt));
+        assert_eq!(r240620[0x89], Some(IosOp::SysIrq));
+        assert_eq!(r240620[0x11], None);
+        assert_eq!(r240620[0x8A], None);
+        let r290823 = Firmware::R290823.opcode_table();
+        assert_eq!(r290823[0x12], Some(IosOp::WrSpp));
+        assert_eq!(r290823[0x8A], Some(IosOp::GetSpp));
+        // The host files on all of them
+        assert_eq!(r190918[0xC1], Some(IosOp::HostErr));
+    }
+
+    #[test]
+    fn names() {
+        assert_eq!(Firmware::R290823.opcode_name(0x86), "READSECT");
+        assert_eq!(Firmware::R290823.opcode_name(0x12), "WRSPP");
+        assert_eq!(Firmware::R190918.opcode_name(0x12), "UNKNOWN");
+        assert_eq!(Firmware::R190918.opcode_name(0xff), "NOP");
+        assert_eq!(Firmware::R290823.opcode_from_name("serial_tx"), Some(0x01));
+        assert_eq!(Firmware::R290823.opcode_from_name("User Key"), Some(0x80));
+        assert_eq!(Firmware::R290823.opcode_from_name("0x50"), Some(0x50));
+        assert_eq!(Firmware::R290823.opcode_from_name("8a"), Some(0x8a));
+        assert_eq!(Firmware::R190918.opcode_from_name("GETSPP"), None);
+        assert_eq!(Firmware::R290823.opcode_from_name("PRINT"), None);
+        assert_eq!(Firmware::R290823.op_from_name("readsect"), Some(IosOp::ReadSect));
+        assert_eq!(Firmware::R290823.op_from_name("0x12"), Some(IosOp::WrSpp));
+        assert_eq!(Firmware::R240620.op_from_name("0x12"), None);
+        // All the names are different
+        for firmware in [Firmware::R190918, Firmware::R240620, Firmware::R290823] {
+            for (opcode, op) in firmware.opcodes() {
+                assert_eq!(firmware.opcode_from_name(op.name()), Some(opcode));
+            }
+        }
+    }
+}
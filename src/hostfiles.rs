@@ -0,0 +1,286 @@
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::path::{Component, Path, PathBuf};
+
+// Host file access for the HGET, HPUT and HDIR utilities. It is not
+// provided by the real IOS, the opcodes used are free on the firmware.
+
+pub const RECORD_SIZE: usize = 128;
+const MAX_NAME_SIZE: usize = 128;
+const EOF_PADDING: u8 = 0x1a; // CP/M end of file, ctrl-z
+
+pub const MODE_READ: u8 = 0;
+pub const MODE_WRITE: u8 = 1;
+pub const MODE_DIRECTORY: u8 = 2;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum HostError {
+    Ok = 0,
+    NoFile = 1,
+    IoError = 2,
+    NotOpened = 3,
+    IllegalName = 4,
+    IllegalMode = 5,
+}
+
+enum Handle {
+    Closed,
+    Reading(fs::File),
+    Writing(fs::File),
+    Listing(io::Cursor<Vec<u8>>),
+}
+
+pub struct HostFiles {
+    root: PathBuf,
+    name: Vec<u8>,
+    handle: Handle,
+    record: [u8; RECORD_SIZE],
+    last_error: HostError,
+}
+
+impl HostFiles {
+    pub fn new(root: &str) -> HostFiles {
+        HostFiles {
+            root: PathBuf::from(root),
+            name: Vec::new(),
+            handle: Handle::Closed,
+            record: [EOF_PADDING; RECORD_SIZE],
+            last_error: HostError::Ok,
+        }
+    }
+
+    pub fn get_last_error(&self) -> u8 {
+        self.last_error as u8
+    }
+
+    pub fn clear_name(&mut self) {
+        self.name.clear();
+    }
+
+    pub fn push_name(&mut self, ch: u8) {
+        if self.name.len() < MAX_NAME_SIZE {
+            self.name.push(ch);
+        }
+    }
+
+    pub fn open(&mut self, mode: u8) {
+        self.close();
+
+        let path = match self.resolve_name() {
+            Some(path) => path,
+            None => {
+                self.last_error = HostError::IllegalName;
+                return;
+            }
+        };
+
+        let result = match mode {
+            MODE_READ => fs::File::open(&path).map(Handle::Reading),
+            MODE_WRITE => fs::File::create(&path).map(Handle::Writing),
+            MODE_DIRECTORY => directory_listing(&path).map(Handle::Listing),
+            _ => {
+                self.last_error = HostError::IllegalMode;
+                return;
+            }
+        };
+
+        self.last_error = match result {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                HostError::NoFile
+            },
+            Err(_) => {
+                HostError::IoError
+            },
+            Ok(handle) => {
+                self.handle = handle;
+                HostError::Ok
+            }
+        }
+    }
+
+    pub fn close(&mut self) {
+        self.handle = Handle::Closed;
+        self.last_error = HostError::Ok;
+    }
+
+    // Loads the next record. Returns the number of valid bytes, zero at
+    // the end of the file. The record is padded with ctrl-z.
+    pub fn read_record(&mut self) -> u8 {
+        self.record = [EOF_PADDING; RECORD_SIZE];
+        let reader: &mut dyn Read = match self.handle {
+            Handle::Reading(ref mut f) => f,
+            Handle::Listing(ref mut c) => c,
+            _ => {
+                self.last_error = HostError::NotOpened;
+                return 0;
+            }
+        };
+
+        let mut size = 0;
+        while size < RECORD_SIZE {
+            match reader.read(&mut self.record[size..]) {
+                Ok(0) => break,
+                Ok(n) => size += n,
+                Err(_) => {
+                    self.last_error = HostError::IoError;
+                    return 0;
+                }
+            }
+        }
+        self.last_error = HostError::Ok;
+        size as u8
+    }
+
+    pub fn record_byte(&self, index: usize) -> u8 {
+        self.record[index % RECORD_SIZE]
+    }
+
+    pub fn write(&mut self, data: u8) {
+        self.last_error = match self.handle {
+            Handle::Writing(ref mut f) => {
+                match f.write_all(&[data]) {
+                    Err(_) => HostError::IoError,
+                    Ok(_) => HostError::Ok,
+                }
+            },
+            _ => HostError::NotOpened,
+        }
+    }
+
+    fn resolve_name(&self) -> Option<PathBuf> {
+        // Only relative paths inside the host folder are allowed
+        let name = String::from_utf8_lossy(&self.name).trim().to_string();
+        let relative = Path::new(&name);
+        for component in relative.components() {
+            match component {
+                Component::Normal(_) | Component::CurDir => (),
+                _ => return None,
+            }
+        }
+        let path = self.root.join(relative);
+        if path.exists() {
+            return Some(path);
+        }
+
+        // CP/M sends the names in uppercase, look for a case insensitive match
+        let parent = path.parent()?;
+        let file_name = path.file_name()?.to_string_lossy().to_lowercase();
+        if let Ok(entries) = fs::read_dir(parent) {
+            for entry in entries.flatten() {
+                if entry.file_name().to_string_lossy().to_lowercase() == file_name {
+                    return Some(entry.path());
+                }
+            }
+        }
+        Some(path)
+    }
+}
+
+fn directory_listing(path: &Path) -> io::Result<io::Cursor<Vec<u8>>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_file() {
+            files.push((entry.file_name().to_string_lossy().to_string(), metadata.len()));
+        }
+    }
+    files.sort();
+
+    let mut listing = String::new();
+    for (name, size) in files {
+        listing.push_str(&format!("{:<24} {:>10}\r\n", name, size));
+    }
+    Ok(io::Cursor::new(listing.into_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_folder(name: &str) -> PathBuf {
+        let folder = std::env::temp_dir().join(format!("z80-mbc2-hostfiles-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&folder);
+        fs::create_dir_all(&folder).unwrap();
+        folder
+    }
+
+    fn set_name(host: &mut HostFiles, name: &str) {
+        host.clear_name();
+        for ch in name.bytes() {
+            host.push_name(ch);
+        }
+    }
+
+    #[test]
+    fn names_outside_of_the_folder() {
+        let folder = test_folder("names");
+        let mut host = HostFiles::new(folder.to_str().unwrap());
+        for name in ["../secret", "/etc/passwd", "a/../../b"].iter() {
+            set_name(&mut host, name);
+            host.open(MODE_READ);
+            assert_eq!(host.get_last_error(), HostError::IllegalName as u8, "{}", name);
+        }
+        set_name(&mut host, "missing.txt");
+        host.open(MODE_READ);
+        assert_eq!(host.get_last_error(), HostError::NoFile as u8);
+        fs::remove_dir_all(&folder).unwrap();
+    }
+
+    #[test]
+    fn write_and_read_records() {
+        let folder = test_folder("records");
+        let mut host = HostFiles::new(folder.to_str().unwrap());
+        set_name(&mut host, "data.bin");
+        host.open(MODE_WRITE);
+        for i in 0..200 {
+            host.write(i as u8);
+        }
+        host.close();
+        assert_eq!(fs::read(folder.join("data.bin")).unwrap().len(), 200);
+
+        // CP/M names are uppercase
+        set_name(&mut host, "DATA.BIN");
+        host.open(MODE_READ);
+        assert_eq!(host.get_last_error(), HostError::Ok as u8);
+        assert_eq!(host.read_record(), 128);
+        assert_eq!(host.record_byte(127), 127);
+        assert_eq!(host.read_record(), 72);
+        assert_eq!(host.record_byte(71), 199);
+        assert_eq!(host.record_byte(72), EOF_PADDING);
+        assert_eq!(host.read_record(), 0);
+        fs::remove_dir_all(&folder).unwrap();
+    }
+
+    #[test]
+    fn listing() {
+        let folder = test_folder("listing");
+        fs::write(folder.join("b.txt"), "12345").unwrap();
+        fs::write(folder.join("a.txt"), "").unwrap();
+        fs::create_dir(folder.join("sub")).unwrap();
+        let mut host = HostFiles::new(folder.to_str().unwrap());
+        set_name(&mut host, ".");
+        host.open(MODE_DIRECTORY);
+        let size = host.read_record() as usize;
+        let text: Vec<u8> = (0..size).map(|i| host.record_byte(i)).collect();
+        assert_eq!(String::from_utf8(text).unwrap(), format!("{:<24} {:>10}\r\n{:<24} {:>10}\r\n",
+            "a.txt", 0, "b.txt", 5));
+        fs::remove_dir_all(&folder).unwrap();
+    }
+
+    #[test]
+    fn not_opened_and_mode() {
+        let folder = test_folder("mode");
+        let mut host = HostFiles::new(folder.to_str().unwrap());
+        host.write(0);
+        assert_eq!(host.get_last_error(), HostError::NotOpened as u8);
+        assert_eq!(host.read_record(), 0);
+        assert_eq!(host.get_last_error(), HostError::NotOpened as u8);
+        set_name(&mut host, "x");
+        host.open(7);
+        assert_eq!(host.get_last_error(), HostError::IllegalMode as u8);
+        fs::remove_dir_all(&folder).unwrap();
+    }
+}
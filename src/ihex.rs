@@ -0,0 +1,139 @@
+// Intel HEX records, with the extended segment and linear address records
+
+pub const DATA: u8 = 0x00;
+pub const END_OF_FILE: u8 = 0x01;
+pub const EXTENDED_SEGMENT_ADDRESS: u8 = 0x02;
+pub const START_SEGMENT_ADDRESS: u8 = 0x03;
+pub const EXTENDED_LINEAR_ADDRESS: u8 = 0x04;
+pub const START_LINEAR_ADDRESS: u8 = 0x05;
+
+pub struct Record {
+    pub kind: u8,
+    pub address: u16,
+    pub data: Vec<u8>,
+}
+
+pub struct HexFile {
+    pub blocks: Vec<(u32, Vec<u8>)>,
+    pub start: Option<u32>,
+}
+
+// Parses a line like ":10010000214601360121470136007EFE09D2190140"
+pub fn parse_record(line: &str) -> Result<Record, String> {
+    let hex = line.trim().strip_prefix(':')
+        .ok_or("missing ':' at the start of the record")?;
+    if hex.len() % 2 != 0 || hex.len() < 10 {
+        return Err("invalid record length".to_string());
+    }
+
+    let mut bytes = Vec::new();
+    for i in (0..hex.len()).step_by(2) {
+        let byte = hex.get(i..i + 2).and_then(|b| u8::from_str_radix(b, 16).ok())
+            .ok_or("invalid hex digit")?;
+        bytes.push(byte);
+    }
+
+    let count = bytes[0] as usize;
+    if bytes.len() != count + 5 {
+        return Err("byte count does not match the record length".to_string());
+    }
+    let checksum = bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+    if checksum != 0 {
+        return Err("checksum error".to_string());
+    }
+
+    Ok(Record {
+        kind: bytes[3],
+        address: ((bytes[1] as u16) << 8) + bytes[2] as u16,
+        data: bytes[4..4 + count].to_vec(),
+    })
+}
+
+fn word(data: &[u8]) -> u32 {
+    ((data[0] as u32) << 8) + data[1] as u32
+}
+
+pub fn parse(text: &str) -> Result<HexFile, String> {
+    let mut hex = HexFile {
+        blocks: Vec::new(),
+        start: None,
+    };
+    let mut base = 0_u32;
+    for (i, line) in text.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record = parse_record(line)
+            .map_err(|e| format!("line {}: {}", i + 1, e))?;
+        let size_error = || format!("line {}: invalid size for record type {:02X}", i + 1, record.kind);
+        match record.kind {
+            DATA => hex.blocks.push((base + record.address as u32, record.data)),
+            END_OF_FILE => break,
+            EXTENDED_SEGMENT_ADDRESS if record.data.len() == 2 => base = word(&record.data) << 4,
+            EXTENDED_LINEAR_ADDRESS if record.data.len() == 2 => base = word(&record.data) << 16,
+            START_SEGMENT_ADDRESS if record.data.len() == 4 => {
+                hex.start = Some((word(&record.data) << 4) + word(&record.data[2..]));
+            },
+            START_LINEAR_ADDRESS if record.data.len() == 4 => {
+                hex.start = Some((word(&record.data) << 16) + word(&record.data[2..]));
+            },
+            EXTENDED_SEGMENT_ADDRESS | EXTENDED_LINEAR_ADDRESS
+                | START_SEGMENT_ADDRESS | START_LINEAR_ADDRESS => return Err(size_error()),
+            _ => return Err(format!("line {}: unknown record type {:02X}", i + 1, record.kind)),
+        }
+    }
+    Ok(hex)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn data_record() {
+        let record = parse_record(":10010000214601360121470136007EFE09D2190140").unwrap();
+        assert_eq!(record.kind, DATA);
+        assert_eq!(record.address, 0x0100);
+        assert_eq!(record.data.len(), 16);
+        assert_eq!(record.data[..3], [0x21, 0x46, 0x01]);
+        assert_eq!(parse_record(" :00000001FF \r").unwrap().kind, END_OF_FILE);
+    }
+
+    #[test]
+    fn record_errors() {
+        let error = |line: &str| parse_record(line).err().unwrap();
+        assert_eq!(error("00000001FF"), "missing ':' at the start of the record");
+        assert_eq!(error(":00000001F"), "invalid record length");
+        assert_eq!(error(":000001FF"), "invalid record length");
+        assert_eq!(error(":0000000GFF"), "invalid hex digit");
+        assert_eq!(error(":01000001FF"), "byte count does not match the record length");
+        assert_eq!(error(":00000001FE"), "checksum error");
+    }
+
+    #[test]
+    fn extended_addresses() {
+        let hex = parse("
+            :0200000212FFEB
+            :0100000042BD
+            :020000040001F9
+            :0101000043BB
+            :0400000500001000E7
+            :00000001FF
+            :0100000044BB
+            ").unwrap();
+        assert_eq!(hex.blocks, [(0x12ff0, vec![0x42]), (0x10100, vec![0x43])]);
+        assert_eq!(hex.start, Some(0x1000));
+
+        let hex = parse(":0400000300100020C9\n").unwrap();
+        assert!(hex.blocks.is_empty());
+        assert_eq!(hex.start, Some(0x120));
+    }
+
+    #[test]
+    fn file_errors() {
+        let error = |text: &str| parse(text).err().unwrap();
+        assert_eq!(error(":0100000042BD\n:00000001FE"), "line 2: checksum error");
+        assert_eq!(error(":03000002000100FA"), "line 1: invalid size for record type 02");
+        assert_eq!(error(":0000000AF6"), "line 1: unknown record type 0A");
+    }
+}
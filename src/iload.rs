@@ -0,0 +1,55 @@
+use iz80::Machine;
+
+use super::ihex;
+use super::mbc2_machine::Mbc2Machine;
+
+// The iLoad boot mode of IOS: an Intel HEX stream is received on the
+// console, loaded and executed from the address of the first record.
+// Returns None on errors, the real iLoad halts the system.
+pub fn iload(machine: &mut Mbc2Machine) -> Option<u16> {
+    machine.print_host("iLoad - Intel-Hex Loader - S200718\r\n\r\nWaiting input stream...\r\n");
+
+    let mut start = None;
+    loop {
+        let line = machine.read_host_line("");
+
+        // Anything before the record mark is ignored
+        let record = match line.find(':') {
+            Some(pos) => ihex::parse_record(&line[pos..]),
+            None => continue,
+        };
+        let record = match record {
+            Ok(record) => record,
+            Err(message) => return load_error(machine, &message),
+        };
+
+        match record.kind {
+            ihex::DATA => {
+                if record.address as usize + record.data.len() > 0x1_0000 {
+                    return load_error(machine, "address out of memory");
+                }
+                if start.is_none() {
+                    start = Some(record.address);
+                }
+                for (i, value) in record.data.iter().enumerate() {
+                    machine.poke(record.address + i as u16, *value);
+                }
+            },
+            ihex::END_OF_FILE => break,
+            _ => return load_error(machine, "illegal record type"),
+        }
+    }
+
+    match start {
+        Some(start) => {
+            machine.print_host(&format!("Starting Address: {:04X}H\r\n", start));
+            Some(start)
+        },
+        None => load_error(machine, "no data records"),
+    }
+}
+
+fn load_error(machine: &mut Mbc2Machine, message: &str) -> Option<u16> {
+    machine.print_host(&format!("ILOAD: {}!\r\nLoad error - System halted\r\n", message));
+    None
+}
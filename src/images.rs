@@ -1,117 +1,274 @@
-use std::env;
 use std::fs;
-use std::io::Read;
 use std::path::Path;
 use std::process;
 
-use iz80::Machine;
+use iz80::{Cpu, Machine};
 
+use super::bootstrap::bootstrap;
+use super::ihex;
 use super::mbc2_machine::Mbc2Machine;
+use super::options::LoadFile;
 
 pub struct ImageDefinition {
-    pub id: &'static str,
-    pub name: &'static str,
-    pub file: &'static str,
+    pub id: String,
+    pub name: String,
+    pub file: String,
     pub address: u16,
     pub disk_set: u8,
     pub int_rx: bool,
     pub int_sys_tick: bool,
+    pub pc: Option<u16>, // The load address by default
+    pub loads: Vec<LoadFile>,
+    pub iload: bool, // Intel HEX upload on the console instead of the file
 }
 
 const IMAGES_FOLDER: &str = "sd";
+const COM_ADDRESS: u16 = 0x0100;
 
 
-static IMAGES: [ImageDefinition; 9] = [
-    ImageDefinition {id: "basic", name: "Basic", file: "basic47.bin",
-        address: 0x0000, disk_set: 0xff, int_rx: true, int_sys_tick: false},
-    ImageDefinition {id: "forth", name: "Forth", file: "forth13.bin",
-        address: 0x0100, disk_set: 0xff, int_rx: false, int_sys_tick: false},
-    ImageDefinition {id: "autoboot", name: "Autoboot", file: "autoboot.bin",
-        address: 0x0000, disk_set: 0xff, int_rx: false, int_sys_tick: false},
-    ImageDefinition {id: "cpm22", name: "CP/M 2.2", file: "cpm22.bin",
-        address: 0xD1E0, disk_set: 0, int_rx: false, int_sys_tick: false},
-    ImageDefinition {id: "qpm", name: "QP/M 2.71", file: "QPMLDR.BIN",
-        address: 0x0080, disk_set: 1, int_rx: false, int_sys_tick: false},
-    ImageDefinition {id: "cpm3", name: "CP/M 3.0", file: "CPMLDR.COM",
-        address: 0x0100, disk_set: 2, int_rx: false, int_sys_tick: false},
-    ImageDefinition {id: "pascal", name: "UCSD Pascal", file: "ucsdldr.bin",
-        address: 0x0000, disk_set: 3, int_rx: false, int_sys_tick: false},
-    ImageDefinition {id: "collapse", name: "Collapse OS", file: "cos.bin",
-        address: 0x0000, disk_set: 4, int_rx: false, int_sys_tick: false},
-    ImageDefinition {id: "fuzix", name: "Fuzix OS", file: "fuzix.bin",
-        address: 0x0000, disk_set: 6, int_rx: true, int_sys_tick: false},
-];
-
-const USAGE: &'static str =
-"Usage: z80-mbc2-emu IMAGE
+fn builtin(id: &str, name: &str, file: &str, address: u16, disk_set: u8,
+        int_rx: bool, int_sys_tick: bool) -> ImageDefinition {
+    ImageDefinition {
+        id: id.to_string(),
+        name: name.to_string(),
+        file: file.to_string(),
+        address,
+        disk_set,
+        int_rx,
+        int_sys_tick,
+        pc: None,
+        loads: Vec::new(),
+        iload: false,
+    }
+}
+
+// The built-in images followed by the ones of the config file, that can
+// replace them
+pub fn all_images(config_images: Vec<ImageDefinition>) -> Vec<ImageDefinition> {
+    let mut images = vec![
+        builtin("basic", "Basic", "basic47.bin", 0x0000, 0xff, true, false),
+        builtin("forth", "Forth", "forth13.bin", 0x0100, 0xff, false, false),
+        builtin("autoboot", "Autoboot", "autoboot.bin", 0x0000, 0xff, false, false),
+        builtin("cpm22", "CP/M 2.2", "cpm22.bin", 0xD1E0, 0, false, false),
+        builtin("qpm", "QP/M 2.71", "QPMLDR.BIN", 0x0080, 1, false, false),
+        builtin("cpm3", "CP/M 3.0", "CPMLDR.COM", 0x0100, 2, false, false),
+        builtin("pascal", "UCSD Pascal", "ucsdldr.bin", 0x0000, 3, false, false),
+        builtin("collapse", "Collapse OS", "cos.bin", 0x0000, 4, false, false),
+        builtin("fuzix", "Fuzix OS", "fuzix.bin", 0x0000, 6, true, false),
+        ImageDefinition {
+            iload: true,
+            ..builtin("iload", "iLoad", "", 0x0000, 0xff, false, false)
+        },
+    ];
+    for image in config_images {
+        match images.iter().position(|i| i.id == image.id) {
+            Some(pos) => images[pos] = image,
+            None => images.push(image),
+        }
+    }
+    images
+}
+
+const USAGE: &str =
+"Usage: z80-mbc2-emu [OPTIONS] [IMAGE]
+  OPTIONS can be:
+
+    --load FILE[@ADDRESS]
+                     load a binary, Intel HEX or CP/M .COM file in memory, the
+                     image is optional. It runs the first file loaded.
+    --bank N         bank 0, 1 or 2 for the next --load files and to start
+    --pc ADDRESS     start address
+    --sp ADDRESS     initial stack pointer
+    --config FILE    configuration file (default 'z80-mbc2-emu.ini')
+    --fault RULE     inject disk faults, see the README for the rule syntax
+    --lock MODE      when a disk is in use by another instance: fail (default),
+                     readonly, cow for a private copy or off to disable locking
+    --disk-stats     show the disk activity on exit
+    --disk-stats-json FILE
+                     write the disk activity as JSON to FILE on exit
+    --cpm-trace FILE write the calls to the CP/M BDOS and BIOS to FILE
+    --ios-trace FILE write the IOS opcodes to FILE, as CSV if it ends with .csv
+                     or as JSON lines
+    --ios-trace-filter LIST
+                     comma separated opcodes to trace, or to skip with '-'
+    --exec-trace FILE
+                     write the instructions executed to FILE
+    --exec-trace-filter FILTER
+                     addresses, banks and start and stop addresses to trace
+    --crash-report FILE
+                     write a crash report to FILE when the emulation aborts
+    --unimplemented POLICY
+                     on the IOS opcodes not implemented: abort (default), warn
+                     or prompt, globally or by opcode as OPCODE=POLICY
+    --firmware REV   IOS firmware revision: R190918, R240620 or R290823 (default)
+    --printer FILE   append the output of the SPP Adapter printer port to FILE
+    --fast-boot      poke the boot program in memory instead of running the IOS bootstrap
+    --timing         charge the IOS I/O WAIT states and run at the speed of the Z80 clock
+    --serial         emulate the baud rate and the RX and TX buffers of the serial port
+    --no-idle        do not wait for input when the Z80 only polls the console
+    --monitor-key KEY
+                     key to open the monitor as ctrl-X, ctrl-] by default, or off
+    --pty            use a pseudo-terminal as the serial console, on Unix
+    --pty-link PATH  same as --pty, with a link to the pseudo-terminal on PATH
+    --modem          add a Hayes modem on the serial port, dialing TCP connections
+    --modem-listen [ADDRESS:]PORT
+                     same as --modem, with incoming connections on the TCP PORT,
+                     of 127.0.0.1 by default
+    --host-files DIR enable the emulator only host file opcodes for the HGET, HPUT
+                     and HDIR utilities, with the files of the folder DIR
+
+  or: z80-mbc2-emu run [OPTIONS] IMAGE PROGRAM.COM [ARGS...]
+    run a CP/M program and exit when it returns to the CCP, with the options:
+    --drive LETTER   drive for the program, A by default, the disk is not modified
+    --timeout SECS   exit with an error after this time (default 60)
+    --fail-on TEXT   exit with an error if the output has a line with TEXT
+
+  or: z80-mbc2-emu bdos [--host-dir DIR] [--cpm-trace FILE] PROGRAM.COM [ARGS...]
+    run a CP/M program with the BDOS of the emulator and the host files
+
+  or: z80-mbc2-emu convert [--add | --force] SOURCE [TARGET]
+    copy the CP/M files between disk images, see the README for the formats
+
   IMAGE can be:
 ";
 
-const USAGE2: &'static str =
+const USAGE2: &str =
 "
 Download the images from https://cdn.hackaday.io/files/1599736844284832/SD-S220718-R290823-v2.zip into the 'sd' directory.
 ";
 
-pub fn select_image() -> &'static ImageDefinition {
-    let args: Vec<String> = env::args().collect();
-
-    if args.len() < 2 {
-        usage();
-        process::exit(1);
-    }
-    let selection = &args[1];
-
-    for i in 0..IMAGES.len() {
-        if IMAGES[i].id == selection {
-            return &IMAGES[i];
+pub fn select_image<'a>(images: &'a [ImageDefinition], selection: &str) -> &'a ImageDefinition {
+    for image in images.iter() {
+        if image.id == selection {
+            return image;
         }
     }
 
     println!("image '{}' not found.", selection);
-    usage();
+    usage(images);
     process::exit(1);
 }
 
-pub fn usage() {
+pub fn usage(images: &[ImageDefinition]) {
     println!("{}", USAGE);
-    for i in 0..IMAGES.len() {
-        let filename = Path::new(IMAGES_FOLDER).join(Path::new(IMAGES[i].file));
-        println!("    {} for {} using {}", IMAGES[i].id, IMAGES[i].name, filename.to_str().unwrap());
+    for image in images.iter() {
+        if image.iload {
+            println!("    {} for {}, upload an Intel HEX file on the console", image.id, image.name);
+            continue;
+        }
+        let filename = Path::new(IMAGES_FOLDER).join(Path::new(&image.file));
+        println!("    {} for {} using {}", image.id, image.name, filename.to_str().unwrap());
     }
     println!("{}", USAGE2);
 }
 
-pub fn load_image(machine: &mut Mbc2Machine, image: &ImageDefinition) -> bool {
-    let filename = Path::new(IMAGES_FOLDER).join(Path::new(image.file));
-
-    println!("Loading {}", filename.to_string_lossy());
+// Loads the image with the bootstrap of IOS, or pokes it and sets the start
+// address with fast_boot. Returns the start address.
+pub fn load_image(machine: &mut Mbc2Machine, cpu: &mut Cpu, image: &ImageDefinition,
+        fast_boot: bool) -> Option<u16> {
+    if image.iload {
+        // Loaded later from the console
+        return Some(image.address);
+    }
+    let filename = Path::new(IMAGES_FOLDER).join(Path::new(&image.file));
 
-    let mut file = match fs::File::open(&filename) {
-        Ok(file) => file,
-        Err(error) => {
-            println!("Error opening the file '{}': {}",
-                filename.to_string_lossy(), error);
-            return false;
-        }
-    };
+    if fast_boot {
+        println!("Loading {}", filename.to_string_lossy());
+    }
 
-    let mut buf = [0u8;65536];
-    let size = match file.read(&mut buf) {
-        Ok(size) => size,
+    let data = match fs::read(&filename) {
+        Ok(data) => data,
         Err(error) => {
             println!("Error reading the file '{}': {}",
                 filename.to_string_lossy(), error);
-            return false;
+            return None;
         }
     };
 
     // Load the code in memory
-    for i in 0..size {
-        machine.poke(image.address + i as u16, buf[i]);
+    let name = filename.to_string_lossy();
+    let start = image.pc.unwrap_or(image.address);
+    let result = if fast_boot {
+        load_data(machine, &name, image.address as u32, &data)
+    } else {
+        check_fit(&name, image.address as u32, data.len())
+            .map(|_| bootstrap(cpu, machine, image.address, start, &data))
+    };
+    if let Err(message) = result {
+        println!("{}", message);
+        return None;
+    }
+
+    // Extra files of the user images
+    for load in image.loads.iter() {
+        if let Err(message) = load_file(machine, load) {
+            println!("{}", message);
+            return None;
+        }
     }
 
     machine.int_rx = image.int_rx;
     machine.int_sys_tick = image.int_sys_tick;
 
-    true
+    if fast_boot {
+        Some(start)
+    } else {
+        Some(0x0000)
+    }
+}
+
+// Loads a file given with --load, returns the start address
+pub fn load_file(machine: &mut Mbc2Machine, load: &LoadFile) -> Result<u16, String> {
+    println!("Loading {}", load.file);
+    let extension = Path::new(&load.file).extension()
+        .map(|e| e.to_string_lossy().to_lowercase());
+    machine.set_bank(load.bank);
+
+    if extension.as_deref() == Some("hex") {
+        let text = fs::read_to_string(&load.file)
+            .map_err(|e| format!("Error reading the file '{}': {}", load.file, e))?;
+        let hex = ihex::parse(&text)
+            .map_err(|e| format!("Error in the Intel HEX file '{}': {}", load.file, e))?;
+        // The address moves the whole file
+        let offset = load.address.unwrap_or(0) as u32;
+        for (address, data) in hex.blocks.iter() {
+            load_data(machine, &load.file, address + offset, data)?;
+        }
+        let start = match (load.address, hex.start, hex.blocks.first()) {
+            (None, Some(start), _) => start,
+            (_, _, Some((address, _))) => address + offset,
+            _ => offset,
+        };
+        if start > 0xffff {
+            return Err(format!("Start address 0x{:x} of '{}' is out of memory", start, load.file));
+        }
+        Ok(start as u16)
+    } else {
+        let data = fs::read(&load.file)
+            .map_err(|e| format!("Error reading the file '{}': {}", load.file, e))?;
+        let address = match load.address {
+            Some(address) => address,
+            None if extension.as_deref() == Some("com") => COM_ADDRESS,
+            None => 0x0000,
+        };
+        load_data(machine, &load.file, address as u32, &data)?;
+        Ok(address)
+    }
+}
+
+fn check_fit(name: &str, address: u32, size: usize) -> Result<(), String> {
+    let end = address + size as u32;
+    if end > 0x1_0000 {
+        return Err(format!("'{}' does not fit in memory: {} bytes at 0x{:04x} end at 0x{:x}",
+            name, size, address, end - 1));
+    }
+    Ok(())
+}
+
+fn load_data(machine: &mut Mbc2Machine, name: &str, address: u32, data: &[u8]) -> Result<(), String> {
+    check_fit(name, address, data.len())?;
+    for (i, value) in data.iter().enumerate() {
+        machine.poke((address + i as u32) as u16, *value);
+    }
+    Ok(())
 }
@@ -0,0 +1,208 @@
+use std::collections::VecDeque;
+use std::fs;
+use std::io::{BufWriter, Write};
+use std::time::Instant;
+
+use super::firmware::{Firmware, IosOp, DEFAULT_FIRMWARE};
+
+// Trace of the IOS opcodes to a file, one line per operation with all the
+// bytes transferred after the opcode is stored. The format is CSV for
+// files ending with .csv and JSON lines otherwise:
+//
+//    {"time": 0.012345, "cycles": 123456, "pc": "0105", "opcode": "86", "name": "READSECT",
+//     "dir": "in", "bytes": 512, "data": "C3 5C D3 ...", "sum": "8A1F", "error": "Ok"}
+//
+// The pc is the address of the first IN or OUT of the data. The transfers
+// longer than 16 bytes are summarized with the first bytes and a sum.
+// The filter is a list of opcode names or numbers, the opcodes starting
+// with '-' are excluded: "READSECT,WRITESECT" or "-SERIALTX,-SYSFLAGS".
+//
+// The last operations are kept for the crash report, without the console
+// output and the polling of the flags.
+
+const DATA_SHOWN: usize = 16;
+const HISTORY_SIZE: usize = 32;
+const NOT_IN_HISTORY: [IosOp; 2] = [IosOp::SerialTx, IosOp::SysFlags];
+
+struct Operation {
+    time: f64,
+    cycles: u64,
+    pc: u16,
+    opcode: u8,
+    in_history: bool,
+    write: bool,
+    data: Vec<u8>,
+}
+
+pub struct IosTracer {
+    firmware: Firmware, // For the names of the opcodes
+    file: Option<BufWriter<fs::File>>,
+    csv: bool,
+    enabled: [bool; 256],
+    started: Instant,
+    current: Option<Operation>,
+    history: VecDeque<String>,
+}
+
+impl IosTracer {
+    pub fn new() -> IosTracer {
+        IosTracer {
+            firmware: DEFAULT_FIRMWARE,
+            file: None,
+            csv: false,
+            enabled: [false; 256],
+            started: Instant::now(),
+            current: None,
+            history: VecDeque::new(),
+        }
+    }
+
+    pub fn set_firmware(&mut self, firmware: Firmware) {
+        self.firmware = firmware;
+    }
+
+    pub fn open(&mut self, filename: &str, filter: Option<&str>) -> Result<(), String> {
+        self.enabled = match filter {
+            Some(filter) => parse_filter(filter, self.firmware)?,
+            None => [true; 256],
+        };
+        let file = fs::File::create(filename)
+            .map_err(|e| format!("Error creating the trace file '{}': {}", filename, e))?;
+        self.file = Some(BufWriter::new(file));
+        self.csv = filename.to_lowercase().ends_with(".csv");
+        if self.csv {
+            self.write("time,cycles,pc,opcode,name,dir,bytes,data,sum,error\n");
+        }
+        Ok(())
+    }
+
+    // The last operations, the oldest first
+    pub fn history(&self) -> impl Iterator<Item = &String> {
+        self.history.iter()
+    }
+
+    // A byte transferred by the opcode on port 0, op is the opcode on the
+    // firmware
+    pub fn data(&mut self, opcode: u8, op: Option<IosOp>, value: u8, write: bool, pc: u16, cycles: u64) {
+        let in_history = !op.is_some_and(|op| NOT_IN_HISTORY.contains(&op));
+        if !self.enabled[opcode as usize] && !in_history {
+            return;
+        }
+        if self.current.as_ref().is_some_and(|op| op.opcode != opcode) {
+            self.end(None);
+        }
+        let started = self.started;
+        let operation = self.current.get_or_insert_with(|| Operation {
+            time: started.elapsed().as_secs_f64(),
+            cycles,
+            pc,
+            opcode,
+            in_history,
+            write,
+            data: Vec::new(),
+        });
+        operation.data.push(value);
+    }
+
+    // The operation is completed, with the error code if any
+    pub fn end(&mut self, error: Option<String>) {
+        let op = match self.current.take() {
+            Some(op) => op,
+            None => return,
+        };
+
+        let shown = op.data.iter().take(DATA_SHOWN)
+            .map(|b| format!("{:02X}", b)).collect::<Vec<String>>().join(" ");
+        let (data, sum) = if op.data.len() > DATA_SHOWN {
+            let sum = op.data.iter().fold(0u16, |sum, b| sum.wrapping_add(*b as u16));
+            (format!("{} ...", shown), format!("{:04X}", sum))
+        } else {
+            (shown, String::new())
+        };
+        let dir = if op.write { "out" } else { "in" };
+        let error = error.unwrap_or_default();
+
+        if op.in_history {
+            if self.history.len() >= HISTORY_SIZE {
+                self.history.pop_front();
+            }
+            let mut line = format!("{:04X} {:<10} {:<3} {:3} {}", op.pc, self.firmware.opcode_name(op.opcode), dir, op.data.len(), data);
+            for extra in [&sum, &error].iter().filter(|t| !t.is_empty()) {
+                line += &format!(" {}", extra);
+            }
+            self.history.push_back(line);
+        }
+        if self.file.is_none() || !self.enabled[op.opcode as usize] {
+            return;
+        }
+
+        let line = if self.csv {
+            format!("{:.6},{},{:04X},{:02X},{},{},{},{},{},{}\n",
+                op.time, op.cycles, op.pc, op.opcode, self.firmware.opcode_name(op.opcode),
+                dir, op.data.len(), data, sum, error)
+        } else {
+            format!(concat!("{{\"time\": {:.6}, \"cycles\": {}, \"pc\": \"{:04X}\", ",
+                "\"opcode\": \"{:02X}\", \"name\": \"{}\", \"dir\": \"{}\", \"bytes\": {}, ",
+                "\"data\": \"{}\", \"sum\": \"{}\", \"error\": \"{}\"}}\n"),
+                op.time, op.cycles, op.pc, op.opcode, self.firmware.opcode_name(op.opcode),
+                dir, op.data.len(), data, sum, error)
+        };
+        self.write(&line);
+    }
+
+    fn write(&mut self, text: &str) {
+        // Errors writing the trace are ignored
+        if let Some(file) = self.file.as_mut() {
+            let _ = file.write_all(text.as_bytes());
+        }
+    }
+}
+
+impl Drop for IosTracer {
+    fn drop(&mut self) {
+        self.end(None);
+        if let Some(file) = self.file.as_mut() {
+            let _ = file.flush();
+        }
+    }
+}
+
+fn parse_filter(filter: &str, firmware: Firmware) -> Result<[bool; 256], String> {
+    let items: Vec<&str> = filter.split(',').map(|i| i.trim()).filter(|i| !i.is_empty()).collect();
+    // Only exclusions trace everything else
+    let mut enabled = [items.iter().all(|i| i.starts_with('-')); 256];
+    for item in items {
+        let (name, enable) = match item.strip_prefix('-') {
+            Some(name) => (name, false),
+            None => (item, true),
+        };
+        let opcode = firmware.opcode_from_name(name)
+            .ok_or(format!("unknown IOS opcode '{}' in the trace filter", name))?;
+        enabled[opcode as usize] = enable;
+    }
+    Ok(enabled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn history_without_the_console_and_the_flags() {
+        let mut tracer = IosTracer::new();
+        tracer.data(0x01, Some(IosOp::SerialTx), b'A', true, 0x100, 0);
+        tracer.end(None);
+        tracer.data(0x83, Some(IosOp::SysFlags), 0, false, 0x104, 0);
+        tracer.end(None);
+        tracer.data(0x86, Some(IosOp::ReadSect), 0xc3, false, 0x108, 0);
+        tracer.end(Some("Ok".to_string()));
+        // By the operation, not by the opcode number
+        tracer.data(0x83, None, 0xff, false, 0x10c, 0);
+        tracer.end(None);
+        let history: Vec<&String> = tracer.history().collect();
+        assert_eq!(history.len(), 2);
+        assert!(history[0].starts_with("0108 READSECT"));
+        assert!(history[0].ends_with("C3 Ok"));
+        assert!(history[1].starts_with("010C"));
+    }
+}
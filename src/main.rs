@@ -1,65 +1,380 @@
+use std::env;
+use std::panic;
+use std::path::Path;
+use std::process;
+
 use iz80::*;
 
+mod bdos;
+mod bootstrap;
+mod config;
+mod console;
+mod convert;
+mod cpm_trace;
+mod crash_report;
+mod cpmfs;
+mod disk_stats;
+mod exec_trace;
+mod faults;
 mod filesystem;
+mod firmware;
+mod hostfiles;
+mod ihex;
+mod iload;
+mod ios_trace;
 mod images;
 mod mbc2_machine;
+mod modem;
+mod monitor;
+mod options;
+mod run;
+mod serial;
+mod timing;
+mod unimplemented;
 
 #[cfg(windows)]
 mod console_windows;
 #[cfg(unix)]
 mod console_unix;
+#[cfg(unix)]
+mod console_pty;
 
 use self::mbc2_machine::Mbc2Machine;
+use self::cpm_trace::CpmTracer;
+use self::crash_report::{install_panic_hook, CrashRecorder};
+use self::config::{Config, DEFAULT_CONFIG_FILE};
+use self::exec_trace::ExecTracer;
+use self::faults::Faults;
+use self::iload::iload;
 use self::images::*;
+use self::monitor::monitor;
+use self::options::*;
+use self::timing::EmulatedClock;
+use self::unimplemented::Unimplemented;
 
 // Welcome message
-const WELCOME: &'static str =
+const WELCOME: &str =
 "z80-mbc2-emu https://github.com/ivanizag/iz-cpm
 Emulation of the Z80-MBC2, https://hackaday.io/project/159973
 
 Press ctrl-c to return to host";
 
+// Longest wait for input when the guest is idle, the ticks and the timeout
+// are checked after it
+const IDLE_WAIT: std::time::Duration = std::time::Duration::from_millis(10);
 
 fn main() {
-    let image = select_image();
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.first().map(|a| a.as_str()) == Some("convert") {
+        if let Err(message) = convert::convert(&args[1..]) {
+            println!("{}", message);
+            process::exit(1);
+        }
+        return;
+    }
+
+    if args.first().map(|a| a.as_str()) == Some("bdos") {
+        match bdos::bdos_command(&args[1..]) {
+            Ok(exit_code) => process::exit(exit_code),
+            Err(message) => {
+                println!("{}", message);
+                process::exit(1);
+            }
+        }
+    }
+
+    let run = args.first().map(|a| a.as_str()) == Some("run");
+    let options_args = if run { &args[1..] } else { &args[..] };
+    let mut options = match parse_options(options_args, run) {
+        Ok(options) => options,
+        Err(message) => {
+            if !message.is_empty() {
+                println!("{}.", message);
+            }
+            // Show the user images of the default config file too
+            let config = Config::load(DEFAULT_CONFIG_FILE, false).unwrap_or_else(|_| Config::new());
+            usage(&all_images(config.images));
+            process::exit(1);
+        }
+    };
+    let mut config = match Config::load(&options.config_file, options.config_required) {
+        Ok(config) => config,
+        Err(message) => {
+            println!("{}", message);
+            process::exit(1);
+        }
+    };
+    let images = all_images(std::mem::take(&mut config.images));
+    let image = options.image.as_ref().map(|id| select_image(&images, id));
+    if let Some(image) = image {
+        if !options.firmware.supports_image(&image.id) {
+            println!("The image '{}' is not supported by the IOS firmware {}", image.id, options.firmware.name());
+            process::exit(1);
+        }
+    }
 
     // Init device
     let mut machine = Mbc2Machine::new();
+    machine.set_firmware(options.firmware);
+    if options.pty {
+        #[cfg(unix)]
+        match console_pty::PtyConsole::open(options.pty_link.as_deref()) {
+            Ok(console) => machine.set_console(Box::new(console)),
+            Err(message) => {
+                drop(machine); // Restore the console
+                println!("{}", message);
+                process::exit(1);
+            }
+        }
+        #[cfg(not(unix))]
+        {
+            drop(machine); // Restore the console
+            println!("The pty console is only available on Unix");
+            process::exit(1);
+        }
+    }
+    if options.modem {
+        let listener = match options.modem_listen.as_deref().map(modem::listen) {
+            Some(Err(message)) => {
+                drop(machine); // Restore the console
+                println!("{}", message);
+                process::exit(1);
+            },
+            Some(Ok(listener)) => Some(listener),
+            None => None,
+        };
+        machine.wrap_console(|con| Box::new(modem::ModemConsole::new(con, listener)));
+    }
     let mut cpu = Cpu::new_z80();
 
-    // Load the image
-    if !load_image(&mut machine, &image) {
-        return;
+    // Load the image and the files
+    let mut pc = 0;
+    let mut label = "loaded files";
+    let mut disk_set = 0xff;
+    if let Some(image) = image {
+        pc = match load_image(&mut machine, &mut cpu, image, options.fast_boot) {
+            Some(start) => start,
+            None => return,
+        };
+        label = &image.id;
+        disk_set = image.disk_set;
     }
+    for (i, load) in options.loads.iter().enumerate() {
+        match load_file(&mut machine, load) {
+            Ok(start) => if image.is_none() && i == 0 {
+                pc = start;
+            },
+            Err(message) => {
+                println!("{}", message);
+                process::exit(1);
+            }
+        }
+    }
+    machine.set_bank(options.bank.unwrap_or(0));
 
-    machine.set_disk_set(image.disk_set);
-    cpu.registers().set_pc(image.address);
+    machine.set_disk_set(disk_set);
+    let geometry = config.geometry(disk_set);
+    machine.set_disk_geometry(geometry);
+    machine.set_disk_lock_mode(options.lock_mode);
+    machine.disk_stats().set_label(label, disk_set);
+    config.fault_rules.extend(options.fault_rules.iter().cloned());
+    let faults = Faults::new(std::mem::take(&mut config.fault_rules));
+    if let Err(message) = faults.check_sector_size(geometry.sector_size) {
+        println!("{}", message);
+        process::exit(1);
+    }
+    machine.set_disk_faults(faults);
+    if let Some(host_dir) = options.host_dir.as_ref() {
+        if !Path::new(host_dir).is_dir() {
+            println!("The host folder '{}' does not exist.", host_dir);
+            process::exit(1);
+        }
+        machine.enable_host_files(host_dir);
+    }
+    machine.set_unimplemented(std::mem::replace(&mut options.unimplemented, Unimplemented::new()));
+    if let Some(filename) = options.printer.as_ref() {
+        if let Err(message) = machine.set_printer(filename) {
+            println!("{}", message);
+            process::exit(1);
+        }
+    }
+    machine.set_clock(config.timing.clock_mhz);
+    machine.set_idle_detection(options.idle_detection);
+    machine.set_monitor_key(options.monitor_key);
+    if options.timing {
+        machine.set_timing(config.timing.clone());
+    }
+    if options.serial {
+        machine.set_serial(config.serial.clone(), config.timing.clock_mhz);
+    }
+    cpu.registers().set_pc(options.pc.unwrap_or(pc));
+    if let Some(sp) = options.sp {
+        cpu.registers().set16(Reg16::SP, sp);
+    }
     cpu.set_trace(false);
-    //machine.trace = true;
+    if let Some(filename) = options.ios_trace.as_ref() {
+        if let Err(message) = machine.open_ios_trace(filename, options.ios_trace_filter.as_deref()) {
+            println!("{}", message);
+            process::exit(1);
+        }
+    }
+
+    let mut cpm_tracer = match options.cpm_trace.as_ref().map(|f| CpmTracer::new(f)) {
+        Some(Err(message)) => {
+            println!("{}", message);
+            process::exit(1);
+        },
+        Some(Ok(tracer)) => Some(tracer),
+        None => None,
+    };
+
+    let mut exec_tracer = match options.exec_trace.as_ref() {
+        Some(filename) => match ExecTracer::new(filename, options.exec_trace_filter.as_deref()) {
+            Ok(tracer) => Some(tracer),
+            Err(message) => {
+                println!("{}", message);
+                process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    let mut deadline = None;
+    if run {
+        let image = image.expect("run requires an image");
+        if let Err(message) = run::prepare(&mut machine, &options, image, &config) {
+            println!("{}", message);
+            process::exit(1);
+        }
+        deadline = Some(std::time::Instant::now() + std::time::Duration::from_secs(options.timeout));
+    } else {
+        match options.monitor_key {
+            Some(key) => println!("{}, {} for the monitor", WELCOME, key_name(key)),
+            None => println!("{}", WELCOME),
+        }
+        println!("IOS firmware {}", machine.firmware().name());
+    }
+    if let Some(image) = image {
+        if !options.fast_boot {
+            let messages = bootstrap::boot_messages(&machine, image);
+            machine.print_host(&messages);
+        }
+    }
 
     // Start the cpu
-    println!("{}", WELCOME);
+    if image.is_some_and(|image| image.iload) {
+        match iload(&mut machine) {
+            Some(start) => cpu.registers().set_pc(options.pc.unwrap_or(start)),
+            None => {
+                drop(machine); // Restore the console
+                process::exit(1);
+            }
+        }
+    }
+    let mut crash_recorder = options.crash_report.as_ref().map(|filename| CrashRecorder::new(filename));
+    if crash_recorder.is_some() {
+        install_panic_hook();
+    }
+
     let mut ref_time = std::time::Instant::now();
-    let mut reg_count = 0 as u64;
+    let mut reg_count = 0_u64;
+    let mut emulated_clock = if options.timing {
+        Some(EmulatedClock::new(config.timing.clock_mhz, cpu.cycle_count()))
+    } else {
+        None
+    };
 
     while !machine.quit {
-        cpu.execute_instruction(&mut machine);
+        if let Some(tracer) = cpm_tracer.as_mut() {
+            tracer.step(&mut cpu, &machine);
+        }
+        let pc = cpu.registers().pc();
+        machine.set_instruction(pc, cpu.cycle_count());
+        if let Some(recorder) = crash_recorder.as_mut() {
+            recorder.record(pc, machine.bank());
+        }
+        if let Some(tracer) = exec_tracer.as_mut() {
+            tracer.before(&mut cpu, &mut machine);
+        }
+        let executed = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            cpu.execute_instruction(&mut machine);
+        }));
+        if executed.is_err() {
+            if let Some(recorder) = crash_recorder.as_ref() {
+                recorder.write("panic", &mut cpu, &mut machine);
+            }
+            drop(machine);
+            drop(cpm_tracer);
+            drop(exec_tracer);
+            process::exit(101);
+        }
+        if let Some(tracer) = exec_tracer.as_mut() {
+            tracer.after(&mut cpu);
+        }
 
         reg_count += 1;
-        if reg_count % 1000 == 0 {
+        if reg_count.is_multiple_of(1000) {
             let now = std::time::Instant::now();
-            let elapsed = now - ref_time;
-            if elapsed.as_micros() > 1000 {
-                machine.tick_ms();
-                ref_time = ref_time - std::time::Duration::from_millis(1);
+            if let Some(clock) = emulated_clock.as_mut() {
+                for _ in 0..clock.sync(cpu.cycle_count() + machine.wait_cycles) {
+                    machine.tick_ms();
+                }
+            } else {
+                let elapsed = now - ref_time;
+                if elapsed.as_micros() > 1000 {
+                    machine.tick_ms();
+                    ref_time -= std::time::Duration::from_millis(1);
+                }
+            }
+            if deadline.is_some_and(|deadline| now > deadline) {
+                println!("\n<<timeout after {} seconds>>", options.timeout);
+                drop(machine);
+                drop(cpm_tracer);
+                drop(exec_tracer);
+                process::exit(run::EXIT_TIMEOUT);
             }
         }
 
+        if machine.is_idle() {
+            machine.wait_input(IDLE_WAIT);
+        }
+        machine.update_rx_interrupt();
         cpu.signal_interrupt(machine.int_raised);
 
         if cpu.is_halted() {
             println!("HALT instruction");
+            machine.crash = Some("HALT instruction".to_string());
             break;
         }
+
+        if machine.monitor_requested {
+            monitor(&mut machine);
+        }
+    }
+
+    if let (Some(reason), Some(recorder)) = (machine.crash.take(), crash_recorder.as_ref()) {
+        recorder.write(&reason, &mut cpu, &mut machine);
+    }
+
+    if machine.serial_overruns() > 0 {
+        println!("Serial RX overruns: {} chars lost", machine.serial_overruns());
+    }
+    if !machine.unimplemented().is_empty() {
+        print!("{}", machine.unimplemented().summary());
+    }
+    if options.disk_stats {
+        print!("{}", machine.disk_stats().to_text());
+    }
+    if let Some(filename) = options.disk_stats_json {
+        machine.disk_stats().write_json(&filename);
+    }
+
+    if run {
+        // HALT or an error of the emulation
+        let exit_code = machine.console_exit_code().unwrap_or(1);
+        drop(machine);
+        drop(cpm_tracer);
+        drop(exec_tracer);
+        process::exit(exit_code);
     }
 }
@@ -1,18 +1,42 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::time::{Duration, Instant};
+
 use chrono::{DateTime, Local, Datelike, Timelike};
 
 use iz80::Machine;
 
-use super::filesystem::FileSystem;
-
+use super::disk_stats::DiskStats;
+use super::faults::Faults;
+use super::filesystem::{DiskGeometry, FileSystem, LockMode};
+use super::firmware::{Firmware, IosOp, DEFAULT_FIRMWARE};
+use super::hostfiles::{HostFiles, RECORD_SIZE};
+use super::ios_trace::IosTracer;
+use super::serial::Serial;
+use super::timing::{Timing, DEFAULT_CLOCK_MHZ};
+use super::unimplemented::{Policy, Unimplemented};
+
+use super::console::{Console, NullConsole};
 #[cfg(windows)]
-use super::console_windows::Console;
+use super::console_windows::TerminalConsole;
 #[cfg(unix)]
-use super::console_unix::Console;
+use super::console_unix::TerminalConsole;
 
 const RAM_SIZE: usize = 128*1024;
 
 const OPCODE_NOP: u8 = 0xff;
 
+pub const DEFAULT_MONITOR_KEY: u8 = 0x1d; // Control ]
+
+// Temperature of the DS3231 of the emulated RTC, in Celsius
+pub const RTC_TEMPERATURE: u8 = 21;
+
+// The guest is idle after these polls of the console with no input and no
+// other I/O, each one within the window of T-states of the first one
+const IDLE_POLLS: u32 = 64;
+const IDLE_WINDOW: u64 = 32000;
+
 const INT_RX_MASK: u8 = 1;
 const INT_SYS_TICK_MASK: u8 = 2;
 
@@ -21,15 +45,22 @@ pub struct Mbc2Machine {
     disk_set: u8,
 
     bank: u8,
+    firmware: Firmware,
+    opcodes: [Option<IosOp>; 256],
     opcode: u8,
     last_rx_is_empty: bool,
+    autoexec: bool, // Set on the boot menu of IOS, not emulated
     io_byte_count: u32,
     track_sel_lo: u8,
     last_time: DateTime<Local>,
     pub quit: bool,
+    pub monitor_requested: bool,
+    monitor_key: Option<u8>,
+    pub crash: Option<String>,
 
-    con: Console,
+    con: Box<dyn Console>,
     fs: FileSystem, 
+    host: Option<HostFiles>,
 
     user_led: bool,
     gpio_a: u8,
@@ -49,25 +80,50 @@ pub struct Mbc2Machine {
     cpm_warm_boot: bool,
     spp: bool,
     spp_fd: bool,
-
-    pub trace: bool,
+    printer: Option<fs::File>,
+
+    ios_trace: IosTracer,
+    unimplemented: Unimplemented,
+    pc: u16,
+    cycles: u64,
+
+    timing: Option<Timing>,
+    op_bytes: u32,
+    pub wait_cycles: u64,
+    serial: Option<Serial>,
+
+    clock_mhz: u64,
+    idle_detection: bool,
+    idle_polls: u32,
+    idle_start: u64,
 }
 
 impl Mbc2Machine {
     pub fn new() -> Mbc2Machine {
+        Mbc2Machine::with_console(Box::new(TerminalConsole::new()))
+    }
+
+    pub fn with_console(con: Box<dyn Console>) -> Mbc2Machine {
         Mbc2Machine {
             mem: [0; RAM_SIZE],
             disk_set: 0xff,
             bank: 0,
+            firmware: DEFAULT_FIRMWARE,
+            opcodes: DEFAULT_FIRMWARE.opcode_table(),
             opcode: OPCODE_NOP,
             last_rx_is_empty: false,
+            autoexec: false,
             io_byte_count: 0,
             track_sel_lo: 0,
             last_time: Local::now(),
             quit: false,
+            monitor_requested: false,
+            monitor_key: Some(DEFAULT_MONITOR_KEY),
+            crash: None,
 
-            con: Console::new(),
+            con,
             fs: FileSystem::new(),
+            host: None,
 
             user_led: false,
             gpio_a: 0,
@@ -87,24 +143,227 @@ impl Mbc2Machine {
             cpm_warm_boot: false,
             spp: false,
             spp_fd: false,
-        
-            trace: false,
+            printer: None,
+
+            ios_trace: IosTracer::new(),
+            unimplemented: Unimplemented::new(),
+            pc: 0,
+            cycles: 0,
+
+            timing: None,
+            op_bytes: 0,
+            wait_cycles: 0,
+            serial: None,
+
+            clock_mhz: DEFAULT_CLOCK_MHZ,
+            idle_detection: false,
+            idle_polls: 0,
+            idle_start: 0,
         }
     }
 
+    // File for the output of the printer on the SPP Adapter
+    pub fn set_printer(&mut self, filename: &str) -> Result<(), String> {
+        let file = fs::OpenOptions::new().create(true).append(true).open(filename)
+            .map_err(|e| format!("Error opening the printer file '{}': {}", filename, e))?;
+        self.printer = Some(file);
+        Ok(())
+    }
+
     pub fn set_disk_set(&mut self, disk_set: u8) {
         self.disk_set = disk_set;
     }
 
-    fn decode_address(&self, address: u16) -> usize {
+    pub fn set_firmware(&mut self, firmware: Firmware) {
+        self.firmware = firmware;
+        self.opcodes = firmware.opcode_table();
+        self.ios_trace.set_firmware(firmware);
+        self.unimplemented.set_firmware(firmware);
+    }
+
+    pub fn firmware(&self) -> Firmware {
+        self.firmware
+    }
+
+    pub fn set_timing(&mut self, timing: Timing) {
+        self.timing = Some(timing);
+    }
+
+    pub fn set_clock(&mut self, clock_mhz: u64) {
+        self.clock_mhz = clock_mhz;
+    }
+
+    pub fn clock_mhz(&self) -> u64 {
+        self.clock_mhz
+    }
+
+    pub fn autoexec(&self) -> bool {
+        self.autoexec
+    }
+
+    // The key to open the monitor, None to give all the keys to the guest
+    pub fn set_monitor_key(&mut self, key: Option<u8>) {
+        self.monitor_key = key;
+    }
+
+    pub fn set_idle_detection(&mut self, enabled: bool) {
+        self.idle_detection = enabled;
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.idle_polls >= IDLE_POLLS
+    }
+
+    // Blocks while the guest only polls the console, up to the timeout. The
+    // emulated clock goes on as if the guest kept polling.
+    pub fn wait_input(&mut self, timeout: Duration) {
+        let start = Instant::now();
+        self.con.wait(timeout);
+        self.wait_cycles += start.elapsed().as_micros() as u64 * self.clock_mhz;
+        self.idle_polls = 0;
+    }
+
+    pub fn set_serial(&mut self, mut serial: Serial, clock_mhz: u64) {
+        serial.start(clock_mhz);
+        self.serial = Some(serial);
+    }
+
+    pub fn serial_overruns(&self) -> u32 {
+        self.serial.as_ref().map_or(0, |serial| serial.overruns)
+    }
+
+    fn ios_op(&self) -> Option<IosOp> {
+        self.opcodes[self.opcode as usize]
+    }
+
+    pub fn set_console(&mut self, con: Box<dyn Console>) {
+        self.con = con;
+    }
+
+    // Replaces the console with one using the current one
+    pub fn wrap_console<F>(&mut self, wrap: F)
+            where F: FnOnce(Box<dyn Console>) -> Box<dyn Console> {
+        let con = std::mem::replace(&mut self.con, Box::new(NullConsole));
+        self.con = wrap(con);
+    }
+
+    pub fn console(&mut self) -> &mut dyn Console {
+        self.con.as_mut()
+    }
+
+    pub fn console_exit_code(&self) -> Option<i32> {
+        self.con.exit_code()
+    }
+
+    pub fn add_virtual_disk(&mut self, disk_number: u8, name: &str,
+            file: Option<fs::File>, overlay: HashMap<u64, Vec<u8>>) {
+        self.fs.add_virtual_disk(disk_number, name, file, overlay);
+    }
+
+    pub fn open_ios_trace(&mut self, filename: &str, filter: Option<&str>) -> Result<(), String> {
+        self.ios_trace.open(filename, filter)
+    }
+
+    pub fn ios_history(&mut self) -> impl Iterator<Item = &String> {
+        // Including the operation in progress
+        self.end_ios_trace(self.opcode);
+        self.ios_trace.history()
+    }
+
+    pub fn set_unimplemented(&mut self, mut unimplemented: Unimplemented) {
+        unimplemented.set_firmware(self.firmware);
+        self.unimplemented = unimplemented;
+    }
+
+    pub fn unimplemented(&self) -> &Unimplemented {
+        &self.unimplemented
+    }
+
+    // Position of the instruction being executed, for the IOS trace
+    pub fn set_instruction(&mut self, pc: u16, cycles: u64) {
+        self.pc = pc;
+        self.cycles = cycles;
+    }
+
+    pub fn set_bank(&mut self, bank: u8) {
+        self.bank = bank;
+    }
+
+    pub fn bank(&self) -> u8 {
+        self.bank
+    }
+
+    pub fn set_disk_geometry(&mut self, geometry: DiskGeometry) {
+        self.fs.set_geometry(geometry);
+    }
+
+    pub fn set_disk_lock_mode(&mut self, lock_mode: LockMode) {
+        self.fs.set_lock_mode(lock_mode);
+    }
+
+    pub fn set_disk_faults(&mut self, faults: Faults) {
+        self.fs.set_faults(faults);
+    }
+
+    pub fn disk_stats(&mut self) -> &mut DiskStats {
+        &mut self.fs.stats
+    }
+
+    // Output to the console for the host monitor
+    pub fn print_host(&mut self, text: &str) {
+        for ch in text.bytes() {
+            self.con.put(ch);
+        }
+    }
+
+    // Line input from the console for the host monitor
+    pub fn read_host_line(&mut self, prompt: &str) -> String {
+        self.read_line(prompt, usize::MAX).unwrap_or_default()
+    }
+
+    // Line input up to max chars, None on control-c
+    pub fn read_line(&mut self, prompt: &str, max: usize) -> Option<String> {
+        for ch in prompt.bytes() {
+            self.con.put(ch);
+        }
+
+        let mut line = Some(String::new());
+        while let Some(text) = line.as_mut() {
+            let ch = self.con.read();
+            match ch {
+                13 | 10 => break,
+                3 => line = None, // Control C
+                8 | 127 if !text.is_empty() => {
+                    text.pop();
+                    self.con.put(8);
+                    self.con.put(b' ');
+                    self.con.put(8);
+                },
+                32..=126 if text.len() < max => {
+                    text.push(ch as char);
+                    self.con.put(ch);
+                },
+                _ => (),
+            }
+        }
+        self.con.put(13);
+        self.con.put(10);
+        line
+    }
+
+    pub fn enable_host_files(&mut self, host_dir: &str) {
+        self.host = Some(HostFiles::new(host_dir));
+    }
+
+    pub fn decode_address(&self, address: u16) -> usize {
         let a15 = (address & 0x8000) != 0;
         let base = (address & 0x7fff) as usize;
         if a15 {
             // Upper addresses, fixed from 0x0_8000 to 0x0_FFFF
-            return address as usize
+            address as usize
         } else {
             // Lower addresses
-            return match self.bank {
+            match self.bank {
                 0 => base, //from 0x0_0000 to 0x0_7FFF
                 1 => base + 0x1_0000, //from 0x1_0000 to 0x1_7FFF
                 2 => base + 0x1_8000, //from 0x1_8000 to 0x1_FFFF
@@ -117,14 +376,29 @@ impl Mbc2Machine {
         if self.int_sys_tick {
             // TODO
         }
-        if self.int_rx && self.con.status() && self.rx_done {
-            self.int_status = self.int_status | INT_RX_MASK;
+    }
+
+    // Raises the RX interrupt as soon as a char is available
+    pub fn update_rx_interrupt(&mut self) {
+        if self.int_rx && self.rx_done && self.rx_available() {
+            self.int_status |= INT_RX_MASK;
             self.int_raised = true;
             self.rx_done = false;
         }
     }
 }
 
+impl Drop for Mbc2Machine {
+    fn drop(&mut self) {
+        // Send the rest of the TX buffer
+        if let Some(serial) = self.serial.as_mut() {
+            for ch in serial.flush_tx() {
+                self.con.put(ch);
+            }
+        }
+    }
+}
+
 impl Machine for Mbc2Machine {
     fn peek(&self, address: u16) -> u8 {
         let ram_address = self.decode_address(address);
@@ -145,21 +419,38 @@ impl Machine for Mbc2Machine {
         let a0 = (address & 1) == 1;
         if a0 {
             // Store opcode
+            self.end_ios_trace(self.opcode);
             self.opcode = value;
             self.io_byte_count = 0;
+            self.op_bytes = 0;
+            if let Some(timing) = self.timing.as_ref() {
+                self.wait_cycles += timing.opcode as u64;
+            }
         } else {
+            let opcode = self.opcode;
+            self.charge_data();
+            self.idle_polls = 0;
             let mut implemented = true;
-            match self.opcode {
-                0x00 => self.user_led = value & 1 != 0, // USER LED
-                0x01 => self.con.put(value), // SERIAL TX
-                0x03 => self.gpio_a = value, // GPIOA WRITE
-                0x04 => self.gpio_b = value, // GPIOB WRITE
-                0x05 => self.io_dir_a = value, // IODIRA WRITE
-                0x06 => self.io_dir_b = value, // IODIRB WRITE
-                0x07 => self.ggpu_a = value, // GGPUAA WRITE
-                0x08 => self.ggpu_b = value, // GGPUAB WRITE
-                0x09 => self.fs.select_disk(self.disk_set, value), // SELDISK
-                0x0a => { // SELTRACK
+            match self.ios_op() {
+                Some(IosOp::UserLed) => self.user_led = value & 1 != 0, // USER LED
+                Some(IosOp::SerialTx) => { // SERIAL TX
+                    if self.serial.is_some() {
+                        self.serial_write(value);
+                    } else {
+                        self.con.put(value);
+                    }
+                    if self.con.exit_code().is_some() {
+                        self.quit = true;
+                    }
+                },
+                Some(IosOp::GpioAWrite) => self.gpio_a = value, // GPIOA WRITE
+                Some(IosOp::GpioBWrite) => self.gpio_b = value, // GPIOB WRITE
+                Some(IosOp::IoDirAWrite) => self.io_dir_a = value, // IODIRA WRITE
+                Some(IosOp::IoDirBWrite) => self.io_dir_b = value, // IODIRB WRITE
+                Some(IosOp::GppuAWrite) => self.ggpu_a = value, // GGPUAA WRITE
+                Some(IosOp::GppuBWrite) => self.ggpu_b = value, // GGPUAB WRITE
+                Some(IosOp::SelDisk) => self.fs.select_disk(self.disk_set, value), // SELDISK
+                Some(IosOp::SelTrack) => { // SELTRACK
                     if self.io_byte_count == 0 {
                         self.track_sel_lo = value;
                         self.io_byte_count += 1
@@ -169,24 +460,24 @@ impl Machine for Mbc2Machine {
                         self.opcode = OPCODE_NOP;
                     }
                 }
-                0x0b => self.fs.select_sector(value), // SELSECT
-                0x0c => { // WRITESECT
+                Some(IosOp::SelSect) => self.fs.select_sector(value), // SELSECT
+                Some(IosOp::WriteSect) => { // WRITESECT
                     if self.io_byte_count == 0 {
                         self.fs.seek();
                     }
 
                     self.fs.write(value);
                     self.io_byte_count += 1;
-                    if self.io_byte_count >= 512 {
+                    if self.io_byte_count >= self.fs.sector_size() {
                         self.opcode = OPCODE_NOP;
                     }
                 }
-                0x0d => { // SETBANK
+                Some(IosOp::SetBank) => { // SETBANK
                     if value <= 2 {
                         self.bank = value
                     }
                 },
-                0x0e => { // SETIRQ
+                Some(IosOp::SetIrq) => { // SETIRQ
                     //   I/O DATA:    D7 D6 D5 D4 D3 D2 D1 D0
                     //               ---------------------------------------------------------
                     //                 X  X  X  X  X  X  X  0    Serial Rx IRQ not enabled
@@ -196,41 +487,45 @@ impl Machine for Mbc2Machine {
                     self.int_rx = value & INT_RX_MASK != 0;
                     self.int_sys_tick = value & INT_SYS_TICK_MASK != 0;
                 },
-                0x0f => { // SETTICK
+                Some(IosOp::SetTick) => { // SETTICK
                     if value > 0 {
                         self.sys_tick_time = value;
                     }
                 },
-                0x10 => { // SETOPT
+                Some(IosOp::SetOpt) => { // SETOPT
                     self.cpm_warm_boot = value & 1 != 0;
                 },
-                0x11 => { // SETSPP
+                Some(IosOp::SetSpp) => { // SETSPP
                     self.spp = true;
                     self.spp_fd = value & 1 != 0;
                 },
-                //0x12 => { // WRSPP
-                //    // Todo: write value to a printer.out file.
-                //},
+                Some(IosOp::WrSpp) => { // WRSPP
+                    // Ignored until SETSPP as on IOS. The printer is always
+                    // ready, the output is lost without a printer file.
+                    if self.spp {
+                        if let Some(printer) = self.printer.as_mut() {
+                            if let Err(e) = printer.write_all(&[value]) {
+                                println!("<<Error writing the printer file: {}>>", e);
+                                self.printer = None;
+                            }
+                        }
+                    }
+                },
+                Some(IosOp::HostName) | Some(IosOp::HostOpen) | Some(IosOp::HostWrite)
+                    | Some(IosOp::HostClose) => implemented = self.host_port_out(value),
                 _ => implemented = false,
             }
 
             if !implemented {
-                println!("<<{} not implemented>>",
-                    opcode_name(self.opcode));
-                self.quit = true;
-            } else if self.trace
-                    && self.opcode != OPCODE_NOP
-                    && self.opcode != 0x01
-                    && self.opcode != 0x0d
-                    && (self.opcode != 0x0c || self.io_byte_count == 1) {
-                println!("<<{}({:02x}) -> {}>>",
-                    opcode_name(self.opcode), value, self.fs.get_last_error());
+                self.not_implemented();
             }
 
-            if self.opcode != 0x0a && self.opcode != 0x0c {
+            let multi_byte = [IosOp::SelTrack, IosOp::WriteSect, IosOp::HostName, IosOp::HostWrite];
+            if !self.ios_op().is_some_and(|op| multi_byte.contains(&op)) {
                 // All done for the single byte opcodes
                 self.opcode = OPCODE_NOP;
             }
+            self.trace_ios(opcode, value, true);
 
         }
     }
@@ -245,32 +540,47 @@ impl Machine for Mbc2Machine {
             // NOTE 3: This is the only I/O that do not require any previous STORE OPCODE operation (for fast polling).
             // NOTE 4: A "RX buffer empty" flag and a "Last Rx char was empty" flag are available in the SYSFLAG opcode 
             //         to allow 8 bit I/O.
-            self.int_status = self.int_status & !INT_RX_MASK; // Reset the RX signal
+            if let Some(timing) = self.timing.as_ref() {
+                self.wait_cycles += timing.byte as u64;
+            }
+            self.int_status &= !INT_RX_MASK; // Reset the RX signal
             self.int_raised = false;
             self.rx_done = true;
 
-            if self.con.status() {
-                let mut ch = self.con.read();
-                if ch == 3 { // Control C
-                    self.quit = true;
-                } else if ch == 127 { // Backspace
-                    ch = 8
-                }
-                self.last_rx_is_empty = false;
-                ch
+            let received = if self.serial.is_some() {
+                self.serial_advance();
+                self.serial.as_mut().and_then(|serial| serial.read())
+            } else if self.con.status() {
+                let ch = self.con.read();
+                self.host_char(ch)
             } else {
-                // No data available
-                self.last_rx_is_empty = true;
-                0xff
+                None
+            };
+            self.console_poll(received.is_none());
+            match received {
+                Some(ch) => {
+                    self.last_rx_is_empty = false;
+                    ch
+                },
+                None => {
+                    // No data available
+                    self.last_rx_is_empty = true;
+                    0xff
+                }
             }
         } else {
             // Execute opcode
+            let opcode = self.opcode;
+            self.charge_data();
+            if self.ios_op() != Some(IosOp::SysFlags) {
+                self.idle_polls = 0;
+            }
             let mut implemented = true;
-            let value = match self.opcode {
-                0x80 => 0, /* not pressed */ // USER KEY
-                0x81 => self.gpio_a, // GPIOA READ
-                0x82 => self.gpio_b, // GPIOB READ
-                0x83 => {
+            let value = match self.ios_op() {
+                Some(IosOp::UserKey) => 0, /* not pressed */ // USER KEY
+                Some(IosOp::GpioARead) => self.gpio_a, // GPIOA READ
+                Some(IosOp::GpioBRead) => self.gpio_b, // GPIOB READ
+                Some(IosOp::SysFlags) => {
                     // SYSFLAGS (Various system flags for the OS):
                     //     I/O DATA:    D7 D6 D5 D4 D3 D2 D1 D0
                     //                 ---------------------------------------------------------
@@ -286,8 +596,10 @@ impl Machine for Mbc2Machine {
                     //                   X  X  X  1  X  X  X  X    CP/M warm boot message enabled
                     //
                     // NOTE: Currently only D0-D4 are used
-                    let mut sysflags: u8 = 0b0010;
-                    if self.con.status() {
+                    let mut sysflags: u8 = 0b0010 | self.autoexec as u8;
+                    let rx_available = self.rx_available();
+                    self.console_poll(!rx_available);
+                    if rx_available {
                         sysflags += 0b0100;
                     }
                     if self.last_rx_is_empty {
@@ -298,7 +610,7 @@ impl Machine for Mbc2Machine {
                     }
                     sysflags
                 },
-                0x84 => {
+                Some(IosOp::DateTime) => {
                     if self.io_byte_count == 0 {
                         self.last_time = Local::now();
                     }
@@ -309,7 +621,7 @@ impl Machine for Mbc2Machine {
                         3 => self.last_time.day() as u8,
                         4 => self.last_time.month() as u8,
                         5 => (self.last_time.year() % 100) as u8,
-                        6 => 21, // 21º Celsius
+                        6 => RTC_TEMPERATURE,
                         _ => 0,
                     };
                     self.io_byte_count += 1;
@@ -318,22 +630,22 @@ impl Machine for Mbc2Machine {
                     }
                     value
                 },
-                0x85 => self.fs.get_last_error(), // ERRDISK
-                0x86 => { // READSECT
+                Some(IosOp::ErrDisk) => self.fs.get_last_error(), // ERRDISK
+                Some(IosOp::ReadSect) => { // READSECT
                     if self.io_byte_count == 0 {
                         self.fs.seek();
                     }
 
                     let value = self.fs.read();
                     self.io_byte_count += 1;
-                    if self.io_byte_count >= 512 {
+                    if self.io_byte_count >= self.fs.sector_size() {
                         self.opcode = OPCODE_NOP;
                     }
                     value
                 }
-                0x87 => 0, //SDMOUNT
-                0x88 => 255, // ATXBUFF, buffers are never full
-                0x89 => { // SYSIRQ
+                Some(IosOp::SdMount) => self.fs.mount(), //SDMOUNT
+                Some(IosOp::AtxBuff) => self.tx_free(), // ATXBUFF
+                Some(IosOp::SysIrq) => { // SYSIRQ
                     //    I/O DATA:    D7 D6 D5 D4 D3 D2 D1 D0
                     //                ---------------------------------------------------------
                     //                  X  X  X  X  X  X  X  0    Serial Rx IRQ not set
@@ -344,7 +656,7 @@ impl Machine for Mbc2Machine {
                     self.int_status = 0;
                     value
                 },
-                0x90 => { // GETSPP
+                Some(IosOp::GetSpp) => { // GETSPP
                     //    I/O DATA:  D7 D6 D5 D4 D3 D2 D1 D0
                     //              ---------------------------------------------------------
                     //                0  0  0  0  0  0  0  0    SPP emulation disabled
@@ -366,62 +678,226 @@ impl Machine for Mbc2Machine {
                         0
                     }
                 },
+                Some(IosOp::HostRead) | Some(IosOp::HostErr) => match self.host_port_in() {
+                    Some(value) => value,
+                    None => {
+                        implemented = false;
+                        0
+                    }
+                },
                 _ => {
                     implemented = false;
                     0
                 }
             };
-            if !implemented {
-                println!("<<{} not implemented>>",
-                    opcode_name(self.opcode));
-                self.quit = true;
-            } else if self.trace
-                    && self.opcode != OPCODE_NOP
-                    && self.opcode != 0x83
-                    && (self.opcode != 0x86 || self.io_byte_count == 1) {
-                println!("<<{} -> {:02x}, {}>>",
-                opcode_name(self.opcode), value, self.fs.get_last_error());
-            }
+            let value = if implemented {
+                value
+            } else {
+                self.not_implemented();
+                0xff
+            };
+            self.trace_ios(opcode, value, false);
             value
         }
     }
 }
 
-fn opcode_name(opcode: u8) -> &'static str {
-    match opcode {
-        0x00 => "USER LED",
-
-        0x01 => "SERIAL TX",
-        0x03 => "GPIOA W",
-        0x04 => "GPIOB W",
-        0x05 => "IODIRA W",
-        0x06 => "IODIRB W",
-        0x07 => "GPPUA W",
-        0x08 => "GPPUB W",
-        0x09 => "SELDISK",
-        0x0A => "SELTRACK",
-        0x0B => "SELSECT",
-        0x0C => "WRITESECT",
-        0x0D => "SETBANK",
-        0x0E => "SETIRQ",
-        0x0F => "SETTICK",
-        0x10 => "SETOPT",
-        0x11 => "SETSPP",
-        0x12 => "WRSPP",
-
-        0x80 => "USER KEY",
-        0x81 => "GPIOA R",
-        0x82 => "GPIOB R",
-        0x83 => "SYSFLAGS",
-        0x84 => "DATETIME",
-        0x85 => "ERRDISK",
-        0x86 => "READSECT",
-        0x87 => "SDMOUNT",
-        0x88 => "ATXBUFF",
-        0x89 => "SYSIRQ",
-        0x8A => "GETSPP",
-
-        0xFF => "NOP",
-        _ => "UNKNOWN"
+impl Mbc2Machine {
+    // The char typed on the host, None if it is not for the guest
+    fn host_char(&mut self, ch: u8) -> Option<u8> {
+        let host_keys = self.con.host_keys();
+        match ch {
+            3 if host_keys => { // Control C
+                self.quit = true;
+                Some(ch)
+            },
+            _ if host_keys && Some(ch) == self.monitor_key => {
+                self.monitor_requested = true;
+                None
+            },
+            127 => Some(8), // Backspace
+            _ => Some(ch),
+        }
+    }
+
+    // Counts the polls of the console with no input for the idle detection
+    fn console_poll(&mut self, empty: bool) {
+        if !empty || !self.idle_detection {
+            self.idle_polls = 0;
+            return;
+        }
+        let cycles = self.cycles + self.wait_cycles;
+        if self.idle_polls == 0 || cycles - self.idle_start > IDLE_WINDOW {
+            self.idle_polls = 0;
+            self.idle_start = cycles;
+        }
+        self.idle_polls += 1;
+    }
+
+    // Moves the chars of the serial model up to the current emulated time
+    fn serial_advance(&mut self) {
+        let cycles = self.cycles + self.wait_cycles;
+        while let Some(ch) = self.serial.as_mut().and_then(|serial| serial.next_tx(cycles)) {
+            self.con.put(ch);
+        }
+        if self.con.exit_code().is_some() {
+            self.quit = true;
+        }
+
+        while self.serial.as_mut().is_some_and(|serial| serial.rx_slot(cycles)) {
+            if !self.con.status() {
+                if let Some(serial) = self.serial.as_mut() {
+                    serial.rx_idle(cycles);
+                }
+                break;
+            }
+            let ch = self.con.read();
+            if let Some(ch) = self.host_char(ch) {
+                if let Some(serial) = self.serial.as_mut() {
+                    serial.receive(ch);
+                }
+            }
+        }
+    }
+
+    fn serial_write(&mut self, value: u8) {
+        // The Z80 waits for room on the TX buffer
+        let cycles = self.cycles + self.wait_cycles;
+        self.wait_cycles += self.serial.as_ref().map_or(0, |serial| serial.tx_wait(cycles));
+        self.serial_advance();
+        let cycles = self.cycles + self.wait_cycles;
+        if let Some(serial) = self.serial.as_mut() {
+            serial.write(value, cycles);
+        }
+    }
+
+    fn rx_available(&mut self) -> bool {
+        if self.serial.is_some() {
+            self.serial_advance();
+            self.serial.as_ref().is_some_and(|serial| serial.rx_available())
+        } else {
+            self.con.status()
+        }
+    }
+
+    fn tx_free(&mut self) -> u8 {
+        self.serial_advance();
+        self.serial.as_ref().map_or(255, |serial| serial.tx_free())
+    }
+
+    // WAIT state of an access to the data port with the timing model
+    fn charge_data(&mut self) {
+        if let Some(timing) = self.timing.as_ref() {
+            self.wait_cycles += timing.data_cost(self.ios_op(), self.op_bytes == 0) as u64;
+        }
+        self.op_bytes += 1;
+    }
+
+    fn not_implemented(&mut self) {
+        let (policy, first) = self.unimplemented.check(self.opcode, self.pc);
+        let name = format!("{} ({:02X}h)", self.firmware.opcode_name(self.opcode), self.opcode);
+        match policy {
+            Policy::Abort => {
+                println!("<<{} not implemented>>", name);
+                self.crash = Some(format!("IOS opcode {} not implemented", name));
+                self.quit = true;
+            },
+            Policy::Warn => if first {
+                println!("<<{} not implemented, ignored>>", name);
+            },
+            Policy::Prompt => {
+                println!("<<{} not implemented at {:04X}>>", name, self.pc);
+                self.monitor_requested = true;
+            },
+        }
+    }
+
+    fn trace_ios(&mut self, opcode: u8, value: u8, write: bool) {
+        // The opcode is already reset at the end of the operation
+        let op = self.opcodes[opcode as usize];
+        self.ios_trace.data(opcode, op, value, write, self.pc, self.cycles);
+        if self.opcode == OPCODE_NOP {
+            // The multi byte transfer is completed
+            self.end_ios_trace(opcode);
+        }
+    }
+
+    fn end_ios_trace(&mut self, opcode: u8) {
+        let error = match self.opcodes[opcode as usize] {
+            Some(IosOp::SelDisk) | Some(IosOp::SelTrack) | Some(IosOp::SelSect) | Some(IosOp::WriteSect)
+                | Some(IosOp::ErrDisk) | Some(IosOp::ReadSect) | Some(IosOp::SdMount) => {
+                Some(format!("{:?}", self.fs.last_error()))
+            },
+            Some(IosOp::HostName) | Some(IosOp::HostOpen) | Some(IosOp::HostWrite) | Some(IosOp::HostClose)
+                | Some(IosOp::HostRead) | Some(IosOp::HostErr) => {
+                self.host.as_ref().map(|host| host.get_last_error().to_string())
+            },
+            _ => None,
+        };
+        self.ios_trace.end(error);
+    }
+
+    // Emulator only opcodes to transfer files with the host. They behave
+    // as not implemented when disabled, as in the real firmware.
+    fn host_port_out(&mut self, value: u8) -> bool {
+        let op = self.ios_op();
+        let host = match self.host.as_mut() {
+            Some(host) => host,
+            None => return false,
+        };
+
+        match op {
+            Some(IosOp::HostName) => { // HOSTNAME
+                // Zero terminated name, relative to the host folder
+                if self.io_byte_count == 0 {
+                    host.clear_name();
+                }
+                self.io_byte_count += 1;
+                if value == 0 {
+                    self.opcode = OPCODE_NOP;
+                } else {
+                    host.push_name(value);
+                }
+            },
+            Some(IosOp::HostOpen) => host.open(value), // HOSTOPEN
+            Some(IosOp::HostWrite) => { // HOSTWRITE
+                host.write(value);
+                self.io_byte_count += 1;
+                if self.io_byte_count >= RECORD_SIZE as u32 {
+                    self.opcode = OPCODE_NOP;
+                }
+            },
+            Some(IosOp::HostClose) => host.close(), // HOSTCLOSE
+            _ => return false,
+        }
+        true
+    }
+
+    fn host_port_in(&mut self) -> Option<u8> {
+        let op = self.ios_op();
+        let host = self.host.as_mut()?;
+
+        let value = match op {
+            Some(IosOp::HostRead) => { // HOSTREAD
+                // The count of valid bytes followed by a 128 bytes record
+                let value = if self.io_byte_count == 0 {
+                    let size = host.read_record();
+                    if size == 0 {
+                        self.opcode = OPCODE_NOP;
+                    }
+                    size
+                } else {
+                    host.record_byte(self.io_byte_count as usize - 1)
+                };
+                self.io_byte_count += 1;
+                if self.io_byte_count > RECORD_SIZE as u32 {
+                    self.opcode = OPCODE_NOP;
+                }
+                value
+            },
+            Some(IosOp::HostErr) => host.get_last_error(), // HOSTERR
+            _ => return None,
+        };
+        Some(value)
     }
 }
@@ -0,0 +1,721 @@
+use std::collections::VecDeque;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{channel, Receiver};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::console::Console;
+
+// Hayes compatible modem on the serial port. The console stays connected in
+// parallel, as with a Y cable: the keys typed go to the Z80 and the output
+// of the Z80 is shown. The Z80 output goes to the modem too:
+//    command mode: the lines starting with AT are commands, the results go
+//        to the Z80
+//    online: the output is sent on the TCP connection, and the data received
+//        goes to the Z80. The escape +++ with the guard time returns to the
+//        command mode.
+//
+// The connections are made on a thread, the emulation goes on while dialing
+// and any char of the Z80 aborts the call, as a key on a real modem. The line
+// feed after the carriage return of the command does not abort it.
+//
+// Commands:
+//    A          answer the incoming connection on the listening port
+//    D HOST:PORT  dial a TCP connection, the port is 23 by default. DT and DP
+//               are the same.
+//    En Qn Vn   echo of the commands, off by default as the console shows
+//               them, quiet mode, verbose results
+//    H          hang up
+//    O          return online
+//    Z, &F      reset
+//    I          information
+//    Sn=v, Sn?  set and query the S-registers
+//    L, M, X, &C, &D...  accepted and ignored
+//
+// S-registers:
+//    S0: rings to answer, 0 for no auto answer
+//    S1: rings of the incoming connection
+//    S2: escape char
+//    S3, S4, S5: carriage return, line feed and backspace chars
+//    S7: seconds to wait for the connection
+//    S12: guard time of the escape, in 1/50 s
+
+const REGISTERS: usize = 32;
+const MAX_LINE: usize = 80;
+const DEFAULT_PORT: u16 = 23;
+const LISTEN_ADDRESS: &str = "127.0.0.1";
+const RING_INTERVAL: Duration = Duration::from_secs(6);
+const POLL_WAIT: Duration = Duration::from_millis(10);
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum ModemResult {
+    Ok,
+    Connect,
+    Ring,
+    NoCarrier,
+    Error,
+    Busy,
+}
+
+impl ModemResult {
+    fn code(&self) -> u8 {
+        match self {
+            ModemResult::Ok => 0,
+            ModemResult::Connect => 1,
+            ModemResult::Ring => 2,
+            ModemResult::NoCarrier => 3,
+            ModemResult::Error => 4,
+            ModemResult::Busy => 7,
+        }
+    }
+
+    fn text(&self) -> &'static str {
+        match self {
+            ModemResult::Ok => "OK",
+            ModemResult::Connect => "CONNECT",
+            ModemResult::Ring => "RING",
+            ModemResult::NoCarrier => "NO CARRIER",
+            ModemResult::Error => "ERROR",
+            ModemResult::Busy => "BUSY",
+        }
+    }
+}
+
+// Listens on [ADDRESS:]PORT, on the local host only by default
+pub fn listen(address: &str) -> Result<TcpListener, String> {
+    let address = listen_address(address);
+    let listener = TcpListener::bind(&address)
+        .map_err(|e| format!("Error listening on {} for the modem: {}", address, e))?;
+    listener.set_nonblocking(true)
+        .map_err(|e| format!("Error listening on {} for the modem: {}", address, e))?;
+    Ok(listener)
+}
+
+fn listen_address(address: &str) -> String {
+    if address.contains(':') {
+        address.to_string()
+    } else {
+        format!("{}:{}", LISTEN_ADDRESS, address)
+    }
+}
+
+// Connects to the first address that answers, on its own thread
+fn connect(address: String, timeout: Duration) -> Result<TcpStream, ModemResult> {
+    let addrs = address.to_socket_addrs().map_err(|_| ModemResult::NoCarrier)?;
+    let mut result = ModemResult::NoCarrier;
+    for addr in addrs {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) if e.kind() == ErrorKind::ConnectionRefused => result = ModemResult::Busy,
+            Err(_) => (),
+        }
+    }
+    Err(result)
+}
+
+pub struct ModemConsole {
+    inner: Box<dyn Console>,
+    listener: Option<TcpListener>,
+    stream: Option<TcpStream>,
+    online: bool,
+    incoming: Option<TcpStream>,
+    last_ring: Option<Instant>,
+    dialing: Option<Receiver<Result<TcpStream, ModemResult>>>,
+    dial_lf: bool, // The line feed of the command is still expected
+
+    rx: VecDeque<u8>,
+    tx: VecDeque<u8>,
+    from_console: bool,
+    line: String,
+    registers: [u8; REGISTERS],
+    echo: bool,
+    quiet: bool,
+    verbose: bool,
+
+    last_tx: Instant,
+    escape_count: u8,
+    escape_time: Option<Instant>,
+}
+
+impl ModemConsole {
+    pub fn new(inner: Box<dyn Console>, listener: Option<TcpListener>) -> ModemConsole {
+        let mut modem = ModemConsole {
+            inner,
+            listener,
+            stream: None,
+            online: false,
+            incoming: None,
+            last_ring: None,
+            dialing: None,
+            dial_lf: false,
+
+            rx: VecDeque::new(),
+            tx: VecDeque::new(),
+            from_console: false,
+            line: String::new(),
+            registers: [0; REGISTERS],
+            echo: false,
+            quiet: false,
+            verbose: true,
+
+            last_tx: Instant::now(),
+            escape_count: 0,
+            escape_time: None,
+        };
+        modem.reset();
+        modem
+    }
+
+    fn reset(&mut self) {
+        self.registers = [0; REGISTERS];
+        self.registers[2] = b'+';
+        self.registers[3] = 13;
+        self.registers[4] = 10;
+        self.registers[5] = 8;
+        self.registers[7] = 50;
+        self.registers[12] = 50;
+        self.echo = false;
+        self.quiet = false;
+        self.verbose = true;
+    }
+
+    fn result(&mut self, result: ModemResult) {
+        if self.quiet {
+            return;
+        }
+        let (cr, lf) = (self.registers[3], self.registers[4]);
+        if self.verbose {
+            self.rx.extend([cr, lf].iter());
+            self.rx.extend(result.text().bytes());
+            self.rx.extend([cr, lf].iter());
+        } else {
+            self.rx.extend(result.code().to_string().bytes());
+            self.rx.push_back(cr);
+        }
+    }
+
+    fn hang_up(&mut self) {
+        self.stream = None;
+        self.tx.clear();
+        self.online = false;
+        self.escape_count = 0;
+        self.escape_time = None;
+    }
+
+    fn go_online(&mut self, stream: TcpStream) {
+        let _ = stream.set_nonblocking(true);
+        let _ = stream.set_nodelay(true);
+        self.stream = Some(stream);
+        self.online = true;
+        self.registers[1] = 0;
+        self.result(ModemResult::Connect);
+    }
+
+    // The result comes later, with poll
+    fn dial(&mut self, address: &str) {
+        let address = if address.contains(':') {
+            address.to_string()
+        } else {
+            format!("{}:{}", address, DEFAULT_PORT)
+        };
+        let timeout = Duration::from_secs(self.registers[7].max(1) as u64);
+        let (sender, receiver) = channel();
+        thread::spawn(move || {
+            // The result is dropped if the call was aborted
+            let _ = sender.send(connect(address, timeout));
+        });
+        self.dialing = Some(receiver);
+        self.dial_lf = true;
+    }
+
+    fn check_dial(&mut self) {
+        let result = match self.dialing.as_ref().map(|dialing| dialing.try_recv()) {
+            Some(Ok(result)) => result,
+            Some(Err(_)) | None => return,
+        };
+        self.dialing = None;
+        match result {
+            Ok(stream) => self.go_online(stream),
+            Err(result) => self.result(result),
+        }
+    }
+
+    fn answer(&mut self) -> ModemResult {
+        match self.incoming.take() {
+            Some(stream) => {
+                self.go_online(stream);
+                ModemResult::Connect
+            },
+            None => ModemResult::NoCarrier,
+        }
+    }
+
+    // Runs the commands after AT. A and D give the CONNECT result when the
+    // connection is established.
+    fn execute(&mut self, commands: &str) {
+        let chars: Vec<char> = commands.chars().filter(|c| *c != ' ').collect();
+        let mut i = 0;
+        let number = |i: &mut usize| {
+            let start = *i;
+            while *i < chars.len() && chars[*i].is_ascii_digit() {
+                *i += 1;
+            }
+            chars[start..*i].iter().collect::<String>().parse::<usize>().unwrap_or(0)
+        };
+
+        while i < chars.len() {
+            let command = chars[i].to_ascii_uppercase();
+            i += 1;
+            match command {
+                'A' => {
+                    let result = self.answer();
+                    if result != ModemResult::Connect {
+                        self.result(result);
+                    }
+                    return;
+                },
+                'D' => {
+                    if self.stream.is_some() {
+                        self.result(ModemResult::Error);
+                        return;
+                    }
+                    if i < chars.len() && "TPtp".contains(chars[i]) {
+                        i += 1;
+                    }
+                    let address: String = chars[i..].iter().collect();
+                    self.dial(&address);
+                    return;
+                },
+                'O' => {
+                    number(&mut i);
+                    if self.stream.is_some() {
+                        self.online = true;
+                        self.result(ModemResult::Connect);
+                    } else {
+                        self.result(ModemResult::NoCarrier);
+                    }
+                    return;
+                },
+                'E' => self.echo = number(&mut i) == 1,
+                'Q' => self.quiet = number(&mut i) == 1,
+                'V' => self.verbose = number(&mut i) == 1,
+                'H' => {
+                    if number(&mut i) == 0 {
+                        self.hang_up();
+                    }
+                },
+                'Z' => {
+                    number(&mut i);
+                    self.hang_up();
+                    self.reset();
+                },
+                'I' => {
+                    number(&mut i);
+                    let (cr, lf) = (self.registers[3], self.registers[4]);
+                    self.rx.extend([cr, lf].iter());
+                    self.rx.extend("z80-mbc2-emu Hayes modem".bytes());
+                },
+                'L' | 'M' | 'X' => {
+                    number(&mut i);
+                },
+                '&' => {
+                    let option = chars.get(i).map(|c| c.to_ascii_uppercase());
+                    i += 1;
+                    number(&mut i);
+                    if option == Some('F') {
+                        self.reset();
+                    }
+                },
+                'S' => {
+                    let register = number(&mut i);
+                    if register >= REGISTERS {
+                        self.result(ModemResult::Error);
+                        return;
+                    }
+                    match chars.get(i) {
+                        Some('=') => {
+                            i += 1;
+                            let value = number(&mut i);
+                            if value > 255 {
+                                self.result(ModemResult::Error);
+                                return;
+                            }
+                            self.registers[register] = value as u8;
+                        },
+                        Some('?') => {
+                            i += 1;
+                            let (cr, lf) = (self.registers[3], self.registers[4]);
+                            let text = format!("{:03}", self.registers[register]);
+                            self.rx.extend([cr, lf].iter());
+                            self.rx.extend(text.bytes());
+                        },
+                        _ => {
+                            self.result(ModemResult::Error);
+                            return;
+                        }
+                    }
+                },
+                _ => {
+                    self.result(ModemResult::Error);
+                    return;
+                }
+            }
+        }
+        self.result(ModemResult::Ok);
+    }
+
+    fn command_char(&mut self, ch: u8) {
+        if self.echo {
+            self.rx.push_back(ch);
+        }
+        if ch == self.registers[3] {
+            let line = std::mem::take(&mut self.line);
+            if line.len() >= 2 && line[..2].eq_ignore_ascii_case("AT") {
+                self.execute(&line[2..]);
+            }
+        } else if ch == self.registers[5] {
+            self.line.pop();
+        } else if (32..127).contains(&ch) && self.line.len() < MAX_LINE {
+            self.line.push(ch as char);
+        }
+    }
+
+    fn online_char(&mut self, ch: u8) {
+        // Escape: the guard time, three escape chars and the guard time
+        let guard = Duration::from_millis(self.registers[12] as u64 * 20);
+        if ch == self.registers[2] && self.escape_count < 3
+                && (self.escape_count > 0 || self.last_tx.elapsed() >= guard) {
+            self.escape_count += 1;
+            if self.escape_count == 3 {
+                self.escape_time = Some(Instant::now());
+            }
+        } else {
+            self.escape_count = 0;
+            self.escape_time = None;
+        }
+        self.last_tx = Instant::now();
+
+        self.tx.push_back(ch);
+        self.flush_tx();
+    }
+
+    // Sends the chars waiting, the rest stays on the buffer
+    fn flush_tx(&mut self) {
+        while !self.tx.is_empty() {
+            let result = match self.stream.as_mut() {
+                Some(stream) => stream.write(self.tx.as_slices().0),
+                None => Ok(0),
+            };
+            match result {
+                Ok(0) => {
+                    self.hang_up();
+                    self.result(ModemResult::NoCarrier);
+                },
+                Ok(size) => {
+                    self.tx.drain(..size);
+                },
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return,
+                Err(e) if e.kind() == ErrorKind::Interrupted => (),
+                Err(_) => {
+                    self.hang_up();
+                    self.result(ModemResult::NoCarrier);
+                }
+            }
+        }
+    }
+
+    // Incoming connections, calls, data sent and received and escapes
+    fn poll(&mut self) {
+        self.check_dial();
+        if let Some(listener) = self.listener.as_ref() {
+            if let Ok((stream, _)) = listener.accept() {
+                if self.stream.is_none() && self.incoming.is_none() {
+                    self.incoming = Some(stream);
+                    self.last_ring = None;
+                }
+                // Else busy, the connection is closed
+            }
+        }
+        if self.incoming.is_some() && self.last_ring.is_none_or(|t| t.elapsed() >= RING_INTERVAL) {
+            self.last_ring = Some(Instant::now());
+            self.registers[1] = self.registers[1].wrapping_add(1);
+            self.result(ModemResult::Ring);
+            if self.registers[0] > 0 && self.registers[1] >= self.registers[0] {
+                self.answer();
+            }
+        }
+
+        self.flush_tx();
+        if self.online {
+            let mut buf = [0; 256];
+            let received = match self.stream.as_mut() {
+                Some(stream) => stream.read(&mut buf),
+                None => Ok(0),
+            };
+            match received {
+                Ok(0) => {
+                    // The remote side closed
+                    self.hang_up();
+                    self.result(ModemResult::NoCarrier);
+                },
+                Ok(size) => self.rx.extend(buf[..size].iter()),
+                Err(_) => (), // Nothing received
+            }
+            self.check_escape();
+        }
+    }
+
+    // Command mode after the guard time following the escape
+    fn check_escape(&mut self) {
+        let guard = Duration::from_millis(self.registers[12] as u64 * 20);
+        if self.online && self.escape_time.is_some_and(|t| t.elapsed() >= guard) {
+            self.online = false;
+            self.escape_count = 0;
+            self.escape_time = None;
+            self.result(ModemResult::Ok);
+        }
+    }
+}
+
+impl Console for ModemConsole {
+    fn status(&mut self) -> bool {
+        self.poll();
+        !self.rx.is_empty() || self.inner.status()
+    }
+
+    fn read(&mut self) -> u8 {
+        loop {
+            self.poll();
+            if let Some(ch) = self.rx.pop_front() {
+                self.from_console = false;
+                return ch;
+            }
+            if self.inner.status() {
+                self.from_console = true;
+                return self.inner.read();
+            }
+            self.inner.wait(POLL_WAIT);
+        }
+    }
+
+    fn put(&mut self, ch: u8) {
+        self.inner.put(ch);
+        self.check_escape();
+        if self.dialing.is_some() {
+            if std::mem::take(&mut self.dial_lf) && ch == self.registers[4] {
+                return;
+            }
+            // Any other char aborts the call
+            self.dialing = None;
+            self.result(ModemResult::NoCarrier);
+        } else if self.online {
+            self.online_char(ch);
+        } else {
+            self.command_char(ch);
+        }
+    }
+
+    fn wait(&mut self, timeout: Duration) -> bool {
+        let start = Instant::now();
+        loop {
+            if self.status() {
+                return true;
+            }
+            let elapsed = start.elapsed();
+            if elapsed >= timeout {
+                return false;
+            }
+            self.inner.wait(POLL_WAIT.min(timeout - elapsed));
+        }
+    }
+
+    fn host_keys(&self) -> bool {
+        // The keys received on the line are for the Z80
+        self.from_console && self.inner.host_keys()
+    }
+
+    fn exit_code(&self) -> Option<i32> {
+        self.inner.exit_code()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::console::NullConsole;
+    use std::thread::sleep;
+
+    fn modem() -> ModemConsole {
+        ModemConsole::new(Box::new(NullConsole), None)
+    }
+
+    // The output of the modem to the Z80
+    fn output(modem: &mut ModemConsole) -> String {
+        modem.poll();
+        modem.rx.drain(..).map(|ch| ch as char).collect()
+    }
+
+    fn command(modem: &mut ModemConsole, line: &str) -> String {
+        for ch in line.bytes() {
+            modem.put(ch);
+        }
+        modem.put(13);
+        output(modem)
+    }
+
+    // The modem online with the other end of the connection
+    fn connected(modem: &mut ModemConsole) -> TcpStream {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        modem.go_online(stream);
+        output(modem);
+        let (remote, _) = listener.accept().unwrap();
+        remote.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        remote
+    }
+
+    fn wait_for(modem: &mut ModemConsole, text: &str) -> String {
+        let start = Instant::now();
+        let mut received = String::new();
+        while !received.contains(text) && start.elapsed() < Duration::from_secs(5) {
+            sleep(POLL_WAIT);
+            received += &output(modem);
+        }
+        received
+    }
+
+    #[test]
+    fn hayes_commands() {
+        let mut modem = modem();
+        assert_eq!(command(&mut modem, "AT"), "\r\nOK\r\n");
+        assert_eq!(command(&mut modem, "at l1 m0 x4 &c1"), "\r\nOK\r\n");
+        assert_eq!(command(&mut modem, "AX\x08T"), "\r\nOK\r\n");
+        assert_eq!(command(&mut modem, "DIR"), "");
+        assert_eq!(command(&mut modem, "ATJ"), "\r\nERROR\r\n");
+        assert_eq!(command(&mut modem, "ATO"), "\r\nNO CARRIER\r\n");
+        assert_eq!(command(&mut modem, "ATA"), "\r\nNO CARRIER\r\n");
+        assert_eq!(command(&mut modem, "ATI"), "\r\nz80-mbc2-emu Hayes modem\r\nOK\r\n");
+        assert_eq!(command(&mut modem, "ATV0"), "0\r");
+        assert_eq!(command(&mut modem, "ATE1"), "0\r");
+        assert_eq!(command(&mut modem, "ATQ1"), "ATQ1\r");
+        assert_eq!(command(&mut modem, "ATZ"), "ATZ\r\r\nOK\r\n");
+    }
+
+    #[test]
+    fn s_registers() {
+        let mut modem = modem();
+        assert_eq!(command(&mut modem, "ATS7?"), "\r\n050\r\nOK\r\n");
+        assert_eq!(command(&mut modem, "ATS7=30S3?"), "\r\n013\r\nOK\r\n");
+        assert_eq!(modem.registers[7], 30);
+        assert_eq!(command(&mut modem, "ATS7=256"), "\r\nERROR\r\n");
+        assert_eq!(command(&mut modem, "ATS32=1"), "\r\nERROR\r\n");
+        assert_eq!(command(&mut modem, "ATS7"), "\r\nERROR\r\n");
+        // New line chars
+        assert_eq!(command(&mut modem, "ATS4=13"), "\r\rOK\r\r");
+        assert_eq!(command(&mut modem, "AT"), "\r\rOK\r\r");
+        assert_eq!(command(&mut modem, "AT&F"), "\r\nOK\r\n");
+        assert_eq!(modem.registers[7], 50);
+    }
+
+    #[test]
+    fn escape_guard_time() {
+        let mut modem = modem();
+        let mut remote = connected(&mut modem);
+        modem.registers[12] = 5; // 100 ms
+
+        // Without the guard time before it is data
+        for ch in b"a+++" {
+            modem.put(*ch);
+        }
+        sleep(Duration::from_millis(150));
+        assert_eq!(output(&mut modem), "");
+        assert!(modem.online);
+
+        // Data after it cancels it
+        for ch in b"+++b" {
+            modem.put(*ch);
+        }
+        sleep(Duration::from_millis(150));
+        assert_eq!(output(&mut modem), "");
+        assert!(modem.online);
+
+        sleep(Duration::from_millis(150));
+        for ch in b"+++" {
+            modem.put(*ch);
+        }
+        assert_eq!(output(&mut modem), "");
+        sleep(Duration::from_millis(150));
+        assert_eq!(output(&mut modem), "\r\nOK\r\n");
+        assert!(!modem.online);
+
+        // The escape chars were sent, the connection is kept
+        let mut buf = [0; 11];
+        remote.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"a++++++b+++");
+        assert_eq!(command(&mut modem, "ATO"), "\r\nCONNECT\r\n");
+        sleep(Duration::from_millis(150));
+        for ch in b"+++" {
+            modem.put(*ch);
+        }
+        sleep(Duration::from_millis(150));
+        assert_eq!(output(&mut modem), "\r\nOK\r\n");
+        assert_eq!(command(&mut modem, "ATH"), "\r\nOK\r\n");
+        assert_eq!(command(&mut modem, "ATO"), "\r\nNO CARRIER\r\n");
+    }
+
+    #[test]
+    fn online_data() {
+        let mut modem = modem();
+        let mut remote = connected(&mut modem);
+        for ch in b"hello" {
+            modem.put(*ch);
+        }
+        let mut buf = [0; 5];
+        remote.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+
+        remote.write_all(b"hi").unwrap();
+        assert_eq!(wait_for(&mut modem, "hi"), "hi");
+        drop(remote);
+        assert_eq!(wait_for(&mut modem, "NO CARRIER"), "\r\nNO CARRIER\r\n");
+        assert!(!modem.online);
+    }
+
+    #[test]
+    fn dial() {
+        let mut modem = modem();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        assert_eq!(command(&mut modem, &format!("ATDT{}", address)), "");
+        assert_eq!(wait_for(&mut modem, "CONNECT"), "\r\nCONNECT\r\n");
+        assert!(modem.online);
+        assert_eq!(modem.registers[1], 0);
+
+        // The line feed after the command, as with PRINT or BDOS 9
+        modem.hang_up();
+        assert_eq!(command(&mut modem, &format!("ATDT{}", address)), "");
+        modem.put(10);
+        assert_eq!(wait_for(&mut modem, "CONNECT"), "\r\nCONNECT\r\n");
+
+        // Any char aborts the call
+        modem.hang_up();
+        assert_eq!(command(&mut modem, &format!("ATD{}", address)), "");
+        modem.put(10);
+        modem.put(b' ');
+        assert_eq!(output(&mut modem), "\r\nNO CARRIER\r\n");
+        assert!(modem.dialing.is_none());
+
+        // Refused
+        drop(listener);
+        assert_eq!(command(&mut modem, &format!("ATDP{}", address)), "");
+        assert_eq!(wait_for(&mut modem, "BUSY"), "\r\nBUSY\r\n");
+    }
+
+    #[test]
+    fn listen_addresses() {
+        assert_eq!(listen_address("2323"), "127.0.0.1:2323");
+        assert_eq!(listen_address("0.0.0.0:2323"), "0.0.0.0:2323");
+        assert!(listen("2323x").is_err());
+    }
+}
@@ -0,0 +1,50 @@
+use super::mbc2_machine::Mbc2Machine;
+
+// Host monitor, opened with control-] or the --monitor-key on the console
+
+const HELP: &str =
+"Commands:
+  stats        show the disk activity
+  stats FILE   write the disk activity as JSON to FILE
+  unimpl       show the IOS opcodes not implemented used
+  quit         exit the emulator
+  continue     return to the emulation, also an empty line";
+
+pub fn monitor(machine: &mut Mbc2Machine) {
+    machine.monitor_requested = false;
+    println!();
+    println!("z80-mbc2-emu monitor, 'help' for the commands");
+
+    loop {
+        let line = machine.read_host_line("> ");
+        let params: Vec<&str> = line.split_whitespace().collect();
+        let command = params.first().copied().unwrap_or("");
+
+        match command {
+            "" | "c" | "continue" => break,
+            "q" | "quit" => {
+                machine.quit = true;
+                break;
+            },
+            "stats" => {
+                match params.get(1) {
+                    None => print!("{}", machine.disk_stats().to_text()),
+                    Some(filename) => {
+                        if machine.disk_stats().write_json(filename) {
+                            println!("Disk activity written to '{}'", filename);
+                        }
+                    }
+                }
+            },
+            "unimpl" => {
+                if machine.unimplemented().is_empty() {
+                    println!("No IOS opcodes not implemented used");
+                } else {
+                    print!("{}", machine.unimplemented().summary());
+                }
+            },
+            "h" | "help" | "?" => println!("{}", HELP),
+            _ => println!("Unknown command '{}', 'help' for the commands", command),
+        }
+    }
+}
@@ -0,0 +1,428 @@
+use super::config::{parse_number, DEFAULT_CONFIG_FILE};
+use super::faults::FaultRule;
+use super::filesystem::LockMode;
+use super::firmware::{Firmware, DEFAULT_FIRMWARE};
+use super::mbc2_machine::DEFAULT_MONITOR_KEY;
+use super::unimplemented::Unimplemented;
+
+pub struct Options {
+    pub image: Option<String>,
+    pub loads: Vec<LoadFile>,
+    pub bank: Option<u8>,
+    pub pc: Option<u16>,
+    pub sp: Option<u16>,
+    pub config_file: String,
+    pub config_required: bool,
+    pub fault_rules: Vec<FaultRule>,
+    pub lock_mode: LockMode,
+    pub disk_stats: bool,
+    pub disk_stats_json: Option<String>,
+    pub cpm_trace: Option<String>,
+    pub ios_trace: Option<String>,
+    pub ios_trace_filter: Option<String>,
+    pub exec_trace: Option<String>,
+    pub exec_trace_filter: Option<String>,
+    pub crash_report: Option<String>,
+    pub unimplemented: Unimplemented,
+    pub firmware: Firmware,
+    pub printer: Option<String>,
+    pub fast_boot: bool,
+    pub timing: bool,
+    pub serial: bool,
+    pub idle_detection: bool,
+    pub monitor_key: Option<u8>,
+    pub pty: bool,
+    pub pty_link: Option<String>,
+    pub modem: bool,
+    pub modem_listen: Option<String>, // [ADDRESS:]PORT
+
+    // Folder for the emulator only IOS opcodes to access host files, they
+    // are disabled without it
+    pub host_dir: Option<String>,
+
+    // For the run command
+    pub program: Option<String>,
+    pub program_args: Vec<String>,
+    pub timeout: u64,
+    pub drive: u8,
+    pub fail_on: Vec<String>,
+}
+
+// Binary, Intel HEX or CP/M .COM file to load in memory
+pub struct LoadFile {
+    pub file: String,
+    pub address: Option<u16>,
+    pub bank: u8,
+}
+
+const DEFAULT_TIMEOUT: u64 = 60;
+
+// Returns the options from the command line or an error message. For the
+// run command, the program and its arguments follow the image.
+pub fn parse_options(args: &[String], run: bool) -> Result<Options, String> {
+
+    let mut image = None;
+    let mut loads = Vec::new();
+    let mut bank = None;
+    let mut pc = None;
+    let mut sp = None;
+    let mut config_file = DEFAULT_CONFIG_FILE.to_string();
+    let mut config_required = false;
+    let mut fault_rules = Vec::new();
+    let mut lock_mode = LockMode::Fail;
+    let mut disk_stats = false;
+    let mut disk_stats_json = None;
+    let mut cpm_trace = None;
+    let mut ios_trace = None;
+    let mut ios_trace_filter = None;
+    let mut exec_trace = None;
+    let mut exec_trace_filter = None;
+    let mut crash_report = None;
+    let mut unimplemented_text = None;
+    let mut printer = None;
+    let mut firmware = DEFAULT_FIRMWARE;
+    let mut fast_boot = false;
+    let mut timing = false;
+    let mut serial = false;
+    let mut idle_detection = true;
+    let mut monitor_key = Some(DEFAULT_MONITOR_KEY);
+    let mut pty = false;
+    let mut pty_link = None;
+    let mut modem = false;
+    let mut modem_listen = None;
+    let mut host_dir = None;
+    let mut program = None;
+    let mut program_args = Vec::new();
+    let mut timeout = DEFAULT_TIMEOUT;
+    let mut drive = 0;
+    let mut fail_on = Vec::new();
+
+    let mut i = 0;
+    while i < args.len() {
+        let arg = &args[i];
+        match arg.as_str() {
+            "--config" => {
+                config_file = option_value(args, i)?;
+                config_required = true;
+                i += 1;
+            },
+            "--load" => {
+                loads.push(parse_load(&option_value(args, i)?, bank.unwrap_or(0))?);
+                i += 1;
+            },
+            "--bank" => {
+                bank = match parse_number(&option_value(args, i)?) {
+                    Some(n) if n <= 2 => Some(n as u8),
+                    _ => return Err("the bank must be 0, 1 or 2".to_string()),
+                };
+                i += 1;
+            },
+            "--pc" => {
+                pc = Some(parse_address(&option_value(args, i)?)?);
+                i += 1;
+            },
+            "--sp" => {
+                sp = Some(parse_address(&option_value(args, i)?)?);
+                i += 1;
+            },
+            "--fault" => {
+                fault_rules.push(FaultRule::parse(&option_value(args, i)?)?);
+                i += 1;
+            },
+            "--lock" => {
+                let name = option_value(args, i)?;
+                lock_mode = LockMode::from_name(&name)
+                    .ok_or(format!("invalid lock mode '{}'", name))?;
+                i += 1;
+            },
+            "--disk-stats" => disk_stats = true,
+            "--disk-stats-json" => {
+                disk_stats_json = Some(option_value(args, i)?);
+                i += 1;
+            },
+            "--cpm-trace" => {
+                cpm_trace = Some(option_value(args, i)?);
+                i += 1;
+            },
+            "--ios-trace" => {
+                ios_trace = Some(option_value(args, i)?);
+                i += 1;
+            },
+            "--ios-trace-filter" => {
+                ios_trace_filter = Some(option_value(args, i)?);
+                i += 1;
+            },
+            "--exec-trace" => {
+                exec_trace = Some(option_value(args, i)?);
+                i += 1;
+            },
+            "--exec-trace-filter" => {
+                exec_trace_filter = Some(option_value(args, i)?);
+                i += 1;
+            },
+            "--crash-report" => {
+                crash_report = Some(option_value(args, i)?);
+                i += 1;
+            },
+            "--unimplemented" => {
+                // The names depend on the firmware, parsed at the end
+                unimplemented_text = Some(option_value(args, i)?);
+                i += 1;
+            },
+            "--firmware" => {
+                firmware = Firmware::parse(&option_value(args, i)?)?;
+                i += 1;
+            },
+            "--fast-boot" => fast_boot = true,
+            "--timing" => timing = true,
+            "--serial" => serial = true,
+            "--no-idle" => idle_detection = false,
+            "--monitor-key" => {
+                monitor_key = parse_key(&option_value(args, i)?)?;
+                i += 1;
+            },
+            "--pty" => pty = true,
+            "--modem" => modem = true,
+            "--modem-listen" => {
+                let value = option_value(args, i)?;
+                modem = true;
+                let port = value.rsplit(':').next().unwrap_or("");
+                if port.parse::<u16>().is_err() {
+                    return Err(format!("invalid port '{}'", value));
+                }
+                modem_listen = Some(value);
+                i += 1;
+            },
+            "--printer" => {
+                printer = Some(option_value(args, i)?);
+                i += 1;
+            },
+            "--pty-link" => {
+                pty = true;
+                pty_link = Some(option_value(args, i)?);
+                i += 1;
+            },
+            "--host-files" => {
+                host_dir = Some(option_value(args, i)?);
+                i += 1;
+            },
+            "--timeout" => {
+                let value = option_value(args, i)?;
+                timeout = value.parse().map_err(|_| format!("invalid timeout '{}'", value))?;
+                i += 1;
+            },
+            "--drive" => {
+                let value = option_value(args, i)?.to_uppercase();
+                drive = match value.as_bytes() {
+                    [letter @ b'A'..=b'P'] => letter - b'A',
+                    _ => return Err(format!("invalid drive '{}'", value)),
+                };
+                i += 1;
+            },
+            "--fail-on" => {
+                fail_on.push(option_value(args, i)?);
+                i += 1;
+            },
+            _ if arg.starts_with("--") => {
+                return Err(format!("unknown option '{}'", arg));
+            },
+            _ => {
+                if image.is_none() {
+                    image = Some(arg.clone());
+                } else if run {
+                    program = Some(arg.clone());
+                    program_args = args[i+1..].to_vec();
+                    break;
+                } else {
+                    return Err(format!("unexpected parameter '{}'", arg));
+                }
+            }
+        }
+        i += 1;
+    }
+
+    if image.is_none() && loads.is_empty() {
+        return Err(String::new()); // Just show the usage
+    }
+    if run && program.is_none() {
+        return Err("the run command requires an image and a program".to_string());
+    }
+    let unimplemented = match unimplemented_text {
+        Some(text) => Unimplemented::parse(&text, firmware)?,
+        None => Unimplemented::new(),
+    };
+    if run && (pty || modem) {
+        return Err("the run command does not use the pty console or the modem".to_string());
+    }
+    Ok(Options {
+        image,
+        loads,
+        bank,
+        pc,
+        sp,
+        config_file,
+        config_required,
+        fault_rules,
+        lock_mode,
+        disk_stats,
+        disk_stats_json,
+        cpm_trace,
+        ios_trace,
+        ios_trace_filter,
+        exec_trace,
+        exec_trace_filter,
+        crash_report,
+        unimplemented,
+        firmware,
+        printer,
+        fast_boot,
+        timing,
+        serial,
+        idle_detection,
+        monitor_key,
+        pty,
+        pty_link,
+        modem,
+        modem_listen,
+        host_dir,
+        program,
+        program_args,
+        timeout,
+        drive,
+        fail_on,
+    })
+}
+
+fn option_value(args: &[String], i: usize) -> Result<String, String> {
+    match args.get(i + 1) {
+        Some(value) => Ok(value.clone()),
+        None => Err(format!("option '{}' requires a value", args[i])),
+    }
+}
+
+pub fn parse_address(text: &str) -> Result<u16, String> {
+    match parse_number(text) {
+        Some(n) if n <= 0xffff => Ok(n as u16),
+        _ => Err(format!("invalid address '{}'", text)),
+    }
+}
+
+// A control key as ctrl-X, ^X or its code, or off. Control-c is kept to
+// return to the host.
+pub fn parse_key(text: &str) -> Result<Option<u8>, String> {
+    if text == "off" {
+        return Ok(None);
+    }
+    let letter = text.strip_prefix("ctrl-").or_else(|| text.strip_prefix("^"));
+    let key = match letter.map(|l| l.to_uppercase().into_bytes()) {
+        Some(l) if l.len() == 1 && (b'@'..=b'_').contains(&l[0]) => l[0] - b'@',
+        Some(_) => return Err(format!("invalid key '{}'", text)),
+        None => match parse_number(text) {
+            Some(n) if n < 0x20 => n as u8,
+            _ => return Err(format!("invalid key '{}'", text)),
+        },
+    };
+    if key == 3 {
+        return Err("control-c is used to return to the host".to_string());
+    }
+    Ok(Some(key))
+}
+
+// Name of a control key
+pub fn key_name(key: u8) -> String {
+    format!("ctrl-{}", (key + b'@') as char)
+}
+
+// FILE or FILE@ADDRESS
+pub fn parse_load(text: &str, bank: u8) -> Result<LoadFile, String> {
+    let (file, address) = match text.rfind('@') {
+        Some(pos) => (&text[..pos], Some(parse_address(&text[pos+1..])?)),
+        None => (text, None),
+    };
+    Ok(LoadFile {
+        file: file.to_string(),
+        address,
+        bank,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str], run: bool) -> Result<Options, String> {
+        let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+        parse_options(&args, run)
+    }
+
+    #[test]
+    fn host_files_are_opt_in() {
+        assert_eq!(parse(&["cpm22"], false).unwrap().host_dir, None);
+        let options = parse(&["--host-files", "host", "cpm22"], false).unwrap();
+        assert_eq!(options.host_dir.as_deref(), Some("host"));
+        assert_eq!(options.image.as_deref(), Some("cpm22"));
+        assert!(parse(&["cpm22", "--host-files"], false).is_err());
+    }
+
+    #[test]
+    fn crash_report_is_opt_in() {
+        assert_eq!(parse(&["cpm22"], false).unwrap().crash_report, None);
+        let options = parse(&["--crash-report", "crash.txt", "cpm22"], false).unwrap();
+        assert_eq!(options.crash_report.as_deref(), Some("crash.txt"));
+    }
+
+    #[test]
+    fn load_files() {
+        let options = parse(&["--bank", "1", "--load", "a.hex", "--load", "b.bin@0x8000"], false).unwrap();
+        assert_eq!(options.image, None);
+        assert_eq!(options.loads[0].file, "a.hex");
+        assert_eq!(options.loads[0].address, None);
+        assert_eq!(options.loads[0].bank, 1);
+        assert_eq!(options.loads[1].file, "b.bin");
+        assert_eq!(options.loads[1].address, Some(0x8000));
+        assert!(parse(&["--bank", "3", "cpm22"], false).is_err());
+        assert!(parse_load("b.bin@0x10000", 0).is_err());
+    }
+
+    #[test]
+    fn usage_and_errors() {
+        assert_eq!(parse(&[], false).err(), Some(String::new()));
+        assert!(parse(&["--unknown", "cpm22"], false).is_err());
+        assert!(parse(&["cpm22", "basic"], false).is_err());
+    }
+
+    #[test]
+    fn run_command() {
+        let options = parse(&["--drive", "c", "--fail-on", "FAILED", "cpm22", "TEST.COM", "--flag", "x"], true).unwrap();
+        assert_eq!(options.drive, 2);
+        assert_eq!(options.fail_on, ["FAILED"]);
+        assert_eq!(options.program.as_deref(), Some("TEST.COM"));
+        assert_eq!(options.program_args, ["--flag", "x"]);
+        assert!(parse(&["cpm22"], true).is_err());
+        assert!(parse(&["--drive", "Q", "cpm22", "TEST.COM"], true).is_err());
+        assert!(parse(&["--pty", "cpm22", "TEST.COM"], true).is_err());
+    }
+
+    #[test]
+    fn modem_listen() {
+        let options = parse(&["--modem-listen", "2323", "cpm22"], false).unwrap();
+        assert!(options.modem);
+        assert_eq!(options.modem_listen.as_deref(), Some("2323"));
+        let options = parse(&["--modem-listen", "0.0.0.0:2323", "cpm22"], false).unwrap();
+        assert_eq!(options.modem_listen.as_deref(), Some("0.0.0.0:2323"));
+        assert!(parse(&["--modem-listen", "localhost", "cpm22"], false).is_err());
+        assert!(parse(&["--modem-listen", "70000", "cpm22"], false).is_err());
+    }
+
+    #[test]
+    fn keys() {
+        assert_eq!(parse_key("ctrl-]"), Ok(Some(0x1d)));
+        assert_eq!(parse_key("^t"), Ok(Some(0x14)));
+        assert_eq!(parse_key("0x1c"), Ok(Some(0x1c)));
+        assert_eq!(parse_key("off"), Ok(None));
+        assert!(parse_key("ctrl-c").is_err());
+        assert!(parse_key("ctrl-1").is_err());
+        assert!(parse_key("65").is_err());
+        assert_eq!(key_name(0x1d), "ctrl-]");
+    }
+}
@@ -0,0 +1,215 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::io::{stdout, Write};
+use std::path::Path;
+
+use super::config::Config;
+use super::console::Console;
+use super::cpmfs::{CpmDisk, CpmFile};
+use super::images::ImageDefinition;
+use super::mbc2_machine::Mbc2Machine;
+use super::options::Options;
+
+// The run command: the program is copied to an in memory copy of a drive of
+// the disk set, the command is typed on the first CCP prompt and the
+// emulation ends on the next prompt.
+
+pub const EXIT_NOT_FOUND: i32 = 1;
+pub const EXIT_TIMEOUT: i32 = 2;
+pub const EXIT_FAILED: i32 = 3;
+
+// BDOS error messages of CP/M 2.2 and CP/M 3
+const BDOS_ERRORS: [&str; 2] = ["Bdos Err", "CP/M Error"];
+
+pub fn prepare(machine: &mut Mbc2Machine, options: &Options, image: &ImageDefinition,
+        config: &Config) -> Result<(), String> {
+    if image.disk_set == 0xff {
+        return Err(format!("the image '{}' has no disk set to run programs", image.id));
+    }
+    let geometry = config.geometry(image.disk_set);
+    let dpb = config.dpb(image.disk_set)?;
+
+    let program = options.program.clone().unwrap_or_default();
+    let data = fs::read(&program)
+        .map_err(|e| format!("Error reading the program '{}': {}", program, e))?;
+    let name = Path::new(&program).file_name()
+        .map(|n| n.to_string_lossy().to_uppercase())
+        .unwrap_or_default();
+    let command_name = name.strip_suffix(".COM")
+        .ok_or(format!("the program '{}' is not a .COM file", program))?
+        .to_string();
+
+    // Add the program to a copy of the disk, the file is not modified
+    let filename = format!("sd/DS{}N{:02}.DSK", image.disk_set, options.drive);
+    let file = fs::File::open(&filename).ok();
+    let mut original = fs::read(&filename).unwrap_or_default();
+    original.resize(geometry.disk_size() as usize, 0xe5);
+    let mut disk = CpmDisk::from_logical(dpb, original.clone());
+    disk.remove_file(0, &name);
+    disk.add_file(&CpmFile {user: 0, name, data})?;
+
+    let sector_size = geometry.sector_size as usize;
+    let mut overlay = HashMap::new();
+    for (i, sector) in disk.logical().chunks(sector_size).enumerate() {
+        if original[i * sector_size..(i + 1) * sector_size] != *sector {
+            overlay.insert((i * sector_size) as u64, sector.to_vec());
+        }
+    }
+    machine.add_virtual_disk(options.drive, &filename, file, overlay);
+
+    let mut command = format!("{}:{}", (b'A' + options.drive) as char, command_name);
+    for arg in options.program_args.iter() {
+        command += " ";
+        command += arg;
+    }
+    command += "\r";
+    let mut failures: Vec<String> = BDOS_ERRORS.iter().map(|e| e.to_string()).collect();
+    failures.extend(options.fail_on.iter().cloned());
+    machine.set_console(Box::new(ScriptConsole::new(&command, &command_name, failures)));
+    Ok(())
+}
+
+struct ScriptConsole {
+    input: VecDeque<u8>,
+    typing: bool,
+    not_found: String, // CCP answer when the program is not found
+    failures: Vec<String>, // Texts of the output lines that make the run fail
+    failed: bool,
+    echo: bool, // The line has the prompt and the command typed
+    line: String,
+    exit_code: Option<i32>,
+}
+
+impl ScriptConsole {
+    fn new(command: &str, command_name: &str, failures: Vec<String>) -> ScriptConsole {
+        ScriptConsole {
+            input: command.bytes().collect(),
+            typing: false,
+            not_found: format!("{}?", command_name),
+            failures,
+            failed: false,
+            echo: false,
+            line: String::new(),
+            exit_code: None,
+        }
+    }
+}
+
+impl Console for ScriptConsole {
+    fn status(&mut self) -> bool {
+        self.typing && !self.input.is_empty()
+    }
+
+    fn read(&mut self) -> u8 {
+        self.input.pop_front().unwrap_or(3) // Control C
+    }
+
+    fn put(&mut self, ch: u8) {
+        print!("{}", ch as char);
+        stdout().flush().unwrap();
+
+        match ch {
+            13 | 10 => {
+                if self.line.trim() == self.not_found {
+                    self.exit_code = Some(EXIT_NOT_FOUND);
+                }
+                // Only the output of the program counts
+                if self.typing && !self.echo && self.failures.iter().any(|f| self.line.contains(f.as_str())) {
+                    self.failed = true;
+                }
+                self.echo = false;
+                self.line.clear();
+            },
+            b'>' if is_prompt(&self.line) => {
+                if !self.typing {
+                    self.typing = true;
+                    self.echo = true;
+                } else if self.input.is_empty() && self.exit_code.is_none() {
+                    // Back to the CCP
+                    self.exit_code = Some(if self.failed {EXIT_FAILED} else {0});
+                }
+            },
+            _ => self.line.push(ch as char),
+        }
+    }
+
+    fn exit_code(&self) -> Option<i32> {
+        self.exit_code
+    }
+}
+
+// Like "A" for "A>" or "A0" for "A0>"
+fn is_prompt(line: &str) -> bool {
+    let mut chars = line.chars();
+    matches!(chars.next(), Some('A'..='P')) && chars.all(|c| c.is_ascii_digit())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn script(failures: &[&str]) -> ScriptConsole {
+        ScriptConsole::new("TEST X\r", "TEST", failures.iter().map(|f| f.to_string()).collect())
+    }
+
+    // Output of the machine, with the command typed on the first prompt
+    fn run(console: &mut ScriptConsole, output: &str) {
+        for ch in output.bytes() {
+            console.put(ch);
+            if ch == b'>' {
+                while console.status() {
+                    let ch = console.read();
+                    console.put(ch);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn prompts() {
+        assert!(is_prompt("A"));
+        assert!(is_prompt("P"));
+        assert!(is_prompt("B15"));
+        assert!(!is_prompt(""));
+        assert!(!is_prompt("Q"));
+        assert!(!is_prompt("a"));
+        assert!(!is_prompt("AB"));
+        assert!(!is_prompt("x = A"));
+    }
+
+    #[test]
+    fn back_to_the_ccp() {
+        let mut console = script(&[]);
+        run(&mut console, "CP/M 2.2\r\n\r\nA>");
+        assert_eq!(console.exit_code(), None);
+        assert!(console.input.is_empty());
+        // Not a prompt, the program output "B>" in the middle of a line
+        run(&mut console, "\r\nvalue B> 3\r\nA");
+        assert_eq!(console.exit_code(), None);
+        run(&mut console, ">");
+        assert_eq!(console.exit_code(), Some(0));
+    }
+
+    #[test]
+    fn not_found() {
+        let mut console = script(&[]);
+        run(&mut console, "A>\r\nTEST?\r\n\r\nA>");
+        assert_eq!(console.exit_code(), Some(EXIT_NOT_FOUND));
+    }
+
+    #[test]
+    fn failures() {
+        let mut console = script(&["FAIL"]);
+        run(&mut console, "A>\r\n1 test FAILED\r\nA>");
+        assert_eq!(console.exit_code(), Some(EXIT_FAILED));
+
+        // The command line and the boot messages do not count
+        let mut console = ScriptConsole::new("FAIL\r", "FAIL", vec!["FAIL".to_string()]);
+        run(&mut console, "FAIL\r\nA>\r\nok\r\nA>");
+        assert_eq!(console.exit_code(), Some(0));
+
+        let mut console = script(&["Bdos Err"]);
+        run(&mut console, "A>\r\nBdos Err On B: Select\r\nA>");
+        assert_eq!(console.exit_code(), Some(EXIT_FAILED));
+    }
+}
@@ -0,0 +1,234 @@
+use std::collections::VecDeque;
+
+use super::config::parse_number;
+
+// Model of the serial port of the ATmega. The chars take 10 bits of the
+// baud rate on the line, in T-states of the emulated clock, and wait on the
+// RX and TX buffers of the ATmega:
+//    RX: the chars typed go to the RX buffer at the line speed. When it is
+//        full they are lost, as on the board, or with flow = rtscts they are
+//        held on the host as with RTS/CTS, to paste text.
+//    TX: the chars wait on the TX buffer until sent at the line speed,
+//        ATXBUFF returns the free space. The Z80 waits when it is full.
+//
+// The settings are on the [serial] section of the config file:
+//
+//    [serial]
+//    baud = 115200
+//    rx_buffer = 64
+//    tx_buffer = 64
+//    flow = none
+//
+// As on the Arduino buffers, one byte of each buffer is not used.
+
+const DEFAULT_BAUD: u32 = 115200;
+const DEFAULT_BUFFER: usize = 64;
+const BITS_PER_CHAR: u64 = 10; // Start, 8 data bits and stop
+
+#[derive(Clone)]
+pub struct Serial {
+    pub baud: u32,
+    pub rx_buffer: usize,
+    pub tx_buffer: usize,
+    pub rts_cts: bool,
+
+    char_cycles: u64,
+    rx: VecDeque<u8>,
+    tx: VecDeque<u8>,
+    rx_next: u64,
+    tx_next: u64,
+    pub overruns: u32,
+}
+
+impl Serial {
+    pub fn new() -> Serial {
+        Serial {
+            baud: DEFAULT_BAUD,
+            rx_buffer: DEFAULT_BUFFER,
+            tx_buffer: DEFAULT_BUFFER,
+            rts_cts: false,
+            char_cycles: 0,
+            rx: VecDeque::new(),
+            tx: VecDeque::new(),
+            rx_next: 0,
+            tx_next: 0,
+            overruns: 0,
+        }
+    }
+
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        if key == "flow" {
+            self.rts_cts = match value.to_lowercase().as_str() {
+                "none" => false,
+                "rtscts" => true,
+                _ => return Err(format!("unknown flow control '{}', use none or rtscts", value)),
+            };
+            return Ok(());
+        }
+        let n = parse_number(value)
+            .ok_or(format!("invalid number '{}'", value))?;
+        match key {
+            "baud" if (300..=1_000_000).contains(&n) => self.baud = n,
+            "baud" => return Err("the baud rate must be between 300 and 1000000".to_string()),
+            "rx_buffer" if (2..=0x1_0000).contains(&n) => self.rx_buffer = n as usize,
+            "tx_buffer" if (2..=0x1_0000).contains(&n) => self.tx_buffer = n as usize,
+            "rx_buffer" | "tx_buffer" => {
+                return Err(format!("value out of range for '{}'", key));
+            },
+            _ => return Err(format!("unknown key '{}'", key)),
+        }
+        Ok(())
+    }
+
+    pub fn start(&mut self, clock_mhz: u64) {
+        self.char_cycles = BITS_PER_CHAR * clock_mhz * 1_000_000 / self.baud as u64;
+    }
+
+    // True if a char can be received from the line now
+    pub fn rx_slot(&mut self, cycles: u64) -> bool {
+        if cycles < self.rx_next {
+            false
+        } else if self.rts_cts && self.rx.len() >= self.rx_buffer - 1 {
+            // CTS off, the host sends again at the line speed later
+            self.rx_next = cycles;
+            false
+        } else {
+            true
+        }
+    }
+
+    // No chars from the host
+    pub fn rx_idle(&mut self, cycles: u64) {
+        self.rx_next = self.rx_next.max(cycles);
+    }
+
+    pub fn receive(&mut self, ch: u8) {
+        self.rx_next += self.char_cycles;
+        if self.rx.len() < self.rx_buffer - 1 {
+            self.rx.push_back(ch);
+        } else {
+            self.overruns += 1;
+        }
+    }
+
+    pub fn rx_available(&self) -> bool {
+        !self.rx.is_empty()
+    }
+
+    pub fn read(&mut self) -> Option<u8> {
+        self.rx.pop_front()
+    }
+
+    // The next char of the TX buffer if it is already on the line
+    pub fn next_tx(&mut self, cycles: u64) -> Option<u8> {
+        if self.tx.is_empty() || cycles < self.tx_next {
+            return None;
+        }
+        self.tx_next += self.char_cycles;
+        self.tx.pop_front()
+    }
+
+    // T-states the Z80 waits for room on the TX buffer
+    pub fn tx_wait(&self, cycles: u64) -> u64 {
+        if self.tx.len() >= self.tx_buffer - 1 {
+            self.tx_next.saturating_sub(cycles)
+        } else {
+            0
+        }
+    }
+
+    pub fn write(&mut self, ch: u8, cycles: u64) {
+        if self.tx.is_empty() {
+            self.tx_next = cycles + self.char_cycles;
+        }
+        self.tx.push_back(ch);
+    }
+
+    // The chars still on the TX buffer
+    pub fn flush_tx(&mut self) -> Vec<u8> {
+        self.tx.drain(..).collect()
+    }
+
+    pub fn tx_free(&self) -> u8 {
+        (self.tx_buffer - 1).saturating_sub(self.tx.len()).min(255) as u8
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 8 MHz at 115200 baud, 694 T-states per char
+    fn serial(config: &[(&str, &str)]) -> Serial {
+        let mut serial = Serial::new();
+        for (key, value) in config.iter() {
+            serial.set(key, value).unwrap();
+        }
+        serial.start(8);
+        serial
+    }
+
+    #[test]
+    fn settings() {
+        let mut serial = Serial::new();
+        assert!(serial.set("baud", "9600").is_ok());
+        assert!(serial.set("flow", "RTSCTS").is_ok());
+        assert!(serial.rts_cts);
+        assert!(serial.set("baud", "100").is_err());
+        assert!(serial.set("rx_buffer", "1").is_err());
+        assert!(serial.set("flow", "xonxoff").is_err());
+        assert!(serial.set("parity", "none").is_err());
+        serial.start(8);
+        assert_eq!(serial.char_cycles, 8333);
+    }
+
+    #[test]
+    fn rx_overruns() {
+        let mut serial = serial(&[("rx_buffer", "4")]);
+        for ch in b"abcde" {
+            assert!(serial.rx_slot(serial.rx_next));
+            serial.receive(*ch);
+        }
+        // One byte of the buffer is not used
+        assert_eq!(serial.overruns, 2);
+        assert!(!serial.rx_slot(serial.rx_next - 1));
+        assert_eq!(serial.read(), Some(b'a'));
+        assert_eq!(serial.read(), Some(b'b'));
+        assert_eq!(serial.read(), Some(b'c'));
+        assert!(!serial.rx_available());
+    }
+
+    #[test]
+    fn rx_flow_control() {
+        let mut serial = serial(&[("rx_buffer", "4"), ("flow", "rtscts")]);
+        for ch in b"abc" {
+            assert!(serial.rx_slot(serial.rx_next));
+            serial.receive(*ch);
+        }
+        // Held on the host until there is room
+        assert!(!serial.rx_slot(1_000_000));
+        serial.read();
+        assert!(serial.rx_slot(1_000_000));
+        assert_eq!(serial.overruns, 0);
+    }
+
+    #[test]
+    fn tx_at_the_line_speed() {
+        let mut serial = serial(&[("tx_buffer", "3")]);
+        assert_eq!(serial.tx_free(), 2);
+        serial.write(b'a', 1000);
+        serial.write(b'b', 1000);
+        assert_eq!(serial.tx_free(), 0);
+        // The Z80 waits until the first char is sent
+        assert_eq!(serial.tx_wait(1000), 694);
+        assert_eq!(serial.next_tx(1693), None);
+        assert_eq!(serial.next_tx(1694), Some(b'a'));
+        assert_eq!(serial.tx_wait(1694), 0);
+        assert_eq!(serial.next_tx(1694), None);
+        assert_eq!(serial.next_tx(2388), Some(b'b'));
+        assert_eq!(serial.next_tx(5000), None);
+
+        serial.write(b'c', 5000);
+        assert_eq!(serial.flush_tx(), [b'c']);
+    }
+}
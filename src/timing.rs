@@ -0,0 +1,180 @@
+use std::collections::HashMap;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::config::parse_number;
+use super::firmware::{IosOp, LATEST_FIRMWARE};
+
+// Timing model of the IOS I/O. On the board each access to the IOS ports
+// stalls the Z80 with a WAIT state while the ATmega serves it. The costs are
+// in T-states of the Z80 clock and are added to the emulated clock:
+//    opcode: storing an opcode on port 1
+//    byte: each data byte on port 0 and each serial read on port 1
+//    readsect_byte, writesect_byte: each byte of READSECT and WRITESECT
+//    OPCODE: the service of the opcode, charged on its first data byte. The
+//        costs are by operation, the same for all the firmware revisions.
+//
+// They can be changed on the [timing] section of the config file:
+//
+//    [timing]
+//    clock = 8
+//    readsect = 16000
+//    0x84 = 8000
+//
+// With the model the emulation runs at the speed of the clock, in MHz.
+
+pub const DEFAULT_CLOCK_MHZ: u64 = 8;
+
+// Costs of the opcodes served with the SD card or the RTC
+const DEFAULT_OPCODE_COSTS: [(IosOp, u32); 5] = [
+    (IosOp::WriteSect, 24000), // The SD write
+    (IosOp::DateTime, 8000),   // The I2C read of the RTC
+    (IosOp::ReadSect, 16000),  // The SD read
+    (IosOp::SdMount, 800000),
+    (IosOp::HostOpen, 8000),
+];
+
+// Further behind the real time, like after the monitor, the emulation does
+// not run faster to catch up
+const MAX_LAG: Duration = Duration::from_millis(100);
+
+#[derive(Clone)]
+pub struct Timing {
+    pub clock_mhz: u64,
+    pub opcode: u32,
+    pub byte: u32,
+    pub read_sect_byte: u32,
+    pub write_sect_byte: u32,
+    costs: HashMap<IosOp, u32>,
+}
+
+impl Timing {
+    pub fn new() -> Timing {
+        Timing {
+            clock_mhz: DEFAULT_CLOCK_MHZ,
+            opcode: 40,
+            byte: 40,
+            read_sect_byte: 80,
+            write_sect_byte: 80,
+            costs: DEFAULT_OPCODE_COSTS.iter().cloned().collect(),
+        }
+    }
+
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        let n = parse_number(value)
+            .ok_or(format!("invalid number '{}'", value))?;
+        match key {
+            "clock" if (1..=40).contains(&n) => self.clock_mhz = n as u64,
+            "clock" => return Err("the clock must be between 1 and 40 MHz".to_string()),
+            "opcode" => self.opcode = n,
+            "byte" => self.byte = n,
+            "readsect_byte" => self.read_sect_byte = n,
+            "writesect_byte" => self.write_sect_byte = n,
+            _ => match LATEST_FIRMWARE.op_from_name(key) {
+                Some(op) => {
+                    self.costs.insert(op, n);
+                },
+                None => return Err(format!("unknown key '{}'", key)),
+            },
+        }
+        Ok(())
+    }
+
+    // T-states of an access to the data port
+    pub fn data_cost(&self, op: Option<IosOp>, first: bool) -> u32 {
+        let mut cost = match op {
+            Some(IosOp::ReadSect) => self.read_sect_byte,
+            Some(IosOp::WriteSect) => self.write_sect_byte,
+            _ => self.byte,
+        };
+        if let Some(op) = op.filter(|_| first) {
+            cost += self.costs.get(&op).unwrap_or(&0);
+        }
+        cost
+    }
+}
+
+// Keeps the emulation in sync with the real time for the emulated clock
+pub struct EmulatedClock {
+    cycles_per_ms: u64,
+    start: Instant,
+    start_cycles: u64,
+    next_tick: u64,
+}
+
+impl EmulatedClock {
+    pub fn new(clock_mhz: u64, cycles: u64) -> EmulatedClock {
+        EmulatedClock {
+            cycles_per_ms: clock_mhz * 1000,
+            start: Instant::now(),
+            start_cycles: cycles,
+            next_tick: cycles + clock_mhz * 1000,
+        }
+    }
+
+    // Waits until the real time reaches the emulated time, returns the
+    // milliseconds elapsed on the emulated clock since the last call
+    pub fn sync(&mut self, cycles: u64) -> u32 {
+        let emulated = Duration::from_micros((cycles - self.start_cycles) * 1000 / self.cycles_per_ms);
+        let elapsed = self.start.elapsed();
+        if emulated > elapsed {
+            thread::sleep(emulated - elapsed);
+        } else if elapsed - emulated > MAX_LAG {
+            self.start += elapsed - emulated;
+        }
+
+        let mut ticks = 0;
+        while cycles >= self.next_tick {
+            self.next_tick += self.cycles_per_ms;
+            ticks += 1;
+        }
+        ticks
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn costs() {
+        let mut timing = Timing::new();
+        assert_eq!(timing.data_cost(Some(IosOp::ReadSect), true), 80 + 16000);
+        assert_eq!(timing.data_cost(Some(IosOp::ReadSect), false), 80);
+        assert_eq!(timing.data_cost(Some(IosOp::SerialTx), true), 40);
+
+        timing.set("readsect", "1000").unwrap();
+        timing.set("0x84", "0").unwrap();
+        timing.set("serial_tx", "7").unwrap();
+        timing.set("byte", "0x10").unwrap();
+        timing.set("clock", "4").unwrap();
+        assert_eq!(timing.data_cost(Some(IosOp::ReadSect), true), 80 + 1000);
+        assert_eq!(timing.data_cost(Some(IosOp::DateTime), true), 16);
+        assert_eq!(timing.data_cost(Some(IosOp::SerialTx), true), 16 + 7);
+        assert_eq!(timing.clock_mhz, 4);
+        assert_eq!(timing.data_cost(None, true), 16);
+    }
+
+    #[test]
+    fn errors() {
+        let mut timing = Timing::new();
+        assert_eq!(timing.set("clock", "50"), Err("the clock must be between 1 and 40 MHz".to_string()));
+        assert_eq!(timing.set("byte", "x"), Err("invalid number 'x'".to_string()));
+        assert_eq!(timing.set("printer", "1"), Err("unknown key 'printer'".to_string()));
+        // Numbers without an operation
+        assert_eq!(timing.set("0x50", "1"), Err("unknown key '0x50'".to_string()));
+    }
+
+    #[test]
+    fn clock_ticks() {
+        // 1 MHz, 1000 cycles per ms
+        let mut clock = EmulatedClock::new(1, 500);
+        assert_eq!(clock.sync(1499), 0);
+        assert_eq!(clock.sync(1500), 1);
+        assert_eq!(clock.sync(4600), 3);
+        // The emulation waits for the real time
+        let started = Instant::now();
+        clock.sync(500 + 20_000);
+        assert!(started.elapsed() >= Duration::from_millis(15));
+    }
+}
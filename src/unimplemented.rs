@@ -0,0 +1,140 @@
+use std::collections::{BTreeMap, HashMap};
+
+use super::firmware::{Firmware, DEFAULT_FIRMWARE};
+
+// What to do with the IOS opcodes not implemented, globally or by opcode:
+//
+//    POLICY[,OPCODE=POLICY...]
+//
+// The policies are:
+//    abort: show the opcode and exit the emulation, the default
+//    warn: show the opcode the first time and continue, the reads return 0xFF
+//    prompt: open the monitor, the reads return 0xFF
+//
+// Example: "warn,WRSPP=abort,0x50=prompt"
+
+const MAX_PCS: usize = 8;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Policy {
+    Abort,
+    Warn,
+    Prompt,
+}
+
+struct Seen {
+    count: u32,
+    pcs: Vec<u16>,
+}
+
+pub struct Unimplemented {
+    firmware: Firmware, // For the names of the opcodes
+    default: Policy,
+    policies: HashMap<u8, Policy>,
+    seen: BTreeMap<u8, Seen>,
+}
+
+impl Unimplemented {
+    pub fn new() -> Unimplemented {
+        Unimplemented {
+            firmware: DEFAULT_FIRMWARE,
+            default: Policy::Abort,
+            policies: HashMap::new(),
+            seen: BTreeMap::new(),
+        }
+    }
+
+    pub fn parse(text: &str, firmware: Firmware) -> Result<Unimplemented, String> {
+        let mut unimplemented = Unimplemented::new();
+        unimplemented.firmware = firmware;
+        for item in text.split(',').map(|i| i.trim()).filter(|i| !i.is_empty()) {
+            match item.find('=') {
+                Some(pos) => {
+                    let name = item[..pos].trim();
+                    let opcode = firmware.opcode_from_name(name)
+                        .ok_or(format!("unknown IOS opcode '{}'", name))?;
+                    unimplemented.policies.insert(opcode, parse_policy(&item[pos+1..])?);
+                },
+                None => unimplemented.default = parse_policy(item)?,
+            }
+        }
+        Ok(unimplemented)
+    }
+
+    pub fn set_firmware(&mut self, firmware: Firmware) {
+        self.firmware = firmware;
+    }
+
+    // Records the opcode, the result is the policy and if it is the first time
+    pub fn check(&mut self, opcode: u8, pc: u16) -> (Policy, bool) {
+        let seen = self.seen.entry(opcode).or_insert(Seen {
+            count: 0,
+            pcs: Vec::new(),
+        });
+        seen.count += 1;
+        if seen.pcs.len() < MAX_PCS && !seen.pcs.contains(&pc) {
+            seen.pcs.push(pc);
+        }
+        let policy = *self.policies.get(&opcode).unwrap_or(&self.default);
+        (policy, seen.count == 1)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.seen.is_empty()
+    }
+
+    pub fn summary(&self) -> String {
+        let mut text = "IOS opcodes not implemented:\n".to_string();
+        for (opcode, seen) in self.seen.iter() {
+            let pcs: Vec<String> = seen.pcs.iter().map(|pc| format!("{:04X}", pc)).collect();
+            text += &format!("  {:02X} {:<10} {:8} times, PC {}\n",
+                opcode, self.firmware.opcode_name(*opcode), seen.count, pcs.join(" "));
+        }
+        text
+    }
+}
+
+fn parse_policy(text: &str) -> Result<Policy, String> {
+    match text.trim().to_lowercase().as_str() {
+        "abort" => Ok(Policy::Abort),
+        "warn" => Ok(Policy::Warn),
+        "prompt" => Ok(Policy::Prompt),
+        _ => Err(format!("unknown policy '{}' for the opcodes not implemented, use abort, warn or prompt", text)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn policies() {
+        let mut unimplemented = Unimplemented::parse(" warn, WRSPP = abort ,0x50=Prompt,", DEFAULT_FIRMWARE).unwrap();
+        assert_eq!(unimplemented.check(0x12, 0x100), (Policy::Abort, true));
+        assert_eq!(unimplemented.check(0x50, 0x100), (Policy::Prompt, true));
+        assert_eq!(unimplemented.check(0x51, 0x100), (Policy::Warn, true));
+        assert_eq!(unimplemented.check(0x51, 0x100), (Policy::Warn, false));
+        assert_eq!(Unimplemented::parse("", DEFAULT_FIRMWARE).unwrap().check(0x12, 0), (Policy::Abort, true));
+    }
+
+    #[test]
+    fn errors() {
+        assert!(Unimplemented::parse("ignore", DEFAULT_FIRMWARE).err().unwrap().starts_with("unknown policy 'ignore'"));
+        assert_eq!(Unimplemented::parse("NOSUCH=warn", DEFAULT_FIRMWARE).err().unwrap(), "unknown IOS opcode 'NOSUCH'");
+        assert!(Unimplemented::parse("WRSPP=", DEFAULT_FIRMWARE).is_err());
+        // Only the opcodes of the revision
+        assert!(Unimplemented::parse("WRSPP=warn", Firmware::R240620).is_err());
+    }
+
+    #[test]
+    fn summary() {
+        let mut unimplemented = Unimplemented::new();
+        assert!(unimplemented.is_empty());
+        for pc in 0..10 {
+            unimplemented.check(0x12, 0x200 + pc % 9);
+        }
+        unimplemented.check(0x12, 0x200);
+        assert_eq!(unimplemented.summary(), format!("IOS opcodes not implemented:\n  12 {:<10} {:8} times, PC {}\n",
+            "WRSPP", 11, "0200 0201 0202 0203 0204 0205 0206 0207"));
+    }
+}
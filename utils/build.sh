@@ -0,0 +1,34 @@
+# Builds the CP/M utilities from the sources with pasmo or z80asm. With
+# --check the result is compared with the .COM files of the folder instead
+# of replacing them.
+cd "$(dirname "$0")" || exit 1
+
+assemble() {
+    if command -v pasmo > /dev/null; then
+        pasmo --bin "$1" "$2"
+    elif command -v z80asm > /dev/null; then
+        z80asm -i "$1" -o "$2"
+    else
+        echo "pasmo or z80asm is required"
+        exit 1
+    fi
+}
+
+status=0
+for source in hget hput hdir; do
+    target=$(echo $source | tr a-z A-Z).COM
+    if [ "$1" = "--check" ]; then
+        assemble $source.asm /tmp/$target.$$ || exit 1
+        if cmp -s /tmp/$target.$$ $target; then
+            echo "$target is up to date"
+        else
+            echo "$target differs from $source.asm"
+            status=1
+        fi
+        rm -f /tmp/$target.$$
+    else
+        assemble $source.asm $target || exit 1
+        echo "$target built"
+    fi
+done
+exit $status
@@ -0,0 +1,126 @@
+; HDIR - List the files on the host
+;
+; Usage: HDIR [HOSTDIR]
+;
+; The host directory is relative to the --host-files folder of z80-mbc2-emu.
+; It uses emulator only IOS opcodes, it does not work on the real Z80-MBC2.
+
+bdos    equ     0005h
+tbuff   equ     0080h
+
+stopc   equ     01h             ; IOS store opcode port
+execp   equ     00h             ; IOS execute opcode port
+
+hname   equ     40h             ; HOSTNAME
+hopen   equ     41h             ; HOSTOPEN
+hclose  equ     43h             ; HOSTCLOSE
+hread   equ     0c0h            ; HOSTREAD
+herr    equ     0c1h            ; HOSTERR
+
+        org     0100h
+
+start:  ld      sp,stack
+        call    endtail
+
+        ; Open the listing of the directory named by the parameter
+        ld      hl,tbuff+1
+        call    skipsp
+        call    sendname
+        ld      a,hopen
+        out     (stopc),a
+        ld      a,2             ; directory mode
+        out     (execp),a
+        call    hosterror
+        jp      nz,nodir
+
+list:   ld      a,hread
+        out     (stopc),a
+        in      a,(execp)       ; valid bytes in the record
+        or      a
+        jr      z,done
+        ld      (count),a
+        ld      hl,tbuff
+        ld      b,128
+get:    in      a,(execp)
+        ld      (hl),a
+        inc     hl
+        djnz    get
+        ld      hl,tbuff
+        ld      a,(count)
+        ld      b,a
+print:  push    bc
+        push    hl
+        ld      e,(hl)
+        ld      c,2             ; console output
+        call    bdos
+        pop     hl
+        pop     bc
+        inc     hl
+        djnz    print
+        jr      list
+
+done:   ld      a,hclose
+        out     (stopc),a
+        out     (execp),a
+        jp      0
+
+nodir:  ld      de,msgnod
+        ld      c,9             ; print string
+        call    bdos
+        jp      0
+
+; Zero terminate the command tail
+endtail:
+        ld      hl,tbuff
+        ld      e,(hl)
+        ld      d,0
+        inc     hl
+        add     hl,de
+        ld      (hl),0
+        ret
+
+; Skip the spaces at HL
+skipsp: ld      a,(hl)
+        cp      ' '
+        ret     nz
+        inc     hl
+        jr      skipsp
+
+; Send the parameter at HL as the host file name, without drive prefix
+sendname:
+        ld      a,hname
+        out     (stopc),a
+        inc     hl
+        ld      a,(hl)
+        dec     hl
+        cp      ':'
+        jr      nz,sendnm1
+        inc     hl
+        inc     hl
+sendnm1:
+        ld      a,(hl)
+        or      a
+        jr      z,sendnm2
+        cp      ' '
+        jr      z,sendnm2
+        out     (execp),a
+        inc     hl
+        jr      sendnm1
+sendnm2:
+        xor     a
+        out     (execp),a
+        ret
+
+; Returns the host error in A, NZ if there was an error
+hosterror:
+        ld      a,herr
+        out     (stopc),a
+        in      a,(execp)
+        or      a
+        ret
+
+msgnod: db      'Host directory not found',13,10,'$'
+
+count:  db      0
+        ds      64
+stack:
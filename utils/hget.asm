@@ -0,0 +1,169 @@
+; HGET - Copy a file from the host to CP/M
+;
+; Usage: HGET HOSTFILE [CPMFILE]
+;
+; The host file is relative to the --host-files folder of z80-mbc2-emu. It
+; uses emulator only IOS opcodes, it does not work on the real Z80-MBC2.
+
+bdos    equ     0005h
+fcb     equ     005ch
+fcb2    equ     006ch
+tbuff   equ     0080h
+
+stopc   equ     01h             ; IOS store opcode port
+execp   equ     00h             ; IOS execute opcode port
+
+hname   equ     40h             ; HOSTNAME
+hopen   equ     41h             ; HOSTOPEN
+hclose  equ     43h             ; HOSTCLOSE
+hread   equ     0c0h            ; HOSTREAD
+herr    equ     0c1h            ; HOSTERR
+
+        org     0100h
+
+start:  ld      sp,stack
+        ld      a,(fcb+1)
+        cp      ' '
+        jp      z,usage
+        call    endtail
+
+        ; Open the host file, named by the first parameter
+        ld      hl,tbuff+1
+        call    skipsp
+        call    sendname
+        ld      a,hopen
+        out     (stopc),a
+        xor     a               ; read mode
+        out     (execp),a
+        call    hosterror
+        jp      nz,nohost
+
+        ; The CP/M file is the second parameter if present
+        ld      a,(fcb2+1)
+        cp      ' '
+        jr      z,create
+        ld      hl,fcb2
+        ld      de,fcb
+        ld      bc,16
+        ldir
+create: xor     a
+        ld      (fcb+12),a
+        ld      (fcb+32),a
+        ld      de,fcb
+        ld      c,19            ; delete file
+        call    bdos
+        ld      de,fcb
+        ld      c,22            ; make file
+        call    bdos
+        inc     a
+        jp      z,dirfull
+        ld      de,tbuff
+        ld      c,26            ; set dma
+        call    bdos
+
+copy:   ld      a,hread
+        out     (stopc),a
+        in      a,(execp)       ; valid bytes in the record
+        or      a
+        jr      z,done
+        ld      hl,tbuff
+        ld      b,128
+get:    in      a,(execp)
+        ld      (hl),a
+        inc     hl
+        djnz    get
+        ld      de,fcb
+        ld      c,21            ; write sequential
+        call    bdos
+        or      a
+        jp      nz,diskfull
+        jr      copy
+
+done:   ld      de,fcb
+        ld      c,16            ; close file
+        call    bdos
+        call    hosterror
+        jp      nz,readerr
+        ld      de,msgok
+        jp      exit
+
+usage:  ld      de,msguse
+        jp      exit
+nohost: ld      de,msgnoh
+        jp      exit
+dirfull:
+        ld      de,msgdir
+        jp      exit
+diskfull:
+        ld      de,msgdsk
+        jp      exit
+readerr:
+        ld      de,msgerr
+
+; Print the message at DE, close the host file and warm boot
+exit:   ld      c,9             ; print string
+        call    bdos
+        ld      a,hclose
+        out     (stopc),a
+        out     (execp),a
+        jp      0
+
+; Zero terminate the command tail
+endtail:
+        ld      hl,tbuff
+        ld      e,(hl)
+        ld      d,0
+        inc     hl
+        add     hl,de
+        ld      (hl),0
+        ret
+
+; Skip the spaces at HL
+skipsp: ld      a,(hl)
+        cp      ' '
+        ret     nz
+        inc     hl
+        jr      skipsp
+
+; Send the parameter at HL as the host file name, without drive prefix
+sendname:
+        ld      a,hname
+        out     (stopc),a
+        inc     hl
+        ld      a,(hl)
+        dec     hl
+        cp      ':'
+        jr      nz,sendnm1
+        inc     hl
+        inc     hl
+sendnm1:
+        ld      a,(hl)
+        or      a
+        jr      z,sendnm2
+        cp      ' '
+        jr      z,sendnm2
+        out     (execp),a
+        inc     hl
+        jr      sendnm1
+sendnm2:
+        xor     a
+        out     (execp),a
+        ret
+
+; Returns the host error in A, NZ if there was an error
+hosterror:
+        ld      a,herr
+        out     (stopc),a
+        in      a,(execp)
+        or      a
+        ret
+
+msguse: db      'Usage: HGET HOSTFILE [CPMFILE]',13,10,'$'
+msgnoh: db      'Host file not found',13,10,'$'
+msgdir: db      'Directory full',13,10,'$'
+msgdsk: db      'Disk full',13,10,'$'
+msgerr: db      'Host read error',13,10,'$'
+msgok:  db      'Done',13,10,'$'
+
+        ds      64
+stack:
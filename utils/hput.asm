@@ -0,0 +1,166 @@
+; HPUT - Copy a file from CP/M to the host
+;
+; Usage: HPUT CPMFILE [HOSTFILE]
+;
+; The host file is relative to the --host-files folder of z80-mbc2-emu. It
+; uses emulator only IOS opcodes, it does not work on the real Z80-MBC2.
+
+bdos    equ     0005h
+fcb     equ     005ch
+tbuff   equ     0080h
+
+stopc   equ     01h             ; IOS store opcode port
+execp   equ     00h             ; IOS execute opcode port
+
+hname   equ     40h             ; HOSTNAME
+hopen   equ     41h             ; HOSTOPEN
+hwrite  equ     42h             ; HOSTWRITE
+hclose  equ     43h             ; HOSTCLOSE
+herr    equ     0c1h            ; HOSTERR
+
+        org     0100h
+
+start:  ld      sp,stack
+        ld      a,(fcb+1)
+        cp      ' '
+        jp      z,usage
+        call    endtail
+
+        ; Open the CP/M file, named by the first parameter
+        xor     a
+        ld      (fcb+12),a
+        ld      (fcb+32),a
+        ld      de,fcb
+        ld      c,15            ; open file
+        call    bdos
+        inc     a
+        jp      z,nofile
+
+        ; The host file is the second parameter if present
+        ld      hl,tbuff+1
+        call    skipsp
+        push    hl
+        call    skipprm
+        call    skipsp
+        ld      a,(hl)
+        or      a
+        jr      z,first
+        pop     de
+        jr      open
+first:  pop     hl
+open:   call    sendname
+        ld      a,hopen
+        out     (stopc),a
+        ld      a,1             ; write mode
+        out     (execp),a
+        call    hosterror
+        jp      nz,nohost
+        ld      de,tbuff
+        ld      c,26            ; set dma
+        call    bdos
+
+copy:   ld      de,fcb
+        ld      c,20            ; read sequential
+        call    bdos
+        or      a
+        jr      nz,done
+        ld      a,hwrite
+        out     (stopc),a
+        ld      hl,tbuff
+        ld      b,128
+put:    ld      a,(hl)
+        out     (execp),a
+        inc     hl
+        djnz    put
+        call    hosterror
+        jp      nz,writeerr
+        jr      copy
+
+done:   ld      de,msgok
+        jp      exit
+
+usage:  ld      de,msguse
+        jp      exit
+nofile: ld      de,msgnof
+        jp      exit
+nohost: ld      de,msgnoh
+        jp      exit
+writeerr:
+        ld      de,msgerr
+
+; Print the message at DE, close the host file and warm boot
+exit:   ld      c,9             ; print string
+        call    bdos
+        ld      a,hclose
+        out     (stopc),a
+        out     (execp),a
+        jp      0
+
+; Zero terminate the command tail
+endtail:
+        ld      hl,tbuff
+        ld      e,(hl)
+        ld      d,0
+        inc     hl
+        add     hl,de
+        ld      (hl),0
+        ret
+
+; Skip the spaces at HL
+skipsp: ld      a,(hl)
+        cp      ' '
+        ret     nz
+        inc     hl
+        jr      skipsp
+
+; Skip the parameter at HL
+skipprm:
+        ld      a,(hl)
+        or      a
+        ret     z
+        cp      ' '
+        ret     z
+        inc     hl
+        jr      skipprm
+
+; Send the parameter at HL as the host file name, without drive prefix
+sendname:
+        ld      a,hname
+        out     (stopc),a
+        inc     hl
+        ld      a,(hl)
+        dec     hl
+        cp      ':'
+        jr      nz,sendnm1
+        inc     hl
+        inc     hl
+sendnm1:
+        ld      a,(hl)
+        or      a
+        jr      z,sendnm2
+        cp      ' '
+        jr      z,sendnm2
+        out     (execp),a
+        inc     hl
+        jr      sendnm1
+sendnm2:
+        xor     a
+        out     (execp),a
+        ret
+
+; Returns the host error in A, NZ if there was an error
+hosterror:
+        ld      a,herr
+        out     (stopc),a
+        in      a,(execp)
+        or      a
+        ret
+
+msguse: db      'Usage: HPUT CPMFILE [HOSTFILE]',13,10,'$'
+msgnof: db      'CP/M file not found',13,10,'$'
+msgnoh: db      'Cannot create the host file',13,10,'$'
+msgerr: db      'Host write error',13,10,'$'
+msgok:  db      'Done',13,10,'$'
+
+        ds      64
+stack: